@@ -0,0 +1,266 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+
+/// Bumped whenever what we store changes, entries of other versions are never read and removed
+/// when the cache is trimmed
+static VERSION: u32 = 1;
+/// how big the cache gets unless `cache-max-bytes` is set
+pub static DEFAULT_MAX_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Graphviz's output kept between builds, keyed by a hash of everything that went into it
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    /// makes the names of files being written unique within this process
+    writes: AtomicUsize,
+}
+
+impl PartialEq for Cache {
+    fn eq(&self, other: &Cache) -> bool {
+        self.dir == other.dir
+    }
+}
+
+impl Cache {
+    /// The directory is only created once there's something to put in it
+    pub fn new(dir: PathBuf) -> Cache {
+        Cache {
+            dir,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+        }
+    }
+
+    /// The key for rendering `code` with these options
+    pub fn key(options: &[&str], code: &str) -> String {
+        let mut hasher = Sha256::new();
+        for option in options {
+            hasher.update(option.as_bytes());
+            // so ["ab", "c"] and ["a", "bc"] hash differently
+            hasher.update([0]);
+        }
+        hasher.update(code.as_bytes());
+
+        hex::encode(hasher.finalize())
+    }
+
+    /// Where entries of our version live
+    fn entries_dir(&self) -> PathBuf {
+        self.dir.join(format!("v{}", VERSION))
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let path = self.entries_dir().join(key);
+        match fs::read(&path) {
+            Ok(output) => {
+                self.hits.fetch_add(1, Ordering::SeqCst);
+                // entries are trimmed least recently used first
+                let _ = File::options()
+                    .append(true)
+                    .open(&path)
+                    .and_then(|file| file.set_modified(SystemTime::now()));
+                Some(output)
+            }
+            Err(_) => {
+                self.misses.fetch_add(1, Ordering::SeqCst);
+                None
+            }
+        }
+    }
+
+    /// A cache we can't write to only costs us time, so that's a warning
+    pub fn put(&self, key: &str, output: &[u8]) {
+        let dir = self.entries_dir();
+        // the directory might have been deleted since the last write
+        let written = fs::create_dir_all(&dir).and_then(|_| {
+            // write then rename, so parallel renders and other builds (`mdbook serve` can start
+            // one before the last finished) never read half written entries
+            let partial = dir.join(format!(
+                "{}.{}.{}.partial",
+                key,
+                process::id(),
+                self.writes.fetch_add(1, Ordering::SeqCst)
+            ));
+            fs::write(&partial, output)?;
+            fs::rename(&partial, dir.join(key))
+        });
+
+        if let Err(e) = written {
+            warn!(
+                "Couldn't write to the graphviz cache in {}: {}",
+                self.dir.display(),
+                e
+            );
+        }
+    }
+
+    /// Remove the least recently used entries until the cache holds at most `max_bytes`, along
+    /// with anything left by other versions
+    pub fn trim(&self, max_bytes: u64) {
+        let current = self.entries_dir();
+        for path in dir_entries(&self.dir) {
+            if path != current && is_ours(&path) {
+                let _ = if path.is_dir() {
+                    fs::remove_dir_all(&path)
+                } else {
+                    fs::remove_file(&path)
+                };
+            }
+        }
+
+        let mut entries: Vec<(SystemTime, u64, PathBuf)> = dir_entries(&current)
+            .into_iter()
+            .filter_map(|path| {
+                let metadata = path.metadata().ok()?;
+                Some((metadata.modified().ok()?, metadata.len(), path))
+            })
+            .collect();
+        let mut bytes: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if bytes <= max_bytes {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                debug!("Removed {} from the graphviz cache", path.display());
+                bytes -= len;
+            }
+        }
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    pub fn summary(&self) -> String {
+        let (entries, bytes) = dir_entries(&self.entries_dir())
+            .iter()
+            .filter_map(|path| path.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .fold((0, 0), |(entries, bytes), metadata| {
+                (entries + 1, bytes + metadata.len())
+            });
+
+        format!(
+            "Graphviz cache in {}: {} hits, {} misses, {} entries ({} bytes)",
+            self.dir.display(),
+            self.hits(),
+            self.misses.load(Ordering::SeqCst),
+            entries,
+            bytes
+        )
+    }
+}
+
+fn dir_entries(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Other versions' directories and entries from before there were versions, anything else
+/// someone put in the cache dir is left alone
+fn is_ours(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+
+    match name.strip_prefix('v') {
+        Some(version) if path.is_dir() => version.parse::<u32>().is_ok(),
+        _ => name.len() == 64 && name.chars().all(|ch| ch.is_ascii_hexdigit()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn keys() {
+        let key = Cache::key(&["-Kneato", "-Tsvg"], "digraph { a -> b }");
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, Cache::key(&["-Kneato", "-Tsvg"], "digraph { a -> b }"));
+
+        assert_ne!(key, Cache::key(&["-Tsvg"], "digraph { a -> b }"));
+        assert_ne!(key, Cache::key(&["-Kneato", "-Tpng"], "digraph { a -> b }"));
+        assert_ne!(key, Cache::key(&["-Kneato", "-Tsvg"], "digraph { a -> c }"));
+        assert_ne!(Cache::key(&["ab", "c"], ""), Cache::key(&["a", "bc"], ""));
+    }
+
+    #[test]
+    fn hits_and_misses() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().join("cache"));
+        let key = Cache::key(&[], "digraph {}");
+
+        // nothing's created until we write
+        assert_eq!(cache.get(&key), None);
+        assert!(!dir.path().join("cache").exists());
+
+        cache.put(&key, b"<svg/>");
+        assert_eq!(cache.get(&key), Some(b"<svg/>".to_vec()));
+
+        // deleted during the build
+        fs::remove_dir_all(dir.path().join("cache")).unwrap();
+        assert_eq!(cache.get(&key), None);
+        cache.put(&key, b"<svg/>");
+        assert_eq!(cache.get(&key), Some(b"<svg/>".to_vec()));
+
+        let summary = cache.summary();
+        assert!(
+            summary.contains("2 hits, 2 misses, 1 entries (6 bytes)"),
+            "{}",
+            summary
+        );
+    }
+
+    #[test]
+    fn trimming() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().into());
+        let keys: Vec<String> = (0..4)
+            .map(|index| Cache::key(&[], &index.to_string()))
+            .collect();
+        for (index, key) in keys.iter().enumerate() {
+            cache.put(key, &[0; 10]);
+            File::options()
+                .append(true)
+                .open(cache.entries_dir().join(key))
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(index as u64))
+                .unwrap();
+        }
+        // the oldest entry was just used
+        assert!(cache.get(&keys[0]).is_some());
+
+        // other versions and entries from before there were versions, and something that isn't ours
+        fs::create_dir(dir.path().join("v0")).unwrap();
+        fs::write(dir.path().join(&keys[0]), "").unwrap();
+        fs::write(dir.path().join("README"), "").unwrap();
+
+        cache.trim(25);
+        assert!(cache.get(&keys[0]).is_some());
+        assert!(cache.get(&keys[1]).is_none());
+        assert!(cache.get(&keys[2]).is_none());
+        assert!(cache.get(&keys[3]).is_some());
+        assert!(!dir.path().join("v0").exists());
+        assert!(!dir.path().join(&keys[0]).exists());
+        assert!(dir.path().join("README").exists());
+    }
+}
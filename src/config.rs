@@ -0,0 +1,953 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::thread;
+
+use mdbook::errors::{Error, Result};
+use regex::Regex;
+use serde::de::{Deserializer, Error as _};
+use serde::Deserialize;
+use toml::value::Table;
+use toml::Value;
+
+use crate::filename_template::FilenameTemplate;
+use crate::info_string::InfoString;
+use crate::preprocessor::{
+    ALT_CHAPTER, ALT_INDEX, ALT_NAME, INFO_STRING_PREFIX, PREPROCESSOR_NAME,
+};
+use crate::renderer::{Filter, GraphvizVersion, Unflatten, ENGINES};
+
+/// the fence other graphviz tools use, accepted with `accept-graphviz-fence`
+static GRAPHVIZ_FENCE: &str = "graphviz";
+/// plain dot code blocks, processed with `render-all-dot`
+static DOT_FENCE: &str = "dot";
+/// the `[output]` entry used for renderers without their own
+static DEFAULT_OUTPUT: &str = "default";
+/// image formats we can ask graphviz for, svg is the only one we can inline
+pub static SVG: &str = "svg";
+pub static FORMATS: &[&str] = &["svg", "png", "jpg", "gif"];
+/// keys mdbook itself reads from every preprocessor table, apart from `renderers` which we share
+static MDBOOK_KEYS: &[&str] = &["command", "before", "after", "optional"];
+
+/// Our settings from the `[preprocessor.graphviz]` table of `book.toml`
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GraphvizConfig {
+    /// the renderers we process the book for, all of them if not set
+    pub renderers: Option<Vec<String>>,
+    /// write images to files and link to them, instead of putting svgs into the chapter
+    pub output_to_file: bool,
+    /// put images into the chapter as data urls instead of writing them to files
+    pub embed_images: bool,
+    /// also write the code graphviz got next to each image file, as `<image>.generated.dot`
+    pub keep_dot: bool,
+    /// write a json list of every rendered graph to this file, relative to the book root
+    pub manifest: Option<PathBuf>,
+    /// where image files are written, relative to the book root. Next to the chapter if not set
+    pub output_dir: Option<PathBuf>,
+    /// a directory next to each chapter its image files are written to, unless there's an
+    /// `output_dir`
+    pub image_subdir: Option<String>,
+    /// what lays graphs out and draws them
+    pub backend: Backend,
+    /// a command run instead of `dot`, e.g. `["sh", "-c", "dot -Tsvg | svgcleaner -c -"]`. It
+    /// gets the code on stdin and prints the image, or writes it to `{output}`
+    pub render_command: Option<Vec<String>>,
+    /// run on every image file written, e.g. `["svgo", "--input", "{file}", "--output", "{file}"]`
+    pub post_process: Option<Vec<String>>,
+    /// seconds `dot` or the `render_command` may take for a graph before it's killed
+    pub render_timeout: Option<u64>,
+    /// the `dot` binary to use instead of the one on the `PATH`
+    pub dot_command: Option<String>,
+    /// warn when the installed graphviz is older than this
+    #[serde(deserialize_with = "min_version")]
+    pub min_version: Option<GraphvizVersion>,
+    /// the graphviz layout engine, e.g. `neato`
+    pub engine: Option<String>,
+    /// makes force directed layouts like `neato` reproducible between builds
+    pub seed: Option<u64>,
+    /// run graphs through graphviz's `unflatten` before laying them out, for wide fan-out graphs
+    #[serde(deserialize_with = "unflatten")]
+    pub unflatten: Option<Unflatten>,
+    /// commands the code of every graph is piped through before graphviz gets it
+    #[serde(deserialize_with = "filters")]
+    pub filters: Vec<Filter>,
+    /// put a `<!-- graphviz:generated name="..." -->` comment before every generated image
+    pub emit_markers: bool,
+    /// when inlining svgs, write them to files instead once a chapter holds this many bytes
+    pub max_inline_total_bytes: Option<usize>,
+    /// the resolution of raster images, graphviz's `-Gdpi`
+    pub dpi: Option<u32>,
+    /// render a trivial graph before the others when a book has more graphs than this, so they
+    /// don't each pay for graphviz loading its libraries and fonts
+    pub warm_up: Option<usize>,
+    /// the keyword marking code blocks we process, `dot process` if not set
+    pub info_string: Option<String>,
+    /// also process ```` ```graphviz ```` blocks
+    pub accept_graphviz_fence: bool,
+    /// also process plain ```` ```dot ```` blocks
+    pub render_all_dot: bool,
+    /// fail on info string options we don't know instead of warning about them
+    pub strict_info_string: bool,
+    /// the image format for each renderer, e.g. `epub = "png"`, with a `default` fallback
+    pub output: BTreeMap<String, String>,
+    /// the format picked from `output` for the renderer we're running for, svg if not set
+    #[serde(skip)]
+    pub(crate) image_format: Option<String>,
+    pub on_missing: OnMissing,
+    /// how generated image files are named
+    pub filename_template: FilenameTemplate,
+    /// `hashed` names files by their content instead of the filename-template
+    pub filenames: Filenames,
+    /// what `{chapter}` in file names is made from
+    pub chapter_names: ChapterNames,
+    /// put the block's index in the file names of named blocks too, so names don't have to be
+    /// unique within a chapter. On unless set, only used without stable-names
+    pub index_names: Option<bool>,
+    /// name files by the graph's name, or a hash of the code for unnamed graphs, instead of the
+    /// block's position, so moving a block doesn't rename the files after it. On unless set
+    pub stable_names: Option<bool>,
+    /// show the code of every block above its graphs, unless it's marked `nosource`
+    pub preserve_source: bool,
+    /// quote the lines of a graph graphviz complained about in the error. On unless set
+    pub error_excerpts: Option<bool>,
+    /// the alt text of images from blocks without an `alt`, e.g. `Diagram: {name}`
+    pub alt_template: Option<String>,
+    /// where rendered graphs are kept between builds, relative to the book root. No caching if
+    /// not set
+    pub cache_dir: Option<PathBuf>,
+    /// how big the cache-dir gets before the least recently used graphs are removed
+    pub cache_max_bytes: Option<u64>,
+    /// render every graph again, ignoring the cache-dir and the hashes recorded next to image files
+    pub no_cache: bool,
+    /// remove generated images that weren't produced by this build
+    pub prune: bool,
+    /// add a line ignoring our images to the book's `.gitignore`
+    pub update_gitignore: bool,
+    /// don't report progress while rendering
+    pub quiet: bool,
+    /// print how long rendering took and the slowest graphs at the end of the build
+    pub stats: bool,
+    /// only check that every graph renders, without writing files or changing the book
+    pub check: bool,
+    /// how many graphs are rendered at once, the number of cpus if not set. A graph's `filters` and
+    /// `unflatten` run as part of it, they aren't counted separately
+    pub jobs: Option<usize>,
+    /// set when graphviz couldn't be run and `on-missing = "skip"`, blocks are left as dot code
+    #[serde(skip)]
+    pub(crate) graphviz_missing: bool,
+    /// whether blocks sharing a `group` are shown in a carousel, set for renderers which take our
+    /// stylesheet. They're shown one after another otherwise
+    #[serde(skip)]
+    pub(crate) carousels: bool,
+}
+
+/// What lays graphs out and draws them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// graphviz's `dot` command, or the `render-command`
+    #[default]
+    #[serde(alias = "command")]
+    Dot,
+    /// the `layout-rs` crate, when built with the `native-layout` feature. It only draws svgs
+    Rust,
+    /// the code is put into the chapter for a script to draw in the browser
+    Client,
+    /// only the code graphviz would get is written, next to where each image would go
+    Dump,
+    /// blocks are left as dot code, e.g. for text-only checks in CI
+    None,
+}
+
+/// What to do when graphviz can't be found or run
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnMissing {
+    /// fail the build
+    #[default]
+    Error,
+    /// warn once and leave every block as a plain dot code block
+    Skip,
+}
+
+/// How generated image files are named
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Filenames {
+    /// by the filename-template
+    #[default]
+    Template,
+    /// by a hash of the graph, images from earlier versions of a graph are removed
+    Hashed,
+}
+
+/// What `{chapter}` in file names is made from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChapterNames {
+    /// the chapter's file, so chapters with the same title get different names
+    #[default]
+    Path,
+    /// the chapter's title, how files were named before
+    Title,
+}
+
+impl GraphvizConfig {
+    /// Read our settings from the `[preprocessor.graphviz]` table, rejecting keys we don't know so
+    /// typos don't go unnoticed
+    pub fn from_table(table: Option<&Table>) -> Result<GraphvizConfig> {
+        let mut table = table.cloned().unwrap_or_default();
+        for key in MDBOOK_KEYS {
+            table.remove(*key);
+        }
+
+        let config: GraphvizConfig = Value::Table(table).try_into().map_err(|e| {
+            Error::msg(format!(
+                "Invalid [preprocessor.{}] configuration: {}",
+                PREPROCESSOR_NAME, e
+            ))
+        })?;
+
+        config.validate()
+    }
+
+    /// Checks the types alone can't express
+    fn validate(mut self) -> Result<GraphvizConfig> {
+        if let Some(ref engine) = self.engine {
+            if !ENGINES.contains(&engine.as_str()) {
+                return Err(Error::msg(format!(
+                    "engine must be one of {}, found: {}",
+                    ENGINES.join(", "),
+                    engine
+                )));
+            }
+        }
+        for (renderer, format) in &self.output {
+            if !FORMATS.contains(&format.as_str()) {
+                return Err(Error::msg(format!(
+                    "output.{} must be one of {}, found: {}",
+                    renderer,
+                    FORMATS.join(", "),
+                    format
+                )));
+            }
+        }
+        if let Some(ref template) = self.alt_template {
+            let placeholders = Regex::new(r"\{[^}]*\}").unwrap();
+            let unknown = placeholders
+                .find_iter(template)
+                .map(|placeholder| placeholder.as_str())
+                .find(|placeholder| ![ALT_NAME, ALT_CHAPTER, ALT_INDEX].contains(placeholder));
+            if let Some(unknown) = unknown {
+                return Err(Error::msg(format!(
+                    "Unknown placeholder {} in alt-template, use {}, {} or {}",
+                    unknown, ALT_NAME, ALT_CHAPTER, ALT_INDEX
+                )));
+            }
+        }
+        if self.filenames == Filenames::Hashed
+            && self.filename_template != FilenameTemplate::default()
+        {
+            return Err(Error::msg(
+                "filenames = \"hashed\" names the files itself, it can't be combined with a filename-template",
+            ));
+        }
+        if self.backend == Backend::Rust {
+            if !cfg!(feature = "native-layout") {
+                return Err(Error::msg(
+                    "backend = \"rust\" needs mdbook-graphviz built with the native-layout feature: \
+                     cargo install mdbook-graphviz --features native-layout",
+                ));
+            }
+            if let Some((renderer, format)) = self.output.iter().find(|(_, format)| *format != SVG)
+            {
+                return Err(Error::msg(format!(
+                    "backend = \"rust\" only draws svgs, found: output.{} = {:?}",
+                    renderer, format
+                )));
+            }
+        }
+        if let Some(ref command) = self.post_process {
+            if command.first().is_none_or(|program| program.is_empty()) {
+                return Err(Error::msg(
+                    "post-process must start with the program to run, e.g. [\"optipng\", \"{file}\"]",
+                ));
+            }
+        }
+        match self.render_command {
+            Some(ref command) if command.first().is_none_or(|program| program.is_empty()) => {
+                return Err(Error::msg(
+                    "render-command must start with the program to run, e.g. [\"dot\", \"-Tsvg\"]",
+                ));
+            }
+            Some(_) if self.backend != Backend::Dot => {
+                return Err(Error::msg(
+                    "render-command replaces graphviz's `dot`, it needs backend = \"dot\"",
+                ));
+            }
+            _ => {}
+        }
+        if self.backend == Backend::Client && (self.output_to_file || self.embed_images) {
+            return Err(Error::msg(
+                "backend = \"client\" draws graphs in the browser, it can't be combined with \
+                 output-to-file or embed-images",
+            ));
+        }
+        if self.backend == Backend::Dump && self.check {
+            return Err(Error::msg(
+                "backend = \"dump\" writes the code of every graph, it can't be combined with check",
+            ));
+        }
+        if let Some(ref subdir) = self.image_subdir {
+            if subdir.is_empty()
+                || subdir == "."
+                || subdir == ".."
+                || subdir.contains(&['/', '\\'][..])
+            {
+                return Err(Error::msg(format!(
+                    "image-subdir must be the name of a directory, found: {:?}",
+                    subdir
+                )));
+            }
+        }
+        if self.jobs == Some(0) {
+            return Err(Error::msg("jobs must be a positive integer, found: 0"));
+        }
+        if self.max_inline_total_bytes == Some(0) {
+            return Err(Error::msg(
+                "max-inline-total-bytes must be a positive integer, found: 0",
+            ));
+        }
+        if self.dpi == Some(0) {
+            return Err(Error::msg("dpi must be a positive integer, found: 0"));
+        }
+        if self.render_timeout == Some(0) {
+            return Err(Error::msg(
+                "render-timeout must be a positive number of seconds, found: 0",
+            ));
+        }
+        if let Some(keyword) = self.info_string.take() {
+            if keyword.trim().is_empty() || keyword.contains(&['`', '\n', '\r'][..]) {
+                return Err(Error::msg(format!(
+                    "info-string must be a keyword without backticks or newlines, found: {:?}",
+                    keyword
+                )));
+            }
+            self.info_string = Some(keyword.trim().to_string());
+        }
+
+        Ok(self)
+    }
+
+    /// The image format to render to, picked by [`GraphvizConfig::adapt_to_renderer`]
+    pub fn image_format(&self) -> &str {
+        self.image_format.as_deref().unwrap_or(SVG)
+    }
+
+    pub fn filename_template(&self) -> FilenameTemplate {
+        match self.filenames {
+            Filenames::Template => self.filename_template.clone(),
+            Filenames::Hashed => FilenameTemplate::hashed(),
+        }
+    }
+
+    pub fn index_names(&self) -> bool {
+        self.index_names.unwrap_or(true)
+    }
+
+    pub fn stable_names(&self) -> bool {
+        self.stable_names.unwrap_or(true)
+    }
+
+    pub fn error_excerpts(&self) -> bool {
+        self.error_excerpts.unwrap_or(true)
+    }
+
+    /// How many graphs we render at once
+    pub fn jobs(&self) -> usize {
+        self.jobs.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(NonZeroUsize::get)
+                .unwrap_or(1)
+        })
+    }
+
+    /// Whether the book should be processed for this renderer, otherwise it's left untouched
+    pub fn processes_renderer(&self, renderer: &str) -> bool {
+        match self.renderers {
+            Some(ref renderers) => renderers.iter().any(|r| r == renderer),
+            None => true,
+        }
+    }
+
+    /// The keyword marking code blocks we process
+    pub fn info_string_keyword(&self) -> &str {
+        self.info_string.as_deref().unwrap_or(INFO_STRING_PREFIX)
+    }
+
+    /// Parse the info string of a code block if any of our keywords mark it for processing
+    pub fn parse_info_string(&self, info_string: &str) -> Result<Option<InfoString>> {
+        let mut keywords = vec![self.info_string_keyword()];
+        if self.accept_graphviz_fence {
+            keywords.push(GRAPHVIZ_FENCE);
+        }
+        // last, so `dot process` isn't read as a graph named "process"
+        if self.render_all_dot {
+            keywords.push(DOT_FENCE);
+        }
+
+        for keyword in keywords {
+            if let Some(info_string) = InfoString::parse(info_string, keyword)? {
+                return Ok(Some(info_string));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// What a renderer is able to display from what we can emit
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct RendererCapabilities {
+    raw_html: bool,
+    /// styles html with the book's `additional-css`
+    stylesheets: bool,
+}
+
+impl RendererCapabilities {
+    /// `None` if we don't know the renderer, in which case we trust the configuration
+    fn of(renderer: &str) -> Option<RendererCapabilities> {
+        match renderer {
+            "html" => Some(RendererCapabilities {
+                raw_html: true,
+                stylesheets: true,
+            }),
+            "epub" | "markdown" => Some(RendererCapabilities {
+                raw_html: false,
+                stylesheets: false,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl GraphvizConfig {
+    /// Turn off anything the renderer can't display, falling back to plain markdown images,
+    /// and describe what we changed
+    pub fn adapt_to_renderer(&mut self, renderer: &str) -> Vec<String> {
+        let mut downgrades = vec![];
+
+        self.carousels =
+            RendererCapabilities::of(renderer).is_some_and(|capabilities| capabilities.stylesheets);
+        self.image_format = self
+            .output
+            .get(renderer)
+            .or_else(|| self.output.get(DEFAULT_OUTPUT))
+            .cloned();
+        if self.image_format() != SVG && !self.output_to_file {
+            self.output_to_file = true;
+            downgrades.push(format!(
+                "{} images can't be inlined, writing graphs to image files for the {} renderer",
+                self.image_format(),
+                renderer
+            ));
+        }
+
+        if let Some(capabilities) = RendererCapabilities::of(renderer) {
+            if !capabilities.raw_html && !self.output_to_file {
+                self.output_to_file = true;
+                downgrades.push(format!(
+                    "the {} renderer can't display inline svgs, writing graphs to image files instead",
+                    renderer
+                ));
+            }
+        }
+
+        downgrades
+    }
+}
+
+/// `unflatten` can either be enabled with defaults (`true`) or configured with a table
+fn unflatten_config(value: &Value) -> Result<Option<Unflatten>> {
+    match value {
+        Value::Boolean(enabled) => Ok(if *enabled {
+            Some(Unflatten::default())
+        } else {
+            None
+        }),
+        Value::Table(table) => {
+            let leaf_stagger = match table.get("leaf-stagger") {
+                Some(Value::Integer(stagger)) if *stagger > 0 => match u32::try_from(*stagger) {
+                    Ok(stagger) => Some(stagger),
+                    Err(_) => {
+                        return Err(Error::msg(format!(
+                            "unflatten.leaf-stagger is too large, found: {}",
+                            stagger
+                        )))
+                    }
+                },
+                Some(other) => {
+                    return Err(Error::msg(format!(
+                        "unflatten.leaf-stagger must be a positive integer, found: {}",
+                        other
+                    )))
+                }
+                None => None,
+            };
+            let fanout = match table.get("fanout") {
+                Some(Value::Boolean(fanout)) => *fanout,
+                Some(other) => {
+                    return Err(Error::msg(format!(
+                        "unflatten.fanout must be a boolean, found: {}",
+                        other
+                    )))
+                }
+                None => false,
+            };
+
+            Ok(Some(Unflatten {
+                leaf_stagger,
+                fanout,
+            }))
+        }
+        other => Err(Error::msg(format!(
+            "unflatten must be a boolean or a table, found: {}",
+            other
+        ))),
+    }
+}
+
+fn min_version<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<GraphvizVersion>, D::Error> {
+    let version = String::deserialize(deserializer)?;
+    GraphvizVersion::parse(&version).map(Some).ok_or_else(|| {
+        D::Error::custom(format!(
+            "min-version must be a graphviz version like \"2.40\", found: {}",
+            version
+        ))
+    })
+}
+
+fn unflatten<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Unflatten>, D::Error> {
+    let value = Value::deserialize(deserializer)?;
+    unflatten_config(&value).map_err(D::Error::custom)
+}
+
+fn filters<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<Filter>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|command| Filter::parse(command).map_err(D::Error::custom))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use toml::Value;
+
+    use super::*;
+
+    #[test]
+    fn representative_table() {
+        let table: Value = toml::from_str(
+            r#"
+            command = "mdbook-graphviz"
+            before = ["links"]
+            renderers = ["html"]
+            output-to-file = true
+            dot-command = "/opt/graphviz/bin/dot"
+            output-dir = "src/generated"
+            image-subdir = "_graphviz"
+            engine = "fdp"
+            seed = 3
+            dpi = 300
+            render-timeout = 30
+            emit-markers = true
+            info-string = " graphviz "
+            strict-info-string = true
+            warm-up = 8
+            on-missing = "skip"
+            jobs = 4
+            cache-dir = ".mdbook-graphviz-cache"
+            cache-max-bytes = 1048576
+            no-cache = true
+            prune = true
+            quiet = true
+            stats = true
+            check = true
+            embed-images = true
+            keep-dot = true
+            manifest = "graphviz-manifest.json"
+            update-gitignore = true
+            filename-template = "{name}_{hash}"
+            index-names = false
+            stable-names = false
+            chapter-names = "title"
+            preserve-source = true
+            error-excerpts = false
+            alt-template = "Diagram: {name}"
+
+            [unflatten]
+            fanout = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            GraphvizConfig::from_table(table.as_table()).unwrap(),
+            GraphvizConfig {
+                renderers: Some(vec!["html".into()]),
+                output_to_file: true,
+                dot_command: Some("/opt/graphviz/bin/dot".into()),
+                output_dir: Some("src/generated".into()),
+                image_subdir: Some("_graphviz".into()),
+                engine: Some("fdp".into()),
+                seed: Some(3),
+                dpi: Some(300),
+                render_timeout: Some(30),
+                unflatten: Some(Unflatten {
+                    leaf_stagger: None,
+                    fanout: true,
+                }),
+                emit_markers: true,
+                info_string: Some("graphviz".into()),
+                strict_info_string: true,
+                warm_up: Some(8),
+                on_missing: OnMissing::Skip,
+                jobs: Some(4),
+                cache_dir: Some(".mdbook-graphviz-cache".into()),
+                cache_max_bytes: Some(1048576),
+                no_cache: true,
+                prune: true,
+                update_gitignore: true,
+                quiet: true,
+                stats: true,
+                check: true,
+                embed_images: true,
+                keep_dot: true,
+                manifest: Some("graphviz-manifest.json".into()),
+                filename_template: FilenameTemplate::parse("{name}_{hash}").unwrap(),
+                index_names: Some(false),
+                stable_names: Some(false),
+                chapter_names: ChapterNames::Title,
+                preserve_source: true,
+                error_excerpts: Some(false),
+                alt_template: Some("Diagram: {name}".into()),
+                ..GraphvizConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_keys_list_the_valid_ones() {
+        let table: Value = toml::from_str("output-to-files = true").unwrap();
+        let error = GraphvizConfig::from_table(table.as_table())
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("output-to-files"), "{}", error);
+        assert!(error.contains("`output-to-file`"), "{}", error);
+        assert!(error.contains("`strict-info-string`"), "{}", error);
+    }
+
+    #[test]
+    fn wrong_types() {
+        for invalid in &[
+            r#"output-to-file = "yes""#,
+            "emit-markers = 1",
+            "dot-command = true",
+            r#"max-inline-total-bytes = "1MB""#,
+            "max-inline-total-bytes = 0",
+            r#"warm-up = "always""#,
+            "dpi = 0",
+            "dpi = -96",
+            "dpi = 4294967297",
+            r#"dpi = "300""#,
+            "jobs = 0",
+            "jobs = -2",
+            "render-timeout = 0",
+            r#"render-timeout = "1m""#,
+            r#"filename-template = "{chapter}_{name}""#,
+            r#"alt-template = "Diagram: {title}""#,
+            r#"filenames = "random""#,
+            "filenames = \"hashed\"\nfilename-template = \"{name}_{hash}\"",
+            r#"on-missing = "ignore""#,
+            "on-missing = false",
+            r#"image-subdir = "images/graphviz""#,
+            r#"image-subdir = "..""#,
+            r#"image-subdir = """#,
+            "post-process = []",
+        ] {
+            let table: Value = toml::from_str(invalid).unwrap();
+            assert!(
+                GraphvizConfig::from_table(table.as_table()).is_err(),
+                "{}",
+                invalid
+            );
+        }
+
+        let table: Value = toml::from_str(r#"render-all-dot = "yes""#).unwrap();
+        let error = GraphvizConfig::from_table(table.as_table())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("render-all-dot"), "{}", error);
+    }
+
+    #[test]
+    fn filters_from_config() {
+        let table: Value =
+            toml::from_str(r#"filters = ["gvpr -c -f style.gvpr", "tred"]"#).unwrap();
+        let config = GraphvizConfig::from_table(table.as_table()).unwrap();
+        assert_eq!(
+            config.filters,
+            vec![
+                Filter::parse("gvpr -c -f style.gvpr").unwrap(),
+                Filter::parse("tred").unwrap()
+            ]
+        );
+
+        let table: Value = toml::from_str(r#"filters = "tred""#).unwrap();
+        assert!(GraphvizConfig::from_table(table.as_table()).is_err());
+        let table: Value = toml::from_str(r#"filters = [""]"#).unwrap();
+        assert!(GraphvizConfig::from_table(table.as_table()).is_err());
+    }
+
+    #[test]
+    fn min_version_from_config() {
+        let table: Value = toml::from_str(r#"min-version = "2.40""#).unwrap();
+        let config = GraphvizConfig::from_table(table.as_table()).unwrap();
+        assert_eq!(config.min_version, GraphvizVersion::parse("2.40"));
+
+        let table: Value = toml::from_str(r#"min-version = "latest""#).unwrap();
+        assert!(GraphvizConfig::from_table(table.as_table()).is_err());
+        let table: Value = toml::from_str("min-version = 2.40").unwrap();
+        assert!(GraphvizConfig::from_table(table.as_table()).is_err());
+    }
+
+    #[test]
+    fn restricted_renderers() {
+        let config = GraphvizConfig::default();
+        assert!(config.processes_renderer("html"));
+        assert!(config.processes_renderer("markdown"));
+
+        let table: Value = toml::from_str(r#"renderers = ["html", "epub"]"#).unwrap();
+        let config = GraphvizConfig::from_table(table.as_table()).unwrap();
+        assert!(config.processes_renderer("html"));
+        assert!(config.processes_renderer("epub"));
+        assert!(!config.processes_renderer("markdown"));
+
+        let table: Value = toml::from_str(r#"renderers = "html""#).unwrap();
+        assert!(GraphvizConfig::from_table(table.as_table()).is_err());
+    }
+
+    #[test]
+    fn html_renderer_keeps_inline_svgs() {
+        let mut config = GraphvizConfig::default();
+
+        assert!(config.adapt_to_renderer("html").is_empty());
+        // only the html renderer shows groups as carousels
+        assert_eq!(
+            config,
+            GraphvizConfig {
+                carousels: true,
+                ..GraphvizConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn epub_renderer_falls_back_to_image_files() {
+        let mut config = GraphvizConfig::default();
+
+        let downgrades = config.adapt_to_renderer("epub");
+        assert_eq!(downgrades.len(), 1);
+        assert!(downgrades[0].contains("epub"), "{}", downgrades[0]);
+        assert!(config.output_to_file);
+        assert!(!config.carousels);
+
+        // there's nothing to downgrade once we're writing files
+        let mut config = GraphvizConfig {
+            output_to_file: true,
+            ..GraphvizConfig::default()
+        };
+        assert!(config.adapt_to_renderer("epub").is_empty());
+    }
+
+    #[test]
+    fn output_formats_per_renderer() {
+        let table: Value = toml::from_str(
+            r#"
+            [output]
+            html = "svg"
+            epub = "png"
+            default = "jpg"
+            "#,
+        )
+        .unwrap();
+        let config = GraphvizConfig::from_table(table.as_table()).unwrap();
+
+        let mut html = config.clone();
+        assert!(html.adapt_to_renderer("html").is_empty());
+        assert_eq!(html.image_format(), "svg");
+        assert!(!html.output_to_file);
+
+        let mut epub = config.clone();
+        assert_eq!(epub.adapt_to_renderer("epub").len(), 1);
+        assert_eq!(epub.image_format(), "png");
+        assert!(epub.output_to_file);
+
+        let mut other = config;
+        other.adapt_to_renderer("my-custom-renderer");
+        assert_eq!(other.image_format(), "jpg");
+
+        assert_eq!(GraphvizConfig::default().image_format(), "svg");
+
+        let table: Value = toml::from_str("[output]\nepub = \"docx\"").unwrap();
+        assert!(GraphvizConfig::from_table(table.as_table()).is_err());
+    }
+
+    #[test]
+    fn unknown_renderers_keep_the_configuration() {
+        let mut config = GraphvizConfig::default();
+
+        assert!(config.adapt_to_renderer("my-custom-renderer").is_empty());
+        assert_eq!(config, GraphvizConfig::default());
+    }
+
+    #[test]
+    fn engine_and_seed_from_config() {
+        let table: Value = toml::from_str("engine = \"neato\"\nseed = 7").unwrap();
+        let config = GraphvizConfig::from_table(table.as_table()).unwrap();
+        assert_eq!(config.engine, Some("neato".into()));
+        assert_eq!(config.seed, Some(7));
+
+        let table: Value = toml::from_str(r#"seed = "random""#).unwrap();
+        assert!(GraphvizConfig::from_table(table.as_table()).is_err());
+        let table: Value = toml::from_str("seed = -1").unwrap();
+        assert!(GraphvizConfig::from_table(table.as_table()).is_err());
+        let table: Value = toml::from_str(r#"engine = "nato""#).unwrap();
+        assert!(GraphvizConfig::from_table(table.as_table()).is_err());
+    }
+
+    #[test]
+    fn backend_from_config() {
+        assert_eq!(GraphvizConfig::default().backend, Backend::Dot);
+
+        let table: Value = toml::from_str(r#"backend = "rust""#).unwrap();
+        let config = GraphvizConfig::from_table(table.as_table());
+        if cfg!(feature = "native-layout") {
+            assert_eq!(config.unwrap().backend, Backend::Rust);
+        } else {
+            assert!(config
+                .unwrap_err()
+                .to_string()
+                .contains("--features native-layout"));
+        }
+
+        // it only draws svgs
+        let table: Value = toml::from_str("backend = \"rust\"\n[output]\nepub = \"png\"").unwrap();
+        assert!(GraphvizConfig::from_table(table.as_table()).is_err());
+        // the name earlier versions used
+        let table: Value = toml::from_str(r#"backend = "command""#).unwrap();
+        let config = GraphvizConfig::from_table(table.as_table()).unwrap();
+        assert_eq!(config.backend, Backend::Dot);
+        let table: Value = toml::from_str(r#"backend = "none""#).unwrap();
+        let config = GraphvizConfig::from_table(table.as_table()).unwrap();
+        assert_eq!(config.backend, Backend::None);
+        let table: Value = toml::from_str("backend = \"client\"\noutput-to-file = true").unwrap();
+        assert!(GraphvizConfig::from_table(table.as_table()).is_err());
+        let table: Value = toml::from_str("backend = \"dump\"\ncheck = true").unwrap();
+        assert!(GraphvizConfig::from_table(table.as_table()).is_err());
+
+        let table: Value = toml::from_str(r#"backend = "wasm""#).unwrap();
+        let error = GraphvizConfig::from_table(table.as_table())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("`dot`, `rust`, `client`, `dump`, `none`"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn render_command_from_config() {
+        let table: Value =
+            toml::from_str(r#"render-command = ["sh", "-c", "dot -Tsvg | svgcleaner -c -"]"#)
+                .unwrap();
+        let config = GraphvizConfig::from_table(table.as_table()).unwrap();
+        assert_eq!(
+            config.render_command,
+            Some(vec![
+                "sh".to_string(),
+                "-c".into(),
+                "dot -Tsvg | svgcleaner -c -".into()
+            ])
+        );
+
+        for invalid in &[
+            "render-command = []",
+            r#"render-command = ["", "-Tsvg"]"#,
+            r#"render-command = "dot -Tsvg""#,
+        ] {
+            let table: Value = toml::from_str(invalid).unwrap();
+            assert!(
+                GraphvizConfig::from_table(table.as_table()).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn info_string_from_config() {
+        let config = GraphvizConfig::from_table(None).unwrap();
+        assert_eq!(config.info_string_keyword(), "dot process");
+
+        let table: Value = toml::from_str(r#"info-string = "graphviz""#).unwrap();
+        let config = GraphvizConfig::from_table(table.as_table()).unwrap();
+        assert_eq!(config.info_string_keyword(), "graphviz");
+
+        for invalid in &[r#""dot`""#, r#""dot\nprocess""#, r#""  ""#, "true"] {
+            let table: Value = toml::from_str(&format!("info-string = {}", invalid)).unwrap();
+            assert!(
+                GraphvizConfig::from_table(table.as_table()).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn unflatten_from_config() {
+        assert_eq!(unflatten_config(&Value::Boolean(false)).unwrap(), None);
+        assert_eq!(
+            unflatten_config(&Value::Boolean(true)).unwrap(),
+            Some(Unflatten::default())
+        );
+
+        let table: Value = toml::from_str("leaf-stagger = 4\nfanout = true").unwrap();
+        assert_eq!(
+            unflatten_config(&table).unwrap(),
+            Some(Unflatten {
+                leaf_stagger: Some(4),
+                fanout: true,
+            })
+        );
+
+        let table: Value = toml::from_str("leaf-stagger = 0").unwrap();
+        assert!(unflatten_config(&table).is_err());
+        let table: Value = toml::from_str("leaf-stagger = 4294967297").unwrap();
+        assert!(unflatten_config(&table)
+            .unwrap_err()
+            .to_string()
+            .contains("too large"));
+        assert!(unflatten_config(&Value::String("yes".into())).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use std::convert::TryFrom;
+
+use mdbook::errors::{Error, Result};
+use serde::Deserialize;
+
+pub static CHAPTER: &str = "chapter";
+pub static NAME: &str = "name";
+pub static INDEX: &str = "index";
+pub static HASH: &str = "hash";
+pub static FORMAT: &str = "format";
+
+static PLACEHOLDERS: &[&str] = &[CHAPTER, NAME, INDEX, HASH, FORMAT];
+/// what's dropped along with an empty placeholder
+static SEPARATORS: &[char] = &['_', '-', '.'];
+
+/// Our naming scheme from before it could be configured
+static DEFAULT_TEMPLATE: &str = "{chapter}_{name}_{index}";
+/// `filenames = "hashed"`, a changed graph gets a new name so images can be cached forever
+static HASHED_TEMPLATE: &str = "{chapter}_{name}.{hash}";
+
+/// How generated image files are named, e.g. `{chapter}_{name}_{index}`. `.generated.<format>` is
+/// always added so the files are easy to tell apart from hand made ones
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct FilenameTemplate {
+    template: String,
+}
+
+enum Part<'a> {
+    Literal(&'a str),
+    Placeholder(&'a str),
+}
+
+impl FilenameTemplate {
+    pub fn parse(template: &str) -> Result<FilenameTemplate> {
+        let parts = parts(template)?;
+
+        for part in &parts {
+            match part {
+                Part::Placeholder(name) if !PLACEHOLDERS.contains(name) => {
+                    return Err(Error::msg(format!(
+                        "Unknown placeholder {{{}}} in filename-template `{}`, use {}",
+                        name,
+                        template,
+                        placeholder_list()
+                    )))
+                }
+                Part::Literal(text)
+                    if text.contains("..")
+                        || text.contains(|ch: char| ch == '/' || ch == '\\' || ch.is_control()) =>
+                {
+                    return Err(Error::msg(format!(
+                        "filename-template `{}` must be a file name without a path",
+                        template
+                    )))
+                }
+                _ => {}
+            }
+        }
+
+        // without one of these every block in a chapter could get the same name
+        let unique = parts.iter().any(|part| match part {
+            Part::Placeholder(name) => *name == INDEX || *name == HASH,
+            Part::Literal(_) => false,
+        });
+        if !unique {
+            return Err(Error::msg(format!(
+                "filename-template `{}` needs an {{{}}} or {{{}}} placeholder so every graph gets its own file",
+                template, INDEX, HASH
+            )));
+        }
+
+        Ok(FilenameTemplate {
+            template: template.into(),
+        })
+    }
+
+    /// Fill in the placeholders. An empty value takes the separator before it along, so an
+    /// unnamed graph is `chapter_0` rather than `chapter__0`, or the one after it when it starts
+    /// the name
+    pub fn expand<F: Fn(&str) -> String>(&self, value: F) -> String {
+        let mut name = String::new();
+        let mut drop_separator = false;
+        // the template was checked when it was parsed
+        for part in parts(&self.template).unwrap_or_default() {
+            match part {
+                Part::Literal(text) if drop_separator => {
+                    name.push_str(text.trim_start_matches(SEPARATORS));
+                }
+                Part::Literal(text) => name.push_str(text),
+                Part::Placeholder(placeholder) => {
+                    let value = value(placeholder);
+                    if !value.is_empty() {
+                        name.push_str(&value);
+                    } else if name.ends_with(SEPARATORS) {
+                        name.pop();
+                    } else if name.is_empty() {
+                        drop_separator = true;
+                        continue;
+                    }
+                }
+            }
+            drop_separator = false;
+        }
+
+        name
+    }
+}
+
+impl FilenameTemplate {
+    pub fn hashed() -> FilenameTemplate {
+        FilenameTemplate {
+            template: HASHED_TEMPLATE.into(),
+        }
+    }
+}
+
+impl Default for FilenameTemplate {
+    fn default() -> FilenameTemplate {
+        FilenameTemplate {
+            template: DEFAULT_TEMPLATE.into(),
+        }
+    }
+}
+
+impl TryFrom<String> for FilenameTemplate {
+    type Error = String;
+
+    fn try_from(template: String) -> std::result::Result<FilenameTemplate, String> {
+        FilenameTemplate::parse(&template).map_err(|e| e.to_string())
+    }
+}
+
+fn placeholder_list() -> String {
+    PLACEHOLDERS
+        .iter()
+        .map(|placeholder| format!("{{{}}}", placeholder))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn parts(template: &str) -> Result<Vec<Part<'_>>> {
+    let mut parts = vec![];
+    let mut rest = template;
+
+    while !rest.is_empty() {
+        match rest.find(&['{', '}'][..]) {
+            Some(start) if rest[start..].starts_with('{') => {
+                if start > 0 {
+                    parts.push(Part::Literal(&rest[..start]));
+                }
+                let end = rest[start..].find('}').ok_or_else(|| {
+                    Error::msg(format!("Unclosed {{ in filename-template `{}`", template))
+                })?;
+                parts.push(Part::Placeholder(&rest[start + 1..start + end]));
+                rest = &rest[start + end + 1..];
+            }
+            Some(_) => {
+                return Err(Error::msg(format!(
+                    "Unmatched }} in filename-template `{}`",
+                    template
+                )))
+            }
+            None => {
+                parts.push(Part::Literal(rest));
+                rest = "";
+            }
+        }
+    }
+
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn expand(template: &FilenameTemplate, name: &str) -> String {
+        template.expand(|placeholder| match placeholder {
+            "chapter" => "intro".into(),
+            "name" => name.into(),
+            "index" => "3".into(),
+            "hash" => "3fa9c2d1".into(),
+            "format" => "png".into(),
+            _ => unreachable!(),
+        })
+    }
+
+    #[test]
+    fn default_naming() {
+        let template = FilenameTemplate::default();
+
+        assert_eq!(expand(&template, "flow"), "intro_flow_3");
+        assert_eq!(expand(&template, ""), "intro_3");
+        assert_eq!(
+            FilenameTemplate::parse("{chapter}_{name}_{index}").unwrap(),
+            template
+        );
+    }
+
+    #[test]
+    fn custom_templates() {
+        let template = FilenameTemplate::parse("{name}.{hash}").unwrap();
+        assert_eq!(expand(&template, "flow"), "flow.3fa9c2d1");
+
+        let template = FilenameTemplate::parse("graph-{index}-{format}").unwrap();
+        assert_eq!(expand(&template, "flow"), "graph-3-png");
+
+        let template = FilenameTemplate::parse("{name}-{index}").unwrap();
+        assert_eq!(expand(&template, ""), "3");
+
+        let template = FilenameTemplate::hashed();
+        assert_eq!(expand(&template, "flow"), "intro_flow.3fa9c2d1");
+        assert_eq!(expand(&template, ""), "intro.3fa9c2d1");
+    }
+
+    #[test]
+    fn invalid_templates() {
+        for invalid in &[
+            "{chapter}_{nmae}_{index}",
+            "{chapter}_{name}",
+            "{chapter}_{index",
+            "{chapter}_index}",
+            "../{index}",
+            "images/{index}",
+            "",
+        ] {
+            assert!(FilenameTemplate::parse(invalid).is_err(), "{}", invalid);
+        }
+
+        let error = FilenameTemplate::parse("{nmae}_{index}")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("{nmae}"), "{}", error);
+        assert!(error.contains("{chapter}, {name}"), "{}", error);
+    }
+}
@@ -0,0 +1,503 @@
+use std::collections::BTreeMap;
+
+use mdbook::errors::{Error, Result};
+
+pub static ALT: &str = "alt";
+pub static CAPTION: &str = "caption";
+pub static WIDTH: &str = "width";
+pub static HEIGHT: &str = "height";
+pub static LINK: &str = "link";
+pub static FILENAME: &str = "filename";
+pub static FILE: &str = "file";
+pub static SKIP: &str = "skip";
+pub static EXPECT_ERROR: &str = "expect-error";
+pub static DPI: &str = "dpi";
+pub static GROUP: &str = "group";
+pub static THUMBNAIL: &str = "thumbnail";
+pub static SOURCE: &str = "source";
+pub static NO_SOURCE: &str = "nosource";
+pub static ENGINE: &str = "engine";
+pub static FORMAT: &str = "format";
+pub static SEED: &str = "seed";
+pub static UNFLATTEN: &str = "unflatten";
+
+/// Every option we understand
+static OPTIONS: &[&str] = &[
+    ALT,
+    CAPTION,
+    WIDTH,
+    HEIGHT,
+    LINK,
+    FILENAME,
+    FILE,
+    SKIP,
+    EXPECT_ERROR,
+    THUMBNAIL,
+    SOURCE,
+    NO_SOURCE,
+    ENGINE,
+    FORMAT,
+    SEED,
+    UNFLATTEN,
+    DPI,
+    GROUP,
+];
+
+/// Options which can be given on their own, `expect-error` is the same as `expect-error=true`
+static FLAGS: &[&str] = &[EXPECT_ERROR, SKIP, SOURCE, NO_SOURCE, UNFLATTEN];
+
+/// The info string of a code block we process:
+/// `<keyword> [key=value | key="quoted value" | flag]... [name]`, the keyword is `dot process`
+/// unless it's configured
+///
+/// Options have to come first, everything from the first word that isn't an option on is the
+/// graph's name, so names can contain spaces, quotes and `=`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InfoString {
+    options: Vec<(String, String)>,
+    name: String,
+}
+
+impl InfoString {
+    /// `None` if this isn't a block we should process
+    pub fn parse(info_string: &str, keyword: &str) -> Result<Option<InfoString>> {
+        // match whole words so `dot processing` or `dotprocess` aren't ours
+        let rest = keyword
+            .split_whitespace()
+            .try_fold(info_string, |rest, word| {
+                strip_word(rest.trim_start(), word)
+            });
+
+        match rest {
+            Some(rest) => parse_options(rest, keyword).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(option, _)| option == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// A `true` or `false` option, which defaults to `false`
+    pub fn flag(&self, key: &str) -> Result<bool> {
+        self.get(key).map_or(Ok(false), |value| {
+            value.parse().map_err(|_| {
+                Error::msg(format!(
+                    "`{}` must be true or false, but is `{}`",
+                    key, value
+                ))
+            })
+        })
+    }
+
+    /// The options that aren't ours, e.g. for a renderer of your own
+    pub fn other_options(&self) -> BTreeMap<String, String> {
+        self.options
+            .iter()
+            .filter(|(key, _)| !OPTIONS.contains(&key.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    /// Problems with the options an author probably didn't intend, like typos in keys or options
+    /// after the name which were read as part of it. `renderer_options` are known as well
+    pub fn problems(&self, renderer_options: &[&str]) -> Vec<String> {
+        let known = |key: &str| OPTIONS.contains(&key) || renderer_options.contains(&key);
+        let unknown = self
+            .options
+            .iter()
+            .filter(|(key, _)| !known(key))
+            .map(|(key, value)| {
+                let mut problem = format!("unknown option `{}={}`", key, value);
+                if let Some(known) = closest_option(key) {
+                    problem.push_str(&format!(", did you mean `{}`?", known));
+                }
+                problem
+            });
+
+        let misplaced = self
+            .name
+            .split_whitespace()
+            .filter(|word| {
+                let key = word.split('=').next().unwrap_or_default();
+                (word.contains('=') && known(key)) || FLAGS.contains(word)
+            })
+            .map(|word| {
+                format!(
+                    "`{}` is read as part of the name, options have to come before it",
+                    word
+                )
+            });
+
+        unknown.chain(misplaced).collect()
+    }
+}
+
+/// A known option close enough to `key` to be a typo of it
+fn closest_option(key: &str) -> Option<&'static str> {
+    OPTIONS
+        .iter()
+        .map(|option| (edit_distance(key, option), *option))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, option)| option)
+}
+
+/// Levenshtein distance, keys are short so the simple version is fine
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_ch) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_ch) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_ch == *b_ch { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+fn strip_word<'a>(input: &'a str, word: &str) -> Option<&'a str> {
+    input
+        .strip_prefix(word)
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+}
+
+fn parse_options(mut rest: &str, keyword: &str) -> Result<InfoString> {
+    let mut info_string = InfoString::default();
+
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+
+        let (key, value, remainder) = match parse_option(rest, keyword)? {
+            Some(option) => option,
+            None => {
+                info_string.name = rest.trim_end().into();
+                break;
+            }
+        };
+
+        if info_string.get(key).is_some() {
+            return Err(Error::msg(format!(
+                "`{}` is given more than once in `{}`",
+                key, keyword
+            )));
+        }
+        info_string.options.push((key.into(), value));
+        rest = remainder;
+    }
+
+    Ok(info_string)
+}
+
+/// Parse `key=value`, `key="value"` or a flag from the start of `input`, returning the key, the
+/// value and the unparsed rest
+fn parse_option<'a>(input: &'a str, keyword: &str) -> Result<Option<(&'a str, String, &'a str)>> {
+    let key_end = input
+        .find(|ch: char| !(ch.is_ascii_lowercase() || ch.is_ascii_digit() || ch == '-'))
+        .unwrap_or(input.len());
+    let key = &input[..key_end];
+    if !key.starts_with(|ch: char| ch.is_ascii_lowercase()) {
+        return Ok(None);
+    }
+
+    let after_key = &input[key_end..];
+    if after_key.is_empty() || after_key.starts_with(char::is_whitespace) {
+        return Ok(if FLAGS.contains(&key) {
+            Some((key, "true".into(), after_key))
+        } else {
+            None
+        });
+    }
+
+    let after_equals = match after_key.strip_prefix('=') {
+        Some(after_equals) => after_equals,
+        None => return Ok(None),
+    };
+
+    let (value, rest) = match after_equals.chars().next() {
+        Some(quote @ '"') | Some(quote @ '\'') => {
+            parse_quoted(key, quote, &after_equals[1..], keyword)?
+        }
+        _ => {
+            let value_end = after_equals
+                .find(char::is_whitespace)
+                .unwrap_or(after_equals.len());
+            (
+                after_equals[..value_end].to_string(),
+                &after_equals[value_end..],
+            )
+        }
+    };
+
+    if !(rest.is_empty() || rest.starts_with(char::is_whitespace)) {
+        return Err(Error::msg(format!(
+            "Expected a space after the value of `{}` in `{}`, found `{}`",
+            key, keyword, rest
+        )));
+    }
+
+    Ok(Some((key, value, rest)))
+}
+
+/// Read a quoted value up to its closing `quote`, `\` escapes the next character
+fn parse_quoted<'a>(
+    key: &str,
+    quote: char,
+    input: &'a str,
+    keyword: &str,
+) -> Result<(String, &'a str)> {
+    let mut value = String::new();
+    let mut chars = input.char_indices();
+
+    while let Some((index, ch)) = chars.next() {
+        match ch {
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    value.push(escaped);
+                }
+            }
+            _ if ch == quote => return Ok((value, &input[index + 1..])),
+            _ => value.push(ch),
+        }
+    }
+
+    Err(Error::msg(format!(
+        "The value of `{}` is missing its closing {} in `{}`",
+        key, quote, keyword
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preprocessor::INFO_STRING_PREFIX;
+
+    fn parse(info_string: &str) -> InfoString {
+        InfoString::parse(info_string, INFO_STRING_PREFIX)
+            .unwrap()
+            .unwrap()
+    }
+
+    fn options(info_string: &InfoString) -> Vec<(&str, &str)> {
+        info_string
+            .options
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn not_ours() {
+        for info_string in &[
+            "",
+            "dot",
+            "dot processing",
+            "dot processor",
+            "dot processing-notes",
+            "dotprocess",
+            "dots process",
+            "rust process",
+        ] {
+            assert_eq!(
+                InfoString::parse(info_string, INFO_STRING_PREFIX).unwrap(),
+                None,
+                "{}",
+                info_string
+            );
+        }
+    }
+
+    #[test]
+    fn whitespace_between_words() {
+        assert_eq!(parse("dot  process"), InfoString::default());
+        assert_eq!(parse("dot\tprocess Name").name(), "Name");
+    }
+
+    #[test]
+    fn configured_keyword() {
+        let info_string = InfoString::parse("graphviz alt=text Name", "graphviz")
+            .unwrap()
+            .unwrap();
+        assert_eq!(options(&info_string), vec![("alt", "text")]);
+        assert_eq!(info_string.name(), "Name");
+
+        assert_eq!(
+            InfoString::parse("graphviz", "graphviz").unwrap(),
+            Some(InfoString::default())
+        );
+        assert_eq!(InfoString::parse("graphviz2", "graphviz").unwrap(), None);
+        assert_eq!(InfoString::parse("dot process", "graphviz").unwrap(), None);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(parse("dot process"), InfoString::default());
+        assert_eq!(parse("dot process   "), InfoString::default());
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(parse("dot process Name").name(), "Name");
+        assert_eq!(
+            parse("dot process  A longer name  ").name(),
+            "A longer name"
+        );
+        assert_eq!(parse("dot process Graph \"Name\"").name(), "Graph \"Name\"");
+        assert_eq!(parse("dot process 2 graphs").name(), "2 graphs");
+    }
+
+    #[test]
+    fn names_containing_equals() {
+        let info_string = parse("dot process E = mc2");
+        assert_eq!(info_string.name(), "E = mc2");
+        assert!(options(&info_string).is_empty());
+
+        // options are only read before the name
+        let info_string = parse("dot process Name alt=text");
+        assert_eq!(info_string.name(), "Name alt=text");
+        assert!(options(&info_string).is_empty());
+
+        let info_string = parse("dot process Total=5");
+        assert_eq!(info_string.name(), "Total=5");
+    }
+
+    #[test]
+    fn options_and_name() {
+        let info_string = parse("dot process alt=text width=200 Name");
+        assert_eq!(
+            options(&info_string),
+            vec![("alt", "text"), ("width", "200")]
+        );
+        assert_eq!(info_string.name(), "Name");
+        assert_eq!(info_string.get("alt"), Some("text"));
+        assert_eq!(info_string.get("missing"), None);
+    }
+
+    #[test]
+    fn quoted_values() {
+        let info_string = parse(r#"dot process alt="A graph with spaces" title='single' Name"#);
+        assert_eq!(
+            options(&info_string),
+            vec![("alt", "A graph with spaces"), ("title", "single")]
+        );
+        assert_eq!(info_string.name(), "Name");
+
+        let info_string = parse(r#"dot process alt="say \"hi\" \\o/" caption="""#);
+        assert_eq!(
+            options(&info_string),
+            vec![("alt", r#"say "hi" \o/"#), ("caption", "")]
+        );
+        assert_eq!(info_string.name(), "");
+    }
+
+    #[test]
+    fn invalid_quoting() {
+        let error =
+            InfoString::parse(r#"dot process alt="open Name"#, INFO_STRING_PREFIX).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The value of `alt` is missing its closing \" in `dot process`"
+        );
+
+        assert!(InfoString::parse(r#"dot process alt="text"Name"#, INFO_STRING_PREFIX).is_err());
+    }
+
+    #[test]
+    fn flags() {
+        let info_string = parse("dot process expect-error Name");
+        assert!(info_string.flag(EXPECT_ERROR).unwrap());
+        assert_eq!(info_string.name(), "Name");
+
+        assert!(parse("dot process expect-error=true")
+            .flag(EXPECT_ERROR)
+            .unwrap());
+        assert!(!parse("dot process expect-error=false")
+            .flag(EXPECT_ERROR)
+            .unwrap());
+        assert!(!parse("dot process Name").flag(EXPECT_ERROR).unwrap());
+        assert!(!parse("dot process Name expect-error")
+            .flag(EXPECT_ERROR)
+            .unwrap());
+
+        assert!(parse("dot process expect-error=maybe")
+            .flag(EXPECT_ERROR)
+            .is_err());
+
+        // only known flags can be given without a value, anything else starts the name
+        assert_eq!(parse("dot process keep-going").name(), "keep-going");
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let info_string = parse("dot process colour=blue expect-error Name");
+        assert_eq!(
+            options(&info_string),
+            vec![("colour", "blue"), (EXPECT_ERROR, "true")]
+        );
+        assert_eq!(info_string.get("colour"), Some("blue"));
+        assert_eq!(
+            info_string.other_options(),
+            BTreeMap::from([("colour".to_string(), "blue".to_string())])
+        );
+    }
+
+    #[test]
+    fn problems() {
+        assert!(parse("dot process alt=text skip A Name")
+            .problems(&[])
+            .is_empty());
+        assert!(parse("dot process E = mc2").problems(&[]).is_empty());
+
+        assert_eq!(
+            parse("dot process fromat=png Name").problems(&[]),
+            vec!["unknown option `fromat=png`, did you mean `format`?"]
+        );
+        assert_eq!(
+            parse("dot process captoin=Text Name").problems(&[]),
+            vec!["unknown option `captoin=Text`, did you mean `caption`?"]
+        );
+        assert_eq!(
+            parse("dot process Name alt=text skip").problems(&[]),
+            vec![
+                "`alt=text` is read as part of the name, options have to come before it",
+                "`skip` is read as part of the name, options have to come before it",
+            ]
+        );
+
+        // a renderer's own options
+        assert!(parse("dot process theme=dark Name")
+            .problems(&["theme"])
+            .is_empty());
+        assert_eq!(
+            parse("dot process Name theme=dark").problems(&["theme"]),
+            vec!["`theme=dark` is read as part of the name, options have to come before it"]
+        );
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("alt", "alt"), 0);
+        assert_eq!(edit_distance("atl", "alt"), 2);
+        assert_eq!(edit_distance("with", "width"), 1);
+        assert_eq!(edit_distance("", "skip"), 4);
+    }
+
+    #[test]
+    fn repeated_keys() {
+        assert!(InfoString::parse("dot process alt=a alt=b", INFO_STRING_PREFIX).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use mdbook::errors::{Error, Result};
+
+/// The stylesheet carousels of grouped graphs need, the book lists it in `additional-css`
+pub static STYLESHEET_FILE: &str = "mdbook-graphviz.css";
+static STYLESHEET: &str = include_str!("carousel.css");
+
+/// Write our stylesheet to a book's root directory, returning where it went
+pub fn install(book_root: &Path) -> Result<PathBuf> {
+    let path = book_root.join(STYLESHEET_FILE);
+    if fs::read_to_string(&path).is_ok_and(|installed| installed == STYLESHEET) {
+        return Ok(path);
+    }
+
+    fs::write(&path, STYLESHEET)
+        .map_err(|e| Error::msg(format!("Couldn't write {}: {}", path.display(), e)))?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn writes_the_stylesheet() {
+        let root = env::temp_dir().join(format!("mdbook-graphviz-install-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+
+        let path = install(&root).unwrap();
+        assert_eq!(path, root.join("mdbook-graphviz.css"));
+        let stylesheet = fs::read_to_string(&path).unwrap();
+        assert!(stylesheet.contains(".graphviz-slide-selector:checked + .graphviz-slide"));
+
+        // installing again updates an outdated copy
+        fs::write(&path, "/* old */").unwrap();
+        install(&root).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), stylesheet);
+
+        assert!(install(&root.join("missing")).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+//! An mdbook preprocessor turning graphviz code blocks into images.
+//!
+//! Besides the `mdbook-graphviz` binary mdbook runs, the preprocessor can be used as a library,
+//! e.g. to render graphs with your own [`GraphvizRenderer`] in a pipeline built on mdbook's
+//! [`Book`](mdbook::book::Book):
+//!
+//! ```
+//! use std::path::Path;
+//!
+//! use mdbook::book::{Book, Chapter};
+//! use mdbook::BookItem;
+//! use mdbook_graphviz::{
+//!     inline_events, Graphviz, GraphvizConfig, GraphvizRenderer, RenderContext, RenderResult,
+//! };
+//!
+//! /// Puts a placeholder where each graph goes
+//! struct Placeholder;
+//!
+//! impl GraphvizRenderer for Placeholder {
+//!     fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+//!         let svg = format!("<svg><title>{}</title></svg>", context.block.title());
+//!         Ok(inline_events(svg).into())
+//!     }
+//! }
+//!
+//! let mut book = Book::new();
+//! book.push_item(Chapter::new(
+//!     "Intro",
+//!     "```dot process Flow\ndigraph { a -> b }\n```\n".into(),
+//!     "intro.md",
+//!     vec![],
+//! ));
+//!
+//! let config = GraphvizConfig::default();
+//! let book = Graphviz::new(Placeholder, config).process_book(book, Path::new("src"))?;
+//!
+//! match book.iter().next() {
+//!     Some(BookItem::Chapter(chapter)) => {
+//!         assert_eq!(chapter.content, "<div><svg><title>Flow</title></svg></div>\n")
+//!     }
+//!     _ => unreachable!(),
+//! }
+//! # Ok::<(), mdbook::errors::Error>(())
+//! ```
+
+#[macro_use]
+extern crate lazy_static;
+
+mod cache;
+mod config;
+mod filename_template;
+mod info_string;
+mod install;
+mod manifest;
+mod native;
+mod preprocessor;
+mod progress;
+mod renderer;
+
+pub use crate::config::{Backend, ChapterNames, Filenames, GraphvizConfig, OnMissing};
+pub use crate::filename_template::FilenameTemplate;
+pub use crate::install::{install, STYLESHEET_FILE};
+pub use crate::preprocessor::{
+    Graphviz, GraphvizBlock, GraphvizPreprocessor, SourceLine, SubGraph, PREPROCESSOR_NAME,
+};
+pub use crate::renderer::{
+    image_events, inline_events, Failure, Filter, GraphvizRenderer, GraphvizVersion, RenderContext,
+    RenderError, RenderResult, Rendered, RendererError, Unflatten,
+};
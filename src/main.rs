@@ -1,17 +1,14 @@
-#[macro_use]
-extern crate lazy_static;
-
 use std::io;
+use std::path::Path;
 use std::process;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
+use env_logger::Env;
+use log::{error, info, warn};
 use mdbook::errors::Error;
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
 
-use crate::preprocessor::{GraphvizPreprocessor, PREPROCESSOR_NAME};
-
-mod preprocessor;
-mod renderer;
+use mdbook_graphviz::{install, GraphvizPreprocessor, PREPROCESSOR_NAME, STYLESHEET_FILE};
 
 pub fn make_app() -> App<'static, 'static> {
     App::new(PREPROCESSOR_NAME)
@@ -21,17 +18,31 @@ pub fn make_app() -> App<'static, 'static> {
                 .arg(Arg::with_name("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            SubCommand::with_name("install")
+                .arg(
+                    Arg::with_name("dir")
+                        .default_value(".")
+                        .help("The root directory of the book"),
+                )
+                .about("Write the stylesheet carousels of grouped graphs need to a book"),
+        )
 }
 
 fn main() {
+    // like mdbook, info and up unless RUST_LOG says otherwise
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+
     let matches = make_app().get_matches();
 
     let preprocessor = GraphvizPreprocessor;
 
     if let Some(sub_args) = matches.subcommand_matches("supports") {
         handle_supports(&preprocessor, sub_args);
+    } else if let Some(sub_args) = matches.subcommand_matches("install") {
+        handle_install(sub_args);
     } else if let Err(e) = handle_preprocessing(&preprocessor) {
-        eprintln!("{}", e);
+        error!("{}", e);
         process::exit(1);
     }
 }
@@ -42,8 +53,8 @@ fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
     if ctx.mdbook_version != mdbook::MDBOOK_VERSION {
         // We should probably use the `semver` crate to check compatibility
         // here...
-        eprintln!(
-            "Warning: The {} plugin was built against version {} of mdbook, \
+        warn!(
+            "The {} plugin was built against version {} of mdbook, \
              but we're being called from version {}",
             pre.name(),
             mdbook::MDBOOK_VERSION,
@@ -57,9 +68,24 @@ fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
     Ok(())
 }
 
+fn handle_install(sub_args: &ArgMatches) {
+    let dir = sub_args.value_of("dir").expect("Has a default");
+    match install(Path::new(dir)) {
+        Ok(path) => info!(
+            "Wrote {}, add \"{}\" to additional-css in the [output.html] table of book.toml",
+            path.display(),
+            STYLESHEET_FILE
+        ),
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
 fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> ! {
     let renderer = sub_args.value_of("renderer").expect("Required argument");
-    let supported = pre.supports_renderer(&renderer);
+    let supported = pre.supports_renderer(renderer);
 
     // Signal whether the renderer is supported by exiting with 1 or 0.
     if supported {
@@ -0,0 +1,123 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use mdbook::errors::{Error, Result};
+use serde::Serialize;
+
+/// A rendered graph as listed in the `manifest`, for tools like search indexes or galleries that
+/// build on the book's graphs
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ManifestEntry {
+    /// the chapter's markdown file, relative to the book's `src`
+    pub chapter: String,
+    pub chapter_title: String,
+    pub name: String,
+    /// the image file, relative to the book root. Not set when the image is in the chapter
+    pub file: Option<String>,
+    pub format: String,
+    /// the short hash of the code and the options it's rendered with
+    pub hash: String,
+    /// set when the image was meant to be inlined, but was written to its `file` since the chapter
+    /// held `max-inline-total-bytes` of svg already
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub spilled: bool,
+}
+
+/// Every graph rendered for the book, in the book's order so the manifest diffs cleanly
+#[derive(Debug)]
+pub struct Manifest {
+    /// image files are listed relative to it
+    root: PathBuf,
+    entries: Mutex<Vec<ManifestEntry>>,
+}
+
+impl Manifest {
+    pub fn new(root: &Path) -> Manifest {
+        Manifest {
+            root: root.to_path_buf(),
+            entries: Mutex::new(vec![]),
+        }
+    }
+
+    /// The path of an image file as the manifest lists it
+    pub fn file(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .replace('\\', "/")
+    }
+
+    /// Add a chapter's graphs, chapters are added one after the other
+    pub fn add(&self, entries: Vec<ManifestEntry>) {
+        self.entries.lock().unwrap().extend(entries);
+    }
+
+    /// Write the manifest as json to `path`, leaving it alone when it's unchanged so `mdbook serve`
+    /// doesn't build again when it's in `src`
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let entries = self.entries.lock().unwrap();
+        let mut json = serde_json::to_string_pretty(&*entries)?;
+        json.push('\n');
+
+        let written = match fs::read_to_string(path) {
+            Ok(ref existing) if *existing == json => Ok(()),
+            _ => path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(path, json)),
+        };
+
+        written.map_err(|e| {
+            Error::msg(format!(
+                "Couldn't write the graphviz manifest to {}: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn files_relative_to_the_root() {
+        let manifest = Manifest::new(Path::new("/books/guide"));
+
+        assert_eq!(
+            manifest.file(Path::new("/books/guide/src/intro_flow.generated.svg")),
+            "src/intro_flow.generated.svg"
+        );
+        // an output-dir outside the book
+        assert_eq!(
+            manifest.file(Path::new("/var/www/images/intro_flow.generated.svg")),
+            "/var/www/images/intro_flow.generated.svg"
+        );
+    }
+
+    #[test]
+    fn unchanged_manifests_arent_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("generated/graphviz-manifest.json");
+        let manifest = Manifest::new(dir.path());
+        manifest.add(vec![ManifestEntry {
+            chapter: "intro.md".into(),
+            chapter_title: "Intro".into(),
+            name: "Flow".into(),
+            file: None,
+            format: "svg".into(),
+            hash: "432a3c80".into(),
+            spilled: false,
+        }]);
+
+        manifest.write(&path).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(written.contains("\"file\": null"), "{}", written);
+
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        manifest.write(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+    }
+}
@@ -0,0 +1,80 @@
+use mdbook::errors::{Error, Result};
+
+use crate::renderer::describe_graph;
+
+/// Lay `code` out and draw it as an svg in rust with the `layout-rs` crate, so graphviz doesn't
+/// have to be installed. It has a single dot-like layout and understands part of the dot language
+#[cfg(feature = "native-layout")]
+pub fn render(graph_name: &str, code: &str) -> Result<Vec<u8>> {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use layout::backends::svg::SVGWriter;
+    use layout::gv::{DotParser, GraphBuilder};
+
+    let unsupported = |reason: &str| {
+        Error::msg(format!(
+            "The rust backend can't draw {}: {}. It only understands part of the dot language, \
+             set backend = \"dot\" and install graphviz for graphs like this one",
+            describe_graph(graph_name),
+            reason
+        ))
+    };
+
+    let graph = DotParser::new(code)
+        .process()
+        .map_err(|e| unsupported(&e))?;
+    // it panics on parts of dot it doesn't support rather than returning an error
+    let svg = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut builder = GraphBuilder::new();
+        builder.visit_graph(&graph);
+        let mut visual_graph = builder.get();
+        let mut svg = SVGWriter::new();
+        visual_graph.do_it(false, false, false, &mut svg);
+        svg.finalize()
+    }))
+    .map_err(|panic| {
+        let reason = panic
+            .downcast_ref::<&str>()
+            .map(|reason| reason.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "its layout failed".into());
+        unsupported(&reason)
+    })?;
+
+    Ok(svg.into_bytes())
+}
+
+/// The config only allows the rust backend when it's built in
+#[cfg(not(feature = "native-layout"))]
+pub fn render(graph_name: &str, _code: &str) -> Result<Vec<u8>> {
+    Err(Error::msg(format!(
+        "Couldn't draw {}, mdbook-graphviz was built without the native-layout feature",
+        describe_graph(graph_name)
+    )))
+}
+
+#[cfg(all(test, feature = "native-layout"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn simple_digraph() {
+        let svg = String::from_utf8(render("Flow", "digraph { a -> b; b -> c }").unwrap()).unwrap();
+
+        assert!(svg.trim_end().ends_with("</svg>"), "{}", svg);
+        assert!(svg.contains(">a<"), "{}", svg);
+    }
+
+    #[test]
+    fn unsupported_dot() {
+        let error = render("Broken", "digraph { a -> }").unwrap_err();
+
+        assert!(
+            error
+                .to_string()
+                .starts_with("The rust backend can't draw graph 'Broken': "),
+            "{}",
+            error
+        );
+    }
+}
@@ -1,23 +1,72 @@
-use std::marker::PhantomData;
-use std::path::PathBuf;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use log::{info, warn};
 use mdbook::book::{Book, Chapter};
-use mdbook::errors::Result;
+use mdbook::errors::{Error, Result};
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
 use mdbook::BookItem;
-use pulldown_cmark::{Event, Parser, Tag};
+use pulldown_cmark::{CowStr, Event, Options, Parser, Tag};
 use pulldown_cmark_to_cmark::fmt::cmark;
-use toml::Value;
+use regex::Regex;
 
-use crate::renderer::{CLIGraphviz, CLIGraphvizToFile, GraphvizRenderer};
+use crate::cache::{Cache, DEFAULT_MAX_BYTES};
+use crate::config::{Backend, ChapterNames, Filenames, GraphvizConfig, OnMissing, FORMATS, SVG};
+use crate::filename_template::{FilenameTemplate, CHAPTER, FORMAT, HASH, INDEX, NAME};
+use crate::info_string::{
+    InfoString, ALT, CAPTION, DPI, ENGINE, EXPECT_ERROR, FILE, FILENAME, FORMAT as FORMAT_OPTION,
+    GROUP, HEIGHT, LINK, NO_SOURCE, SEED, SKIP, SOURCE, THUMBNAIL, UNFLATTEN, WIDTH,
+};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::progress::Progress;
+use crate::renderer::{
+    describe_graph, find_dot, CLIGraphviz, CLIGraphvizCheck, CLIGraphvizEmbedded,
+    CLIGraphvizToFile, ClientSide, DumpDot, Failure, GraphvizCommand, GraphvizRenderer,
+    RenderContext, RenderError, RenderResult, RendererError, Unflatten, WarmUp, ENGINES,
+};
 
 pub static PREPROCESSOR_NAME: &str = "graphviz";
 pub static INFO_STRING_PREFIX: &str = "dot process";
+/// what blocks marked with `skip` are turned into, so they're still highlighted as dot and left
+/// alone when our output is processed again, `render-all-dot` included
+static SKIPPED_FENCE: &str = "dot skip";
+/// `link=self` links an image to its own file
+static LINK_SELF: &str = "self";
+/// placeholders of `alt-template`
+pub static ALT_NAME: &str = "{name}";
+pub static ALT_CHAPTER: &str = "{chapter}";
+pub static ALT_INDEX: &str = "{index}";
+/// added to the file name of thumbnails
+static THUMBNAIL_SUFFIX: &str = "_thumb";
+/// in the name of every image file we generate, hand made ones are never pruned
+pub static GENERATED: &str = ".generated.";
+static GITIGNORE: &str = ".gitignore";
 
+/// The preprocessor mdbook runs, configured from `book.toml`
 pub struct GraphvizPreprocessor;
 
-pub struct Graphviz<R: GraphvizRenderer> {
-    _phantom: PhantomData<*const R>,
+/// Renders the graphs of a book's chapters with a [`GraphvizRenderer`]
+pub struct Graphviz<'c, R: GraphvizRenderer> {
+    renderer: R,
+    config: GraphvizConfig,
+    /// shared by every chapter of the book
+    progress: Option<&'c Progress>,
+    /// every image file written so far and the block it's from, shared by every chapter so two
+    /// chapters can't overwrite each other's images
+    outputs: Option<&'c Mutex<HashMap<PathBuf, String>>>,
+    /// the book's `src` directory, where `file` options are looked up after the chapter's directory
+    src_dir: Option<&'c Path>,
+    /// where rendered graphs are listed with `manifest`, shared by every chapter
+    manifest: Option<&'c Manifest>,
 }
 
 impl Preprocessor for GraphvizPreprocessor {
@@ -26,316 +75,6497 @@ impl Preprocessor for GraphvizPreprocessor {
     }
 
     fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        let output_to_file = ctx
-            .config
-            .get_preprocessor(self.name())
-            .and_then(|t| t.get("output-to-file"))
-            .and_then(Value::as_bool)
-            .unwrap_or(false);
+        let mut config = GraphvizConfig::from_table(ctx.config.get_preprocessor(self.name()))?;
+        if !config.processes_renderer(&ctx.renderer) {
+            info!(
+                "Not processing graphs for the {} renderer, it isn't in the configured renderers",
+                ctx.renderer
+            );
+            return Ok(book);
+        }
+        if config.backend == Backend::None {
+            info!("Leaving graphs as they are since backend = \"none\"");
+            return Ok(book);
+        }
+        let downgrades = config.adapt_to_renderer(&ctx.renderer);
+        for downgrade in &downgrades {
+            warn!("{}", downgrade);
+        }
+        let src_dir = resolve_src_dir(&ctx.root, &ctx.config.book.src)?;
+        if let Some(ref output_dir) = config.output_dir {
+            config.output_dir = Some(if config.check {
+                book_path(&ctx.root, output_dir)?
+            } else {
+                resolve_output_dir(&ctx.root, output_dir)?
+            });
+        }
+        if config.update_gitignore && !config.check {
+            update_gitignore(&book_path(&ctx.root, Path::new(""))?, &src_dir, &config)?;
+        }
+        let command = match probe_graphviz(&config, &ctx.root) {
+            // nothing's written when checking, the cache included
+            Ok(command) if config.check => GraphvizCommand {
+                cache: None,
+                ..command
+            },
+            Ok(command) => command,
+            Err(e) if config.on_missing == OnMissing::Skip => {
+                warn!(
+                    "Leaving graphs as dot code blocks since on-missing = \"skip\": {}",
+                    e
+                );
+                config.graphviz_missing = true;
+                GraphvizCommand::default()
+            }
+            Err(e) => return Err(e),
+        };
+
+        let total = if config.graphviz_missing {
+            0
+        } else {
+            book.iter()
+                .map(|item| match item {
+                    BookItem::Chapter(chapter) => count_graphs(&config, &chapter.content),
+                    _ => 0,
+                })
+                .sum()
+        };
+        let progress = Progress::new(total, config.quiet);
+        let command = match config.warm_up {
+            Some(threshold) if total > threshold => GraphvizCommand {
+                warm_up: Some(Arc::new(WarmUp::default())),
+                ..command
+            },
+            _ => command,
+        };
+        let started = Instant::now();
+        let outputs = Mutex::new(HashMap::new());
+        // nothing's written when checking
+        let manifest = match config.manifest {
+            Some(ref path) if !config.check => Some((
+                Manifest::new(&book_path(&ctx.root, Path::new(""))?),
+                book_path(&ctx.root, path)?,
+            )),
+            _ => None,
+        };
+
+        // every chapter is processed even after a failure, so all broken graphs are reported at once
+        let mut errors = vec![];
+        // every image file this build is responsible for
+        let mut images = HashSet::new();
+
+        book.for_each_mut(|item: &mut BookItem| {
+            if let BookItem::Chapter(ref mut chapter) = item {
+                // a draft chapter has no file yet, so nothing to render and nowhere to put images.
+                // Its sub chapters are visited on their own
+                let path = match chapter.path {
+                    Some(ref path) => path,
+                    None => return,
+                };
+                let mut full_path = src_dir.join(path);
+
+                // remove the chapter filename
+                full_path.pop();
+
+                // the book is passed on as it is when checking, so only a copy is changed
+                let mut copy;
+                let chapter = if config.check {
+                    copy = chapter.clone();
+                    &mut copy
+                } else {
+                    chapter
+                };
+                let chapter_images =
+                    Graphviz::new(chapter_renderer(&config, &command), config.clone())
+                        .with_progress(&progress)
+                        .with_outputs(&outputs)
+                        .with_src_dir(&src_dir)
+                        .with_manifest(manifest.as_ref().map(|(manifest, _)| manifest))
+                        .process_chapter(chapter, &full_path);
+                match chapter_images {
+                    Ok(chapter_images) => images.extend(chapter_images),
+                    Err(e) => errors.push(e),
+                }
+            }
+        });
+
+        if let Some(ref cache) = command.cache {
+            cache.trim(config.cache_max_bytes.unwrap_or(DEFAULT_MAX_BYTES));
+            info!("{}", cache.summary());
+        }
+        if config.stats {
+            info!(
+                "{}",
+                progress.stats(started.elapsed(), command.cache.as_deref())
+            );
+        }
+        // the warnings are long gone once every chapter has reported its graphs
+        if let Some(summary) = downgrade_summary(&ctx.renderer, &downgrades) {
+            info!("{}", summary);
+        }
+        if !errors.is_empty() {
+            return Err(GraphErrors::combine(errors));
+        }
+        // without graphviz nothing was rendered, so everything would look stale
+        if config.prune && !config.graphviz_missing && !config.check {
+            let mut dirs = vec![src_dir.clone()];
+            dirs.extend(config.output_dir.clone());
+            // other renderers' builds write other formats, their images aren't ours to remove
+            let mut formats: HashSet<&str> = images
+                .iter()
+                .filter_map(|image| generated_format(image))
+                .collect();
+            formats.insert(config.image_format());
+            prune(&dirs, &images, &formats);
+        }
+        if let Some((manifest, path)) = manifest {
+            manifest.write(&path)?;
+        }
+
+        Ok(book)
+    }
+
+    fn supports_renderer(&self, _renderer: &str) -> bool {
+        // since we're just outputting markdown images or inline html, this "should" support any renderer
+        true
+    }
+}
+
+/// The renderer for the configured backend. Every chapter gets its own, so
+/// `max-inline-total-bytes` holds per chapter
+fn chapter_renderer(
+    config: &GraphvizConfig,
+    command: &GraphvizCommand,
+) -> Box<dyn GraphvizRenderer> {
+    match config.backend {
+        Backend::Client => Box::new(ClientSide),
+        Backend::Dump => Box::new(DumpDot::new(command.clone())),
+        _ if config.check => Box::new(CLIGraphvizCheck::new(command.clone())),
+        _ if config.embed_images => Box::new(CLIGraphvizEmbedded::new(command.clone())),
+        _ if !config.output_to_file => Box::new(CLIGraphviz::new(
+            command.clone(),
+            config.max_inline_total_bytes,
+        )),
+        _ => Box::new(CLIGraphvizToFile::new(command.clone())),
+    }
+}
+
+/// What was changed for the renderer, for the end of the build
+fn downgrade_summary(renderer: &str, downgrades: &[String]) -> Option<String> {
+    match downgrades {
+        [] => None,
+        downgrades => Some(format!(
+            "Graphs were adapted to the {} renderer: {}",
+            renderer,
+            downgrades.join("; ")
+        )),
+    }
+}
+
+/// Remove generated files in one of `formats` under `dirs` that aren't one of `images`, e.g. from
+/// renamed or deleted graphs
+fn prune(dirs: &[PathBuf], images: &HashSet<PathBuf>, formats: &HashSet<&str>) {
+    let mut generated = HashSet::new();
+    for dir in dirs {
+        generated_files(dir, &mut generated);
+    }
+
+    for path in generated {
+        if !generated_format(&path).is_some_and(|format| formats.contains(format)) {
+            continue;
+        }
+        // the hash files next to images count as part of them
+        let path_name = path.to_string_lossy();
+        if images
+            .iter()
+            .any(|image| path_name.starts_with(&*image.to_string_lossy()))
+        {
+            continue;
+        }
+
+        info!("Removing the stale image {}", path.display());
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Couldn't remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// The format of a generated file, `svg` for `intro_flow.generated.svg` and the
+/// `intro_flow.generated.svg.sha256` next to it
+fn generated_format(path: &Path) -> Option<&str> {
+    let (_, extension) = path.file_name()?.to_str()?.rsplit_once(GENERATED)?;
+
+    extension.split('.').next()
+}
+
+/// Every `*.generated.*` file under `dir`
+fn generated_files(dir: &Path, files: &mut HashSet<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => generated_files(&path, files),
+            Ok(file_type)
+                if file_type.is_file()
+                    && entry.file_name().to_string_lossy().contains(GENERATED) =>
+            {
+                files.insert(path);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Make sure the book's `.gitignore`, or `src/.gitignore` when only that one's there, ignores our
+/// images. Only a missing line is added, everything else in the file is left as it is
+fn update_gitignore(root: &Path, src_dir: &Path, config: &GraphvizConfig) -> Result<()> {
+    let dir = if !root.join(GITIGNORE).exists() && src_dir.join(GITIGNORE).exists() {
+        src_dir
+    } else {
+        root
+    };
+    let path = dir.join(GITIGNORE);
+    // the whole output-dir when it's one of ours, it holds nothing else
+    let pattern = match config
+        .output_dir
+        .as_ref()
+        .and_then(|output_dir| output_dir.strip_prefix(dir).ok())
+        .filter(|relative| !relative.as_os_str().is_empty())
+    {
+        Some(relative) => format!("/{}/", relative.to_string_lossy().replace('\\', "/")),
+        None => format!("*{}*", GENERATED),
+    };
+
+    let existing = match fs::read_to_string(&path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(Error::msg(format!(
+                "Couldn't read {}: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+    if existing.lines().any(|line| line.trim() == pattern) {
+        return Ok(());
+    }
+
+    let newline = if existing.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let mut updated = existing.clone();
+    if !updated.is_empty() && !updated.ends_with('\n') {
+        updated.push_str(newline);
+    }
+    updated.push_str(&pattern);
+    updated.push_str(newline);
+
+    info!("Adding {} to {}", pattern, path.display());
+    fs::write(&path, updated)
+        .map_err(|e| Error::msg(format!("Couldn't update {}: {}", path.display(), e)))
+}
+
+/// Find graphviz and make sure we can run it, once before processing any chapters
+fn probe_graphviz(config: &GraphvizConfig, root: &Path) -> Result<GraphvizCommand> {
+    let command = GraphvizCommand {
+        dot: match config.backend {
+            Backend::Dot if config.render_command.is_none() => {
+                find_dot(config.dot_command.as_deref(), env::var_os("GRAPHVIZ_DOT"))?
+            }
+            // never run
+            _ => GraphvizCommand::default().dot,
+        },
+        // once we know graphviz runs
+        version: None,
+        backend: config.backend,
+        render_command: config.render_command.clone(),
+        timeout: config.render_timeout.map(Duration::from_secs),
+        post_process: config.post_process.clone(),
+        filters: config.filters.clone(),
+        working_dir: root.to_path_buf(),
+        // once we know how many graphs there are
+        warm_up: None,
+        cache: match config.cache_dir {
+            Some(ref cache_dir) if !config.no_cache => {
+                Some(Arc::new(Cache::new(book_path(root, cache_dir)?)))
+            }
+            _ => None,
+        },
+        skip_unchanged: !config.no_cache,
+    };
+
+    let layout_options = config.engine.is_some() || config.seed.is_some();
+    match config.backend {
+        Backend::Rust => {
+            if layout_options {
+                warn!("The rust backend has a single dot-like layout, engine and seed are ignored");
+            }
+            return Ok(command);
+        }
+        // nothing is drawn here
+        Backend::Client | Backend::None => return Ok(command),
+        // filters still run, the code they produce is what's dumped
+        Backend::Dump => return Ok(command),
+        Backend::Dot if config.render_command.is_some() => {
+            if layout_options {
+                warn!(
+                    "engine and seed are only passed to `dot`, the render-command is run as it is"
+                );
+            }
+            return Ok(command);
+        }
+        Backend::Dot => {}
+    }
+
+    let version = command.version()?;
+    if let Some(ref min_version) = config.min_version {
+        if version < *min_version {
+            warn!(
+                "Graphviz {} is older than the configured min-version {}, some output may be missing",
+                version, min_version
+            );
+        }
+    }
+
+    Ok(GraphvizCommand {
+        version: Some(version),
+        ..command
+    })
+}
+
+/// An absolute path for one configured relative to the book root
+fn book_path(root: &Path, path: &Path) -> Result<PathBuf> {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        root.join(path)
+    };
+
+    Ok(if path.is_absolute() {
+        path
+    } else {
+        env::current_dir()?.join(path)
+    })
+}
+
+/// Find the book's source directory, making sure it exists before we try to write next to any
+/// of its chapters
+fn resolve_src_dir(root: &Path, src: &Path) -> Result<PathBuf> {
+    let src_dir = book_path(root, src)?;
+
+    if !src_dir.is_dir() {
+        return Err(Error::msg(format!(
+            "The book's src directory {} doesn't exist or isn't a directory",
+            src_dir.display()
+        )));
+    }
+
+    Ok(src_dir)
+}
+
+/// Find the `output-dir` images are written to, creating it if it's missing
+fn resolve_output_dir(root: &Path, output_dir: &Path) -> Result<PathBuf> {
+    let output_dir = book_path(root, output_dir)?;
+
+    fs::create_dir_all(&output_dir).map_err(|e| {
+        Error::msg(format!(
+            "Couldn't create the output-dir {}: {}",
+            output_dir.display(),
+            e
+        ))
+    })?;
+
+    Ok(output_dir)
+}
+
+/// Percent-encodes everything but letters, digits and `-._~` of a link's path segment, so links to
+/// file names with spaces, `&` or accents resolve from any web server
+fn encode_link(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+/// A link from files in `from` to the directory `to`, both absolute, with a trailing `/` unless
+/// they're the same. It's a url, so it's built from the paths' components and uses `/` whatever
+/// separator the platform has
+fn relative_link(from: &Path, to: &Path) -> String {
+    let (from, to) = (lexical_components(from), lexical_components(to));
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    from[common..]
+        .iter()
+        .map(|_| "..".to_string())
+        .chain(
+            to[common..]
+                .iter()
+                .map(|component| encode_link(&component.as_os_str().to_string_lossy())),
+        )
+        .map(|segment| format!("{}/", segment))
+        .collect()
+}
+
+/// The components of `path` with `.` left out and `..` taking out the one before it, without
+/// touching the file system
+fn lexical_components(path: &Path) -> Vec<Component<'_>> {
+    let mut components = vec![];
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if matches!(components.last(), Some(Component::Normal(_))) => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+
+    components
+}
+
+impl<'c, R: GraphvizRenderer> Graphviz<'c, R> {
+    fn check_options(&self, info_string: &InfoString, chapter_name: &str) -> Result<()> {
+        let problems = info_string.problems(self.renderer.info_string_options());
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        let location = format!(
+            "{} in chapter '{}'",
+            describe_graph(info_string.name()),
+            chapter_name
+        );
+        if self.config.strict_info_string {
+            return Err(Error::msg(format!(
+                "Invalid options for {}: {}",
+                location,
+                problems.join("; ")
+            )));
+        }
+
+        for problem in problems {
+            warn!("{} for {}", problem, location);
+        }
+
+        Ok(())
+    }
+
+    /// Graphs are rendered with `renderer`, e.g. one of your own when using this crate as a library
+    pub fn new(renderer: R, config: GraphvizConfig) -> Graphviz<'c, R> {
+        Graphviz {
+            renderer,
+            config,
+            progress: None,
+            outputs: None,
+            src_dir: None,
+            manifest: None,
+        }
+    }
+
+    fn with_progress(self, progress: &'c Progress) -> Graphviz<'c, R> {
+        Graphviz {
+            progress: Some(progress),
+            ..self
+        }
+    }
+
+    fn with_outputs(self, outputs: &'c Mutex<HashMap<PathBuf, String>>) -> Graphviz<'c, R> {
+        Graphviz {
+            outputs: Some(outputs),
+            ..self
+        }
+    }
+
+    fn with_src_dir(self, src_dir: &'c Path) -> Graphviz<'c, R> {
+        Graphviz {
+            src_dir: Some(src_dir),
+            ..self
+        }
+    }
+
+    fn with_manifest(self, manifest: Option<&'c Manifest>) -> Graphviz<'c, R> {
+        Graphviz { manifest, ..self }
+    }
+
+    /// Render the graphs of every chapter, whose files are under `src_dir`. Chapters are processed
+    /// even after a failure, so the error lists every broken graph
+    pub fn process_book(self, mut book: Book, src_dir: &'c Path) -> Result<Book> {
+        let graphviz = self.with_src_dir(src_dir);
+        let mut errors = vec![];
+        book.for_each_mut(|item| {
+            let chapter = match item {
+                BookItem::Chapter(chapter) => chapter,
+                _ => return,
+            };
+            // draft chapters have nothing to render
+            let mut chapter_dir = match chapter.path {
+                Some(ref path) => src_dir.join(path),
+                None => return,
+            };
+            chapter_dir.pop();
+
+            if let Err(e) = graphviz.process_chapter(chapter, &chapter_dir) {
+                errors.push(e);
+            }
+        });
+
+        if !errors.is_empty() {
+            return Err(GraphErrors::combine(errors));
+        }
+
+        Ok(book)
+    }
+
+    /// Whether the block's image is written to a file, inlined svgs and data urls aren't
+    fn writes_file(&self, block: &GraphvizBlock) -> bool {
+        // only svgs are inlined, and the browser draws them for the client backend
+        (self.config.output_to_file || block.thumbnail.is_some() || block.format != SVG)
+            && !self.config.embed_images
+            && self.config.backend != Backend::Client
+    }
+
+    /// Fail when another chapter's block already writes to one of this block's image files
+    fn check_outputs(&self, block: &GraphvizBlock, chapter_name: &str) -> Result<()> {
+        let outputs = match self.outputs {
+            Some(outputs) if self.writes_file(block) => outputs,
+            _ => return Ok(()),
+        };
+        let location = format!(
+            "{} at {} in chapter '{}'",
+            describe_graph(&block.title()),
+            block.source,
+            chapter_name
+        );
+
+        let mut outputs = outputs.lock().unwrap();
+        for path in block.image_paths() {
+            match outputs.entry(path) {
+                Entry::Occupied(first) => {
+                    return Err(Error::msg(format!(
+                        "Two graphs render to {}: {} and {}. Give one of them a `filename` or \
+                         rename a chapter",
+                        first.key().display(),
+                        first.get(),
+                        location
+                    )));
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(location.clone());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Render the chapter's graphs, returning the image files they're written to
+    fn process_chapter(&self, chapter: &mut Chapter, chapter_path: &Path) -> Result<Vec<PathBuf>> {
+        if !has_graphs(&self.config, &chapter.content) {
+            return Ok(vec![]);
+        }
+
+        let mut graphviz_block_builder: Option<GraphvizBlockBuilder> = None;
+        let mut image_index = 0;
+        // which block first used a file name, so two blocks don't overwrite each other's images
+        let mut file_names: HashMap<String, (usize, String, SourceLine)> = HashMap::new();
+        // how many unnamed blocks with the same code we've seen, they're numbered to tell them apart
+        let mut identical: HashMap<String, usize> = HashMap::new();
+        // the parts of the chapter we replace, everything else is kept byte for byte
+        let mut splices = vec![];
+        // every block we render, they're rendered together once we've seen the whole chapter
+        let mut blocks = vec![];
+        // every block is checked and rendered even when some fail, so all failures are reported
+        let mut errors = vec![];
+        let source_file = match chapter.source_path.as_ref().or(chapter.path.as_ref()) {
+            Some(path) => path.display().to_string(),
+            None => chapter.name.clone(),
+        };
+        let chapter_id = chapter_id(chapter, self.config.chapter_names);
+        let (output_dir, link_dir) = match self.config.output_dir {
+            Some(ref output_dir) => (output_dir.clone(), relative_link(chapter_path, output_dir)),
+            None => match self.config.image_subdir {
+                Some(ref subdir) => (
+                    chapter_path.join(subdir),
+                    format!("{}/", encode_link(subdir)),
+                ),
+                None => (chapter_path.to_path_buf(), String::new()),
+            },
+        };
+
+        for (e, range) in markdown_parser(&chapter.content).into_offset_iter() {
+            if let Some(mut builder) = graphviz_block_builder.take() {
+                match e {
+                    Event::Text(ref text) => {
+                        builder.append_code(&**text);
+                        graphviz_block_builder = Some(builder);
+                    }
+                    // code blocks don't nest, so this closes our block
+                    Event::End(Tag::CodeBlock(_)) => {
+                        builder.check_fences(&chapter.name)?;
+                        if let Some(file) = builder.file.clone() {
+                            builder.load_file(&file, chapter_path, self.src_dir, &chapter.name)?;
+                        }
+                        // graphviz can't make anything of it, so a leftover draft is dropped
+                        if builder.code.trim().is_empty() {
+                            warn!(
+                                "Leaving out the empty {} at {} in chapter '{}'",
+                                describe_graph(&builder.graph_name),
+                                builder.source,
+                                chapter.name
+                            );
+                            // along with the blank lines after it
+                            let end = match chapter.content[range.end..]
+                                .find(|ch| ch != '\n' && ch != '\r')
+                            {
+                                Some(blank_lines) => range.end + blank_lines,
+                                None => chapter.content.len(),
+                            };
+                            splices.push((range.start..end, Splice::Text(String::new())));
+                            continue;
+                        }
+                        let source = if builder.show_source {
+                            Some(builder.code.clone())
+                        } else {
+                            None
+                        };
+                        // finish our digraph, a block can hold several graphs
+                        let group = builder.group.clone();
+                        let mut built = builder.build(image_index);
+                        for block in &mut built {
+                            if block.stable_names && block.graph_name.is_empty() {
+                                let seen = identical.entry(block.content_hash.clone()).or_insert(0);
+                                block.duplicate = *seen;
+                                *seen += 1;
+                            }
+                        }
+                        // a block that would overwrite another's image isn't rendered
+                        built.retain(|block| {
+                            let checked = check_file_name(&mut file_names, block, &chapter.name)
+                                .and_then(|_| self.check_outputs(block, &chapter.name));
+                            match checked {
+                                Ok(()) => true,
+                                Err(e) => {
+                                    errors.push(e);
+                                    false
+                                }
+                            }
+                        });
+                        image_index += 1;
+                        splices.push((
+                            range,
+                            Splice::Graphs {
+                                source,
+                                count: built.len(),
+                                caption: built.first().and_then(|block| block.caption.clone()),
+                                group,
+                            },
+                        ));
+                        blocks.extend(built);
+                    }
+                    // code blocks only hold text, anything else means we lost track of our block
+                    e => {
+                        return Err(Error::msg(format!(
+                            "Unexpected {:?} in the graphviz block at {}, in chapter '{}'",
+                            e,
+                            builder.source.below(&builder.code),
+                            chapter.name
+                        )))
+                    }
+                }
+            } else if let Event::Start(Tag::CodeBlock(ref info_string)) = e {
+                // indented code blocks have no info string, so they're never ours and are left as
+                // they are, even when they show a graphviz block
+                if info_string.is_empty() {
+                    continue;
+                }
+                // the code starts on the line after the fence
+                let source = SourceLine {
+                    file: source_file.clone(),
+                    line: chapter.content[..range.start].matches('\n').count() + 2,
+                };
+                let in_block = |e: Error| {
+                    Error::msg(format!(
+                        "{} in the graphviz block at {} in chapter '{}'",
+                        e, source, chapter.name
+                    ))
+                };
+                let info_string = self
+                    .config
+                    .parse_info_string(info_string)
+                    .map_err(in_block)?;
+                if let Some(ref info_string) = info_string {
+                    self.check_options(info_string, &chapter.name)?;
+                }
+
+                match info_string {
+                    // left as plain dot code
+                    Some(_) if self.config.graphviz_missing => {
+                        splices.push(skipped_fence(&chapter.content, range));
+                    }
+                    Some(info_string) if info_string.flag(SKIP).map_err(in_block)? => {
+                        info!(
+                            "Skipping {} in chapter '{}'",
+                            describe_graph(info_string.name()),
+                            chapter.name
+                        );
+                        splices.push(skipped_fence(&chapter.content, range));
+                    }
+                    Some(info_string) => {
+                        let mut builder = GraphvizBlockBuilder::new(
+                            &info_string,
+                            &chapter.name,
+                            output_dir.clone(),
+                            link_dir.clone(),
+                            &self.config,
+                        )
+                        .map_err(in_block)?;
+                        builder.source = source;
+                        builder.fence = opening_fence(&chapter.content[range]).to_string();
+                        builder.chapter_id = chapter_id.clone();
+                        graphviz_block_builder = Some(builder);
+                    }
+                    None => {}
+                }
+            }
+        }
+        if let Some(builder) = graphviz_block_builder {
+            return Err(Error::msg(format!(
+                "The graphviz block at {} in chapter '{}' is never closed",
+                builder.source, chapter.name
+            )));
+        }
+
+        let images: Vec<PathBuf> = blocks.iter().flat_map(GraphvizBlock::image_paths).collect();
+        let stale_images = match self.config.filenames {
+            Filenames::Hashed if !self.config.check => stale_images(&blocks),
+            _ => vec![],
+        };
+        // with the file each image would be written to
+        let manifest_entries: Vec<(ManifestEntry, PathBuf)> = match self.manifest {
+            Some(manifest) => blocks
+                .iter()
+                .map(|block| {
+                    let entry = ManifestEntry {
+                        chapter: source_file.clone(),
+                        chapter_title: chapter.name.clone(),
+                        name: block.title(),
+                        file: Some(manifest.file(&block.output_path()))
+                            .filter(|_| self.writes_file(block)),
+                        format: block.format.clone(),
+                        hash: block.content_hash.clone(),
+                        spilled: false,
+                    };
+                    (entry, block.output_path())
+                })
+                .collect(),
+            None => vec![],
+        };
+        let (started, graphs) = (Instant::now(), blocks.len());
+        // every block is rendered even when some fail, so their images are there for the next build
+        let mut rendered = vec![];
+        for result in self.render_all(blocks) {
+            match result {
+                Ok(events) => rendered.push(events),
+                Err(e) => errors.push(e),
+            }
+        }
+        let spilled = self.renderer.spilled();
+        if !errors.is_empty() {
+            return Err(GraphErrors::combine(errors));
+        }
+        if let (Some(progress), false) = (self.progress, spilled.is_empty()) {
+            progress.graphs_spilled(spilled.len());
+        }
+        if let Some(manifest) = self.manifest {
+            let manifest_entries = manifest_entries
+                .into_iter()
+                .map(|(mut entry, output_path)| {
+                    if spilled.contains(&output_path) {
+                        entry.file = Some(manifest.file(&output_path));
+                        entry.spilled = true;
+                    }
+                    entry
+                })
+                .collect();
+            manifest.add(manifest_entries);
+        }
+        if let (Some(progress), true) = (self.progress, graphs > 0) {
+            let chapter_path = match chapter.path {
+                Some(ref path) => path.display().to_string(),
+                None => chapter.name.clone(),
+            };
+            progress.chapter_done(&chapter_path, graphs, started.elapsed());
+        }
+        for stale_image in stale_images {
+            if let Err(e) = fs::remove_file(&stale_image) {
+                warn!(
+                    "Couldn't remove the outdated image {}: {}",
+                    stale_image.display(),
+                    e
+                );
+            }
+        }
+        // put the rendered graphs in place of their blocks
+        let slides = if self.config.carousels {
+            carousel_slides(&chapter.content, &splices, &chapter_id)
+        } else {
+            vec![None; splices.len()]
+        };
+        let mut rendered = rendered.into_iter();
+        let mut buf = String::with_capacity(chapter.content.len());
+        let mut copied = 0;
+        for ((range, splice), slide) in splices.into_iter().zip(slides) {
+            buf.push_str(&chapter.content[copied..range.start]);
+            match splice {
+                Splice::Text(text) => buf.push_str(&text),
+                Splice::Graphs {
+                    source,
+                    count,
+                    caption,
+                    group: _,
+                } => {
+                    let events = source
+                        .map_or(vec![], source_events)
+                        .into_iter()
+                        .chain(rendered.by_ref().take(count).flatten())
+                        .chain(caption.as_deref().map_or(vec![], caption_events));
+                    let mut markdown = String::new();
+                    if let Some(ref slide) = slide {
+                        markdown.push_str(&slide.opening());
+                    }
+                    cmark(events, &mut markdown, None)?;
+                    if let Some(ref slide) = slide {
+                        markdown.push_str(&slide.closing());
+                    }
+                    let (start, fitted) = fit_in(&chapter.content, &range, &markdown);
+                    buf.truncate(buf.len() - (range.start - start));
+                    buf.push_str(&fitted);
+                }
+            }
+            copied = range.end;
+        }
+        buf.push_str(&chapter.content[copied..]);
+
+        chapter.content = buf;
+
+        Ok(images)
+    }
+}
+
+/// A block's place in a carousel, the blocks next to each other sharing a group. The carousel is
+/// a radio button per slide, our stylesheet only shows the slide after the checked one
+#[derive(Clone, Debug, PartialEq)]
+struct Slide {
+    /// the id of the carousel's element, its slides' ids start with it
+    carousel: String,
+    index: usize,
+    count: usize,
+}
+
+impl Slide {
+    fn id(&self, index: usize) -> String {
+        format!("{}-slide-{}", self.carousel, index + 1)
+    }
+
+    /// Html before the slide's graph, also opening the carousel for the first slide. The blank
+    /// line at the end lets the graph be markdown
+    fn opening(&self) -> String {
+        let mut html = String::new();
+        if self.index == 0 {
+            html.push_str(&format!(
+                "<div class=\"graphviz-carousel\" id=\"{}\">\n",
+                self.carousel
+            ));
+        }
+        html.push_str(&format!(
+            "<input type=\"radio\" class=\"graphviz-slide-selector\" name=\"{}\" id=\"{}\" \
+             aria-label=\"Graph {} of {}\"{}>\n<div class=\"graphviz-slide\">\n\n",
+            self.carousel,
+            self.id(self.index),
+            self.index + 1,
+            self.count,
+            if self.index == 0 { " checked" } else { "" }
+        ));
+
+        html
+    }
+
+    /// The controls moving to the slides around this one, also closing the carousel after the
+    /// last slide
+    fn closing(&self) -> String {
+        let previous = (self.index + self.count - 1) % self.count;
+        let next = (self.index + 1) % self.count;
+        let mut html = format!(
+            "\n\n<p class=\"graphviz-carousel-controls\"><label for=\"{}\" title=\"Previous graph\">\
+             &#8249;</label> {} / {} <label for=\"{}\" title=\"Next graph\">&#8250;</label></p>\n</div>",
+            self.id(previous),
+            self.index + 1,
+            self.count,
+            self.id(next)
+        );
+        if self.index + 1 == self.count {
+            html.push_str("\n</div>");
+        }
+
+        html
+    }
+}
+
+/// Each splice's slide, for runs of blocks sharing a `group` with only blank lines between them.
+/// Ids are made from the chapter's, the print version has every chapter on one page
+fn carousel_slides(
+    content: &str,
+    splices: &[(Range<usize>, Splice)],
+    chapter_id: &str,
+) -> Vec<Option<Slide>> {
+    let group = |index: usize| match splices[index].1 {
+        Splice::Graphs {
+            group: Some(ref group),
+            ..
+        } => Some(group),
+        _ => None,
+    };
+
+    let mut slides = vec![None; splices.len()];
+    // a group can come back later in the chapter, it's another carousel then
+    let mut carousels: HashMap<String, usize> = HashMap::new();
+    let mut start = 0;
+    while start < splices.len() {
+        let mut end = start + 1;
+        while end < splices.len()
+            && group(start).is_some()
+            && group(end) == group(start)
+            && content[splices[end - 1].0.end..splices[end].0.start]
+                .trim()
+                .is_empty()
+        {
+            end += 1;
+        }
+        if let (Some(group), true) = (group(start), end - start > 1) {
+            let id = format!("graphviz-{}-{}", chapter_id, normalize_id(group));
+            let seen = carousels.entry(id.clone()).or_insert(0);
+            *seen += 1;
+            let carousel = match *seen {
+                1 => id,
+                seen => format!("{}-{}", id, seen),
+            };
+            for (index, slide) in slides[start..end].iter_mut().enumerate() {
+                *slide = Some(Slide {
+                    carousel: carousel.clone(),
+                    index,
+                    count: end - start,
+                });
+            }
+        }
+        start = end;
+    }
+
+    slides
+}
+
+/// Images from earlier versions of these blocks' graphs with `filenames = "hashed"`, they have the
+/// same name apart from the hash
+fn stale_images(blocks: &[GraphvizBlock]) -> Vec<PathBuf> {
+    lazy_static! {
+        static ref HASH_RE: Regex = Regex::new(r"^[0-9a-f]{8}[._]").unwrap();
+    }
+
+    let current: HashSet<PathBuf> = blocks.iter().flat_map(GraphvizBlock::image_paths).collect();
+    // the name up to the hash, e.g. `chapter_flow.`
+    let prefixes: HashSet<(&Path, String)> = blocks
+        .iter()
+        .filter(|block| block.file_stem.is_none())
+        .filter_map(|block| {
+            let file_name = block.file_name();
+            let start = file_name.find(&format!(".{}", block.content_hash))?;
+            Some((block.output_dir.as_path(), file_name[..=start].to_string()))
+        })
+        .collect();
+
+    let mut stale = vec![];
+    for (dir, prefix) in prefixes {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+            let is_stale = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => {
+                    name.contains(GENERATED)
+                        && name
+                            .strip_prefix(&prefix)
+                            .is_some_and(|rest| HASH_RE.is_match(rest))
+                        // images we're keeping, and the hash files next to them
+                        && !current.iter().any(|image| {
+                            path.to_string_lossy()
+                                .starts_with(&*image.to_string_lossy())
+                        })
+                }
+                None => false,
+            };
+            if is_stale {
+                stale.push(path);
+            }
+        }
+    }
+
+    stale
+}
+
+/// Every graph that failed to render, so they can all be fixed before building again
+#[derive(Debug)]
+pub struct GraphErrors(Vec<String>);
+
+impl GraphErrors {
+    /// A single error for all of these, errors which were already combined are listed one by one
+    fn combine(errors: Vec<Error>) -> Error {
+        let mut messages = vec![];
+        for error in errors {
+            match error.downcast::<GraphErrors>() {
+                Ok(GraphErrors(combined)) => messages.extend(combined),
+                Err(error) => messages.push(error.to_string()),
+            }
+        }
+
+        GraphErrors(messages).into()
+    }
+}
+
+impl fmt::Display for GraphErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.as_slice() {
+            [message] => write!(f, "{}", message),
+            messages => write!(
+                f,
+                "{} graphs failed to render:\n\n{}",
+                messages.len(),
+                messages.join("\n\n")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GraphErrors {}
+
+/// What goes in place of a part of the chapter
+enum Splice {
+    Text(String),
+    /// the next `count` rendered graphs, after the block's code if it's shown and followed by its
+    /// caption
+    Graphs {
+        source: Option<String>,
+        count: usize,
+        caption: Option<String>,
+        /// shown in a carousel with the blocks right next to it in the same group
+        group: Option<String>,
+    },
+}
+
+impl<'c, R: GraphvizRenderer> Graphviz<'c, R> {
+    /// Render blocks in parallel, at most `jobs` at a time, keeping them in order
+    fn render_all<'a>(&self, blocks: Vec<GraphvizBlock>) -> Vec<Result<Vec<Event<'a>>>> {
+        let rendered = if self.renderer.batches() {
+            self.render_batch(blocks)
+        } else {
+            self.render_each(blocks)
+        };
+
+        // in the chapter's order, whichever graph finished first
+        rendered
+            .into_iter()
+            .map(|(block, rendered)| {
+                let rendered = self.renderer.settle(&RenderContext::new(&block), rendered);
+                let rendered = self.check_expected(&block, rendered);
+                self.finish(block, rendered)
+            })
+            .collect()
+    }
+
+    /// Hand the renderer one block at a time from `jobs` threads
+    fn render_each<'a>(
+        &self,
+        blocks: Vec<GraphvizBlock>,
+    ) -> Vec<(GraphvizBlock, RenderResult<'a>)> {
+        let jobs = self.config.jobs().min(blocks.len());
+        if jobs <= 1 {
+            return blocks
+                .into_iter()
+                .map(|block| self.render_one(block))
+                .collect();
+        }
+
+        let queue = Mutex::new(blocks.into_iter().enumerate());
+        let results = Mutex::new(vec![]);
+        thread::scope(|scope| {
+            for _ in 0..jobs {
+                scope.spawn(|| loop {
+                    let next = queue.lock().unwrap().next();
+                    match next {
+                        Some((index, block)) => {
+                            let rendered = self.render_one(block);
+                            results.lock().unwrap().push((index, rendered));
+                        }
+                        None => break,
+                    }
+                });
+            }
+        });
+
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, rendered)| rendered).collect()
+    }
+
+    /// Hand the renderer all blocks at once, reporting on each of them as if they were rendered
+    /// one by one
+    fn render_batch<'a>(
+        &self,
+        blocks: Vec<GraphvizBlock>,
+    ) -> Vec<(GraphvizBlock, RenderResult<'a>)> {
+        if blocks.is_empty() {
+            return vec![];
+        }
+
+        let started = Instant::now();
+        let results = self
+            .renderer
+            .render_batch(&blocks.iter().map(RenderContext::new).collect::<Vec<_>>());
+        let elapsed = started.elapsed() / blocks.len() as u32;
+        for block in &blocks {
+            self.report(block, elapsed);
+        }
+        if results.len() != blocks.len() {
+            let (count, total) = (results.len(), blocks.len());
+            return blocks
+                .into_iter()
+                .map(|block| {
+                    let rendered = Err(RendererError::Other(Error::msg(format!(
+                        "The renderer returned {} results for a chapter with {} graphs",
+                        count, total
+                    ))));
+                    (block, rendered)
+                })
+                .collect();
+        }
+
+        blocks.into_iter().zip(results).collect()
+    }
+
+    fn render_one<'a>(&self, block: GraphvizBlock) -> (GraphvizBlock, RenderResult<'a>) {
+        let started = Instant::now();
+        let rendered = self.renderer.render_graphviz(&RenderContext::new(&block));
+        self.report(&block, started.elapsed());
+
+        (block, rendered)
+    }
+
+    /// Count a block as done as soon as it's rendered, so progress doesn't wait for the chapter
+    fn report(&self, block: &GraphvizBlock, elapsed: Duration) {
+        if let Some(progress) = self.progress {
+            progress.graph_done(&block.chapter_name, &block.title(), elapsed);
+        }
+    }
+
+    /// Add the marker to a rendered block, or say which block it is when it failed
+    fn finish<'a>(
+        &self,
+        block: GraphvizBlock,
+        rendered: RenderResult<'a>,
+    ) -> Result<Vec<Event<'a>>> {
+        let marker = if self.config.emit_markers {
+            Some(generated_marker(&block.graph_name))
+        } else {
+            None
+        };
+        let title = block.title();
+
+        // graphs are rendered in parallel, so every error says where it's from
+        rendered
+            .map(|rendered| match marker {
+                Some(marker) => marker.into_iter().chain(rendered.events).collect(),
+                None => rendered.events,
+            })
+            .map_err(|e| {
+                let message = e.to_string();
+                let mut report = format!(
+                    "Couldn't render {} at {}, block {} of chapter '{}': {}",
+                    describe_graph(&title),
+                    block.source,
+                    block.index + 1,
+                    block.chapter_name,
+                    block.source.locate_lines(&message)
+                );
+                // only when graphviz rejected the graph, the code has nothing to do with other errors
+                let rejected = matches!(
+                    e,
+                    RendererError::Rejected(ref e) if matches!(e.failure(), Failure::Exit(_))
+                );
+                if rejected && self.config.error_excerpts() {
+                    report.push_str("\n\n");
+                    report.push_str(&block.source.excerpt(&block.code, &message));
+                }
+
+                Error::msg(report)
+            })
+    }
+
+    /// Turn the error of a block marked `expect-error` into its output, and its success into an
+    /// error
+    fn check_expected<'a>(
+        &self,
+        block: &GraphvizBlock,
+        rendered: RenderResult<'a>,
+    ) -> RenderResult<'a> {
+        if !block.expect_error {
+            return rendered;
+        }
+
+        match rendered {
+            Ok(_) => Err(RendererError::Other(Error::msg(format!(
+                "Expected graph '{}' to fail, but Graphviz rendered it (remove `{}` if it's fixed)",
+                block.graph_name, EXPECT_ERROR
+            )))),
+            // a crash isn't the error the author meant to show either
+            Err(RendererError::Rejected(render_error))
+                if matches!(render_error.failure(), Failure::Signal(_)) =>
+            {
+                Err(RendererError::Rejected(render_error))
+            }
+            Err(RendererError::Rejected(render_error)) => {
+                Ok(expected_error_events(&render_error).into())
+            }
+            // we couldn't even run graphviz, that's not what the author meant to show
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The code of a block shown next to its graphs. Its fence is marked `skip`, so it's left alone
+/// when our output is processed again
+fn source_events<'a>(code: String) -> Vec<Event<'a>> {
+    vec![
+        Event::Start(Tag::CodeBlock(SKIPPED_FENCE.into())),
+        Event::Text(code.into()),
+        Event::End(Tag::CodeBlock(SKIPPED_FENCE.into())),
+    ]
+}
+
+/// Show what Graphviz reported for a graph that's expected to fail
+fn expected_error_events<'a>(error: &RenderError) -> Vec<Event<'a>> {
+    let mut report = format!("Graphviz failed with {}\n", error.status);
+    if !error.stderr.is_empty() {
+        report.push_str(&error.stderr);
+        report.push('\n');
+    }
+
+    vec![
+        Event::Start(Tag::CodeBlock("text".into())),
+        Event::Text(report.into()),
+        Event::End(Tag::CodeBlock("text".into())),
+    ]
+}
+
+/// Turn the fence of the block at `range` into a skipped dot block's, leaving its code as it is
+fn skipped_fence(content: &str, range: Range<usize>) -> (Range<usize>, Splice) {
+    let block = &content[range.clone()];
+    let fence = opening_fence(block).len();
+    let info_string_end = block.find(['\r', '\n']).unwrap_or(block.len());
+
+    (
+        range.start + fence..range.start + info_string_end,
+        Splice::Text(SKIPPED_FENCE.into()),
+    )
+}
+
+/// The backticks or tildes a fenced block starts with
+fn opening_fence(block: &str) -> &str {
+    let fence = match block.chars().next() {
+        Some(ch @ '`') | Some(ch @ '~') => block.len() - block.trim_start_matches(ch).len(),
+        _ => 0,
+    };
+
+    &block[..fence]
+}
+
+/// Markdown to put in place of the block at `range`, indented to stay in the lists and block quotes
+/// the block is in, with blank lines around it so it doesn't run into the paragraphs next to it.
+/// It replaces the chapter from the returned offset, the start of the block's first line
+fn fit_in(content: &str, range: &Range<usize>, markdown: &str) -> (usize, String) {
+    let line_start = content[..range.start]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let prefix = &content[line_start..range.start];
+    // list markers become spaces, block quote markers stay
+    let continuation: String = prefix
+        .chars()
+        .map(|ch| if ch == '>' || ch == '\t' { ch } else { ' ' })
+        .collect();
+    let blank_line = continuation.trim_end();
+    // the chapter's line endings, so a chapter written with `\r\n` doesn't end up with both
+    let newline = if content.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let is_blank = |line: &str| line.chars().all(|ch| ch == '>' || ch.is_whitespace());
+
+    let mut fitted = String::new();
+    // a fence can interrupt a paragraph, the graphs shouldn't join it. A block starting a list item
+    // has nothing before it in the item
+    let previous = content[..line_start]
+        .strip_suffix('\n')
+        .and_then(|before| before.rsplit('\n').next());
+    if line_start > 0 && prefix == continuation && !previous.is_some_and(is_blank) {
+        fitted.push_str(blank_line);
+        fitted.push_str(newline);
+    }
+    fitted.push_str(prefix);
+    for (index, line) in markdown.trim_matches('\n').lines().enumerate() {
+        if index > 0 {
+            fitted.push_str(newline);
+            fitted.push_str(if line.is_empty() {
+                blank_line
+            } else {
+                &continuation
+            });
+        }
+        fitted.push_str(line);
+    }
+    let next = content[range.end..]
+        .strip_prefix(newline)
+        .and_then(|rest| rest.split('\n').next());
+    if next.is_some_and(|next| !is_blank(next)) {
+        fitted.push_str(newline);
+        fitted.push_str(blank_line);
+    }
+
+    (line_start, fitted)
+}
+
+fn check_file_name(
+    file_names: &mut HashMap<String, (usize, String, SourceLine)>,
+    block: &GraphvizBlock,
+    chapter_name: &str,
+) -> Result<()> {
+    match file_names.entry(block.file_name()) {
+        Entry::Occupied(first) => {
+            let (first_index, first_name, first_source) = first.get();
+            Err(Error::msg(format!(
+                "Graph blocks {} ({}) at {} and {} ({}) at {} in chapter '{}' both render to {}",
+                first_index + 1,
+                describe_name(first_name),
+                first_source,
+                block.index + 1,
+                describe_name(&block.graph_name),
+                block.source,
+                chapter_name,
+                first.key()
+            )))
+        }
+        Entry::Vacant(entry) => {
+            entry.insert((block.index, block.graph_name.clone(), block.source.clone()));
+            Ok(())
+        }
+    }
+}
+
+fn describe_name(graph_name: &str) -> String {
+    if graph_name.is_empty() {
+        "unnamed".into()
+    } else {
+        format!("'{}'", graph_name)
+    }
+}
+
+/// The caption is markdown, so emphasis and links in it are rendered as such
+fn caption_events<'a>(caption: &str) -> Vec<Event<'a>> {
+    markdown_parser(caption).map(owned_event).collect()
+}
+
+fn owned_event<'a>(event: Event) -> Event<'a> {
+    fn owned<'a>(text: CowStr) -> CowStr<'a> {
+        text.into_string().into()
+    }
+
+    fn owned_tag<'a>(tag: Tag) -> Tag<'a> {
+        match tag {
+            Tag::Paragraph => Tag::Paragraph,
+            Tag::Rule => Tag::Rule,
+            Tag::Header(level) => Tag::Header(level),
+            Tag::BlockQuote => Tag::BlockQuote,
+            Tag::CodeBlock(info_string) => Tag::CodeBlock(owned(info_string)),
+            Tag::List(start) => Tag::List(start),
+            Tag::Item => Tag::Item,
+            Tag::FootnoteDefinition(label) => Tag::FootnoteDefinition(owned(label)),
+            Tag::HtmlBlock => Tag::HtmlBlock,
+            Tag::Table(alignments) => Tag::Table(alignments),
+            Tag::TableHead => Tag::TableHead,
+            Tag::TableRow => Tag::TableRow,
+            Tag::TableCell => Tag::TableCell,
+            Tag::Emphasis => Tag::Emphasis,
+            Tag::Strong => Tag::Strong,
+            Tag::Strikethrough => Tag::Strikethrough,
+            Tag::Link(link_type, url, title) => Tag::Link(link_type, owned(url), owned(title)),
+            Tag::Image(link_type, url, title) => Tag::Image(link_type, owned(url), owned(title)),
+        }
+    }
+
+    match event {
+        Event::Start(tag) => Event::Start(owned_tag(tag)),
+        Event::End(tag) => Event::End(owned_tag(tag)),
+        Event::Text(text) => Event::Text(owned(text)),
+        Event::Code(code) => Event::Code(owned(code)),
+        Event::Html(html) => Event::Html(owned(html)),
+        Event::InlineHtml(html) => Event::InlineHtml(owned(html)),
+        Event::FootnoteReference(label) => Event::FootnoteReference(owned(label)),
+        Event::SoftBreak => Event::SoftBreak,
+        Event::HardBreak => Event::HardBreak,
+        Event::TaskListMarker(checked) => Event::TaskListMarker(checked),
+    }
+}
+
+/// An html comment placed right before every generated image so downstream tools can tell
+/// our images apart from hand authored ones
+fn generated_marker<'a>(graph_name: &str) -> Vec<Event<'a>> {
+    vec![
+        Event::Start(Tag::HtmlBlock),
+        Event::Html(
+            format!(
+                "<!-- graphviz:generated name=\"{}\" -->\n",
+                escape_html(graph_name)
+            )
+            .into(),
+        ),
+        Event::End(Tag::HtmlBlock),
+    ]
+}
+
+pub fn escape_html(content: &str) -> String {
+    let mut escaped = String::with_capacity(content.len());
+    for ch in content.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(ch),
+        }
+    }
+
+    escaped
+}
+
+struct GraphvizBlockBuilder {
+    chapter_name: String,
+    /// `{chapter}` in file names, from the chapter's file unless `chapter-names = "title"`
+    chapter_id: String,
+    graph_name: String,
+    code: String,
+    output_dir: PathBuf,
+    link_dir: String,
+    /// shown in a carousel with the blocks right next to it in the same group
+    group: Option<String>,
+    dpi: Option<u32>,
+    format: String,
+    engine: Option<String>,
+    seed: Option<u64>,
+    unflatten: Option<Unflatten>,
+    filename_template: FilenameTemplate,
+    index_names: bool,
+    stable_names: bool,
+    /// what goes into the `{hash}` along with the code
+    render_options: Vec<String>,
+    alt: Option<String>,
+    alt_template: Option<String>,
+    caption: Option<String>,
+    width: Option<String>,
+    height: Option<String>,
+    link: Option<String>,
+    file_stem: Option<String>,
+    expect_error: bool,
+    thumbnail: Option<u32>,
+    keep_dot: bool,
+    /// show the block's code above its graphs
+    show_source: bool,
+    /// where the code starts in the chapter's file
+    source: SourceLine,
+    /// the fence the block was opened with
+    fence: String,
+    /// a dot file to render instead of the block's code
+    file: Option<String>,
+    options: BTreeMap<String, String>,
+}
+
+impl GraphvizBlockBuilder {
+    fn new(
+        info_string: &InfoString,
+        chapter_name: &str,
+        output_dir: PathBuf,
+        link_dir: String,
+        config: &GraphvizConfig,
+    ) -> Result<GraphvizBlockBuilder> {
+        let format = image_format(info_string, config)?;
+        let engine = engine(info_string, config)?;
+        let seed = seed(info_string, config)?;
+        let unflatten = unflatten(info_string, config)?;
+        let dpi = dpi(info_string, config, &format)?;
+
+        let mut render_options = vec![
+            format.clone(),
+            engine.clone().unwrap_or_default(),
+            seed.map(|seed| seed.to_string()).unwrap_or_default(),
+        ];
+        // only when it's on, so graphs without it keep their hashes
+        if let Some(ref unflatten) = unflatten {
+            render_options.push(format!("unflatten {}", unflatten.args().join(" ")));
+        }
+        if let Some(dpi) = dpi {
+            render_options.push(format!("dpi {}", dpi));
+        }
+        // a renderer of our own might draw the graph differently
+        let options = info_string.other_options();
+        for (key, value) in &options {
+            render_options.push(format!("{}={}", key, value));
+        }
+
+        Ok(GraphvizBlockBuilder {
+            chapter_name: chapter_name.trim().into(),
+            group: group(info_string)?,
+            chapter_id: normalize_id(chapter_name.trim()),
+            graph_name: info_string.name().into(),
+            code: String::new(),
+            output_dir,
+            link_dir,
+            dpi,
+            render_options,
+            format,
+            engine,
+            seed,
+            unflatten,
+            filename_template: config.filename_template(),
+            index_names: config.index_names(),
+            stable_names: config.stable_names(),
+            alt: info_string.get(ALT).map(String::from),
+            alt_template: config.alt_template.clone(),
+            caption: info_string.get(CAPTION).map(String::from),
+            width: css_size(info_string, WIDTH)?,
+            height: css_size(info_string, HEIGHT)?,
+            link: link(info_string)?,
+            file_stem: file_stem(info_string)?,
+            expect_error: info_string.flag(EXPECT_ERROR)?,
+            thumbnail: thumbnail(info_string)?,
+            // the dumped code is what a dump build produces, so prune has to keep it
+            keep_dot: config.keep_dot || config.backend == Backend::Dump,
+            show_source: show_source(info_string, config)?,
+            source: SourceLine::default(),
+            fence: String::new(),
+            file: info_string.get(FILE).map(String::from),
+            options,
+        })
+    }
+
+    fn append_code<S: Into<String>>(&mut self, code: S) {
+        self.code.push_str(&code.into());
+        // graphviz gets, and file names hash, the same code whatever line endings the chapter has.
+        // The `\n` of a `\r\n` can come with the next text
+        if self.code.contains('\r') && !self.code.ends_with('\r') {
+            self.code = self.code.replace("\r\n", "\n").replace('\r', "\n");
+        }
+    }
+
+    /// A fence in the code that could have closed ours means a block nested in ours, whose end
+    /// closed ours early. Shorter fences, or ones of the other kind, are just part of the code
+    fn check_fences(&self, chapter_name: &str) -> Result<()> {
+        let fence = self
+            .code
+            .lines()
+            .position(|line| !self.fence.is_empty() && line.trim_start().starts_with(&self.fence));
+
+        match fence {
+            Some(index) => Err(Error::msg(format!(
+                "The graphviz block at {} in chapter '{}' holds a code fence on line {}, blocks \
+                 can't be nested. Use a longer fence (````) around the outer block",
+                self.source,
+                chapter_name,
+                SourceLine {
+                    file: self.source.file.clone(),
+                    line: self.source.line + index,
+                }
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes the code from `file`, next to the chapter or else in the book's `src` directory. The
+    /// block has to be empty, so it's clear which code is rendered
+    fn load_file(
+        &mut self,
+        file: &str,
+        chapter_dir: &Path,
+        src_dir: Option<&Path>,
+        chapter_name: &str,
+    ) -> Result<()> {
+        if !self.code.trim().is_empty() {
+            return Err(Error::msg(format!(
+                "The graphviz block at {} in chapter '{}' has code as well as `file={}`, leave \
+                 the block empty to render the file or remove the option to render the code",
+                self.source, chapter_name, file
+            )));
+        }
+        let candidates: Vec<PathBuf> = std::iter::once(chapter_dir)
+            .chain(src_dir)
+            .map(|dir| dir.join(file))
+            .collect();
+        let path = match candidates.iter().find(|path| path.is_file()) {
+            Some(path) => path,
+            None => {
+                return Err(Error::msg(format!(
+                    "Couldn't find `file={}` of the graphviz block at {} in chapter '{}', looked \
+                     for {}",
+                    file,
+                    self.source,
+                    chapter_name,
+                    candidates
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(" and ")
+                )))
+            }
+        };
+        let code = fs::read_to_string(path).map_err(|e| {
+            Error::msg(format!(
+                "Couldn't read {} for the graphviz block at {} in chapter '{}': {}",
+                path.display(),
+                self.source,
+                chapter_name,
+                e
+            ))
+        })?;
+
+        self.code.clear();
+        self.append_code(code);
+        // errors point into the file
+        self.source = SourceLine {
+            file: path.display().to_string(),
+            line: 1,
+        };
+
+        Ok(())
+    }
+
+    fn build(self, index: usize) -> Vec<GraphvizBlock> {
+        let GraphvizBlockBuilder {
+            chapter_name,
+            chapter_id,
+            graph_name,
+            code,
+            output_dir,
+            link_dir,
+            group: _,
+            dpi,
+            format,
+            engine,
+            seed,
+            unflatten,
+            filename_template,
+            index_names,
+            stable_names,
+            render_options,
+            alt,
+            alt_template,
+            caption,
+            width,
+            height,
+            link,
+            file_stem,
+            expect_error,
+            thumbnail,
+            keep_dot,
+            show_source: _,
+            source,
+            fence: _,
+            file: _,
+            options,
+        } = self;
+        let cleaned_code = code.trim();
+        // dot counts lines from the first one we hand it
+        let source = source.below(&code[..code.len() - code.trim_start().len()]);
+        let render_options: Vec<&str> = render_options.iter().map(String::as_str).collect();
+        let content_hash = |code: &str| Cache::key(&render_options, code)[..8].to_string();
+
+        let block = GraphvizBlock {
+            graph_name,
+            code: cleaned_code.into(),
+            chapter_name,
+            chapter_id,
+            output_dir,
+            link_dir,
+            format,
+            engine,
+            seed,
+            unflatten,
+            filename_template,
+            index_names,
+            stable_names,
+            duplicate: 0,
+            content_hash: content_hash(cleaned_code),
+            index,
+            dpi,
+            sub_graph: None,
+            alt,
+            alt_template,
+            caption,
+            width,
+            height,
+            link,
+            file_stem,
+            expect_error,
+            thumbnail,
+            keep_dot,
+            source,
+            options,
+        };
+
+        let graphs = split_graphs(cleaned_code);
+        if graphs.len() <= 1 {
+            return vec![block];
+        }
+
+        graphs
+            .into_iter()
+            .enumerate()
+            .map(|(sub_index, graph)| GraphvizBlock {
+                code: graph.into(),
+                content_hash: content_hash(graph),
+                // graphs are slices of the block's code
+                source: block.source.below(
+                    &cleaned_code[..graph.as_ptr() as usize - cleaned_code.as_ptr() as usize],
+                ),
+                sub_graph: Some(SubGraph {
+                    index: sub_index,
+                    id: graph_id(graph),
+                }),
+                ..block.clone()
+            })
+            .collect()
+    }
+}
+
+/// The block's image `format`, or the one configured for the renderer
+fn image_format(info_string: &InfoString, config: &GraphvizConfig) -> Result<String> {
+    match info_string.get(FORMAT_OPTION) {
+        Some(format) if !FORMATS.contains(&format) => Err(Error::msg(format!(
+            "format must be one of {}, found: {}",
+            FORMATS.join(", "),
+            format
+        ))),
+        Some(format) if format != SVG && config.backend == Backend::Rust => {
+            Err(Error::msg(format!(
+                "backend = \"rust\" only draws svgs, found: format={}",
+                format
+            )))
+        }
+        Some(format) => Ok(format.into()),
+        None => Ok(config.image_format().into()),
+    }
+}
+
+/// The block's layout `engine`, or the configured one
+fn engine(info_string: &InfoString, config: &GraphvizConfig) -> Result<Option<String>> {
+    match info_string.get(ENGINE) {
+        Some(engine) if !ENGINES.contains(&engine) => Err(Error::msg(format!(
+            "engine must be one of {}, found: {}",
+            ENGINES.join(", "),
+            engine
+        ))),
+        Some(engine) => Ok(Some(engine.into())),
+        None => Ok(config.engine.clone()),
+    }
+}
+
+/// The block's `seed`, falling back to the configured one
+fn seed(info_string: &InfoString, config: &GraphvizConfig) -> Result<Option<u64>> {
+    match info_string.get(SEED) {
+        Some(seed) => seed.parse().map(Some).map_err(|_| {
+            Error::msg(format!(
+                "seed must be a non-negative integer, found: {}",
+                seed
+            ))
+        }),
+        None => Ok(config.seed),
+    }
+}
+
+/// The block's `unflatten`: on its own or `true` for the configured parameters (or none), `false`
+/// to leave it out, or the leaf stagger `-l` as a number
+fn unflatten(info_string: &InfoString, config: &GraphvizConfig) -> Result<Option<Unflatten>> {
+    let configured = config.unflatten.clone();
+    match info_string.get(UNFLATTEN) {
+        None => Ok(configured),
+        Some("true") => Ok(Some(configured.unwrap_or_default())),
+        Some("false") => Ok(None),
+        Some(leaf_stagger) => match leaf_stagger.parse() {
+            Ok(leaf_stagger) if leaf_stagger > 0 => Ok(Some(Unflatten {
+                leaf_stagger: Some(leaf_stagger),
+                ..configured.unwrap_or_default()
+            })),
+            _ => Err(Error::msg(format!(
+                "unflatten must be true, false or a positive leaf stagger, found: {}",
+                leaf_stagger
+            ))),
+        },
+    }
+}
+
+/// A css length from the info string, e.g. `60%` or `400px`. It ends up in a style attribute, so
+/// anything which could break out of it is rejected
+fn css_size(info_string: &InfoString, key: &str) -> Result<Option<String>> {
+    match info_string.get(key) {
+        Some(size)
+            if size.is_empty()
+                || size.contains(|ch: char| "\"';<>&".contains(ch) || ch.is_control()) =>
+        {
+            Err(Error::msg(format!(
+                "{} must be a css length like 60% or 400px, found: {}",
+                key, size
+            )))
+        }
+        size => Ok(size.map(String::from)),
+    }
+}
+
+/// A url from the info string, they're written into markdown as is so can't contain spaces
+fn link(info_string: &InfoString) -> Result<Option<String>> {
+    match info_string.get(LINK) {
+        Some(url)
+            if url.is_empty() || url.contains(|ch: char| ch.is_whitespace() || ch.is_control()) =>
+        {
+            Err(Error::msg(format!(
+                "link must be a url without spaces or `self`, found: {}",
+                url
+            )))
+        }
+        url => Ok(url.map(String::from)),
+    }
+}
+
+/// Whether to show the code, from the `source` and `nosource` flags or `preserve-source`
+fn show_source(info_string: &InfoString, config: &GraphvizConfig) -> Result<bool> {
+    match (info_string.flag(SOURCE)?, info_string.flag(NO_SOURCE)?) {
+        (true, true) => Err(Error::msg(format!(
+            "{} and {} can't both be set",
+            SOURCE, NO_SOURCE
+        ))),
+        (true, false) => Ok(true),
+        (false, true) => Ok(false),
+        (false, false) => Ok(config.preserve_source),
+    }
+}
+
+/// The thumbnail width in pixels, `400` or `400px`
+fn thumbnail(info_string: &InfoString) -> Result<Option<u32>> {
+    match info_string.get(THUMBNAIL) {
+        Some(width) => match width.trim_end_matches("px").parse() {
+            Ok(width) if width > 0 => Ok(Some(width)),
+            _ => Err(Error::msg(format!(
+                "thumbnail must be a width in pixels like 400px, found: {}",
+                width
+            ))),
+        },
+        None => Ok(None),
+    }
+}
+
+/// The file name for a block, which has to stay a plain name next to the chapter
+fn file_stem(info_string: &InfoString) -> Result<Option<String>> {
+    match info_string.get(FILENAME) {
+        Some(stem)
+            if stem.is_empty()
+                || stem.contains("..")
+                || stem.contains(|ch: char| ch == '/' || ch == '\\' || ch.is_control()) =>
+        {
+            Err(Error::msg(format!(
+                "filename must be a file name without a path, found: {}",
+                stem
+            )))
+        }
+        stem => Ok(stem.map(String::from)),
+    }
+}
+
+/// Parses markdown the way mdbook does, so we see the same blocks it does. Without the extensions
+/// a table is a paragraph, and our captions would lose their tables and strikethrough
+fn markdown_parser(text: &str) -> Parser<'_> {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+
+    Parser::new_ext(text, options)
+}
+
+/// Whether any code block in the chapter is one of ours. Blocks with an invalid info string count,
+/// so processing the chapter reports them
+fn has_graphs(config: &GraphvizConfig, content: &str) -> bool {
+    markdown_parser(content).any(|event| match event {
+        Event::Start(Tag::CodeBlock(ref info_string)) => {
+            !matches!(config.parse_info_string(info_string), Ok(None))
+        }
+        _ => false,
+    })
+}
+
+/// How many graphs a chapter holds, counted up front so we can report progress against it.
+/// Problems with a block are left to processing the chapter to report
+fn count_graphs(config: &GraphvizConfig, content: &str) -> usize {
+    let mut graphs = 0;
+    let mut code: Option<String> = None;
+
+    for event in markdown_parser(content) {
+        match event {
+            Event::Start(Tag::CodeBlock(ref info_string)) => {
+                if let Ok(Some(info_string)) = config.parse_info_string(info_string) {
+                    if info_string.flag(SKIP).unwrap_or(false) {
+                        continue;
+                    }
+                    // the file isn't read up front, so it counts as one graph
+                    if info_string.get(FILE).is_some() {
+                        graphs += 1;
+                    } else {
+                        code = Some(String::new());
+                    }
+                }
+            }
+            Event::Text(ref text) => {
+                if let Some(ref mut code) = code {
+                    code.push_str(text);
+                }
+            }
+            Event::End(Tag::CodeBlock(_)) => match code.take() {
+                Some(code) if !code.trim().is_empty() => {
+                    graphs += split_graphs(code.trim()).len().max(1)
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    graphs
+}
+
+/// Split code into its top level graphs, `dot` accepts several of them in one input
+fn split_graphs(code: &str) -> Vec<&str> {
+    let mut graphs = vec![];
+    let mut start = 0;
+    let mut depth = 0;
+    let mut line_start = true;
+    let mut chars = code.char_indices().peekable();
+
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            // strings and comments can hold braces which don't count
+            '"' => {
+                while let Some((_, ch)) = chars.next() {
+                    match ch {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '<' if depth > 0 => {
+                let mut html_depth = 1;
+                for (_, ch) in chars.by_ref() {
+                    match ch {
+                        '<' => html_depth += 1,
+                        '>' if html_depth == 1 => break,
+                        '>' => html_depth -= 1,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if matches!(chars.peek(), Some((_, '*'))) => {
+                chars.next();
+                let mut previous = ' ';
+                for (_, ch) in chars.by_ref() {
+                    if previous == '*' && ch == '/' {
+                        break;
+                    }
+                    previous = ch;
+                }
+            }
+            '/' if matches!(chars.peek(), Some((_, '/'))) => {
+                chars.by_ref().find(|(_, ch)| *ch == '\n');
+                line_start = true;
+                continue;
+            }
+            '#' if line_start => {
+                chars.by_ref().find(|(_, ch)| *ch == '\n');
+                line_start = true;
+                continue;
+            }
+            '{' => depth += 1,
+            '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    graphs.push((start, i + 1));
+                    start = i + 1;
+                }
+            }
+            _ => {}
+        }
+
+        line_start = ch == '\n' || (line_start && ch.is_whitespace());
+    }
+
+    // leave anything trailing with the last graph so dot can complain about it
+    if !code[start..].trim().is_empty() {
+        match graphs.last_mut() {
+            Some(last) => last.1 = code.len(),
+            None => graphs.push((start, code.len())),
+        }
+    }
+
+    graphs
+        .into_iter()
+        .map(|(start, end)| code[start..end].trim())
+        .collect()
+}
+
+/// The ID of a graph from its header: `[strict] (graph | digraph) [ID] {`
+fn graph_id(graph: &str) -> Option<String> {
+    lazy_static! {
+        static ref GRAPH_ID_RE: Regex = Regex::new(
+            r#"(?i)^(?:\s*(?:/\*(?s:.)*?\*/|//[^\n]*|#[^\n]*))*\s*(?:strict\s+)?(?:di)?graph\s*("(?:[^"\\]|\\.)*"|[^\s{"]+)?\s*\{"#
+        )
+        .unwrap();
+    }
+
+    let id = GRAPH_ID_RE.captures(graph)?.get(1)?.as_str();
+    Some(if id.starts_with('"') {
+        id[1..id.len() - 1].replace("\\\"", "\"")
+    } else {
+        id.to_string()
+    })
+}
+
+/// A graph to render, with everything a renderer needs to know about it
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct GraphvizBlock {
+    pub graph_name: String,
+    pub code: String,
+    pub chapter_name: String,
+    /// `{chapter}` in file names
+    pub chapter_id: String,
+    /// where the image file is written, next to the chapter unless `output-dir` is set
+    pub output_dir: PathBuf,
+    /// `output_dir` relative to the chapter, empty when it's the chapter's own directory
+    pub link_dir: String,
+    /// the image format graphviz renders to, also the file extension
+    pub format: String,
+    /// the layout engine passed to `dot` with `-K`, graphviz picks `dot` if there's none
+    pub engine: Option<String>,
+    /// random start for engines with force directed layouts so they're reproducible
+    pub seed: Option<u64>,
+    /// piped through Graphviz's `unflatten` before it's laid out, after any filters
+    pub unflatten: Option<Unflatten>,
+    /// names the image file unless the block has a `filename`
+    pub filename_template: FilenameTemplate,
+    /// whether named blocks get their index in the file name, unnamed ones always do
+    pub index_names: bool,
+    /// whether `{index}` is left out for named blocks and a hash of the code for unnamed ones,
+    /// instead of the block's position
+    pub stable_names: bool,
+    /// how many identical unnamed blocks came before this one in the chapter, they'd have the
+    /// same name otherwise
+    pub duplicate: usize,
+    /// a short hash of the code and the options it's rendered with, `{hash}` in file names
+    pub content_hash: String,
+    pub index: usize,
+    /// the resolution graphviz draws raster images at, never set for svgs
+    pub dpi: Option<u32>,
+    /// Set when this is one of several graphs from the same code block
+    pub sub_graph: Option<SubGraph>,
+    /// alt text for the image, the title is used if there's none
+    pub alt: Option<String>,
+    /// the alt text for blocks without an `alt`, e.g. `Diagram: {name}`
+    pub alt_template: Option<String>,
+    /// markdown shown below the block's images, unlike the name it doesn't end up in file names
+    pub caption: Option<String>,
+    /// css lengths like `60%`, images with a size are written as html
+    pub width: Option<String>,
+    pub height: Option<String>,
+    /// where clicking the image goes, `self` links to the image file
+    pub link: Option<String>,
+    /// replaces our `<chapter>_<name>_<index>.generated` naming scheme
+    pub file_stem: Option<String>,
+    pub expect_error: bool,
+    /// the width in pixels of a thumbnail shown instead of the full size image
+    pub thumbnail: Option<u32>,
+    /// also write the code graphviz got next to the image, see [`GraphvizBlock::dot_path`]
+    pub keep_dot: bool,
+    pub source: SourceLine,
+    /// info string options we don't know, for renderers with options of their own
+    pub options: BTreeMap<String, String>,
+}
+
+/// A line of a chapter's markdown file, e.g. `intro.md:217`
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize,
+}
+
+lazy_static! {
+    /// lines graphviz mentions in its errors
+    static ref LINE_RE: Regex = Regex::new(r"\bline (\d+)").unwrap();
+}
+
+/// how many lines of a graph errors quote
+static EXCERPT_LINES: usize = 10;
+/// longer lines are cut off in excerpts, generated graphs can have very long ones
+static EXCERPT_LINE_CHARS: usize = 120;
+
+impl SourceLine {
+    /// The lines of `code`, which starts on this line, around the first line graphviz complains
+    /// about in `message`, numbered as in the file. That line is marked with a `>`
+    fn excerpt(&self, code: &str, message: &str) -> String {
+        let lines: Vec<&str> = code.lines().collect();
+        let marked = LINE_RE
+            .captures(message)
+            .and_then(|captures| captures[1].parse::<usize>().ok())
+            .filter(|line| (1..=lines.len()).contains(line))
+            .map(|line| line - 1);
+        // the marked line with a few lines of context before it
+        let start = marked
+            .map(|marked| marked.saturating_sub(EXCERPT_LINES / 2 - 1))
+            .unwrap_or(0)
+            .min(lines.len().saturating_sub(EXCERPT_LINES));
+        let end = lines.len().min(start + EXCERPT_LINES);
+        let width = (self.line + end).to_string().len();
+
+        let mut excerpt = vec![];
+        if start > 0 {
+            excerpt.push(format!("  {:>width$} | ...", "", width = width));
+        }
+        for (index, line) in lines.iter().enumerate().take(end).skip(start) {
+            let line = match line.char_indices().nth(EXCERPT_LINE_CHARS) {
+                Some((cut, _)) => format!("{}...", &line[..cut]),
+                None => line.to_string(),
+            };
+            excerpt.push(format!(
+                "{} {:>width$} | {}",
+                if Some(index) == marked { ">" } else { " " },
+                self.line + index,
+                line,
+                width = width
+            ));
+        }
+        if end < lines.len() {
+            excerpt.push(format!(
+                "  {:>width$} | ... {} more lines",
+                "",
+                lines.len() - end,
+                width = width
+            ));
+        }
+
+        excerpt.join("\n")
+    }
+
+    /// The line `text` ends on, when it starts on this one
+    fn below(&self, text: &str) -> SourceLine {
+        SourceLine {
+            file: self.file.clone(),
+            line: self.line + text.matches('\n').count(),
+        }
+    }
+
+    /// Point lines in graphviz's errors, which count from the start of the graph, at the file
+    fn locate_lines(&self, message: &str) -> String {
+        LINE_RE
+            .replace_all(message, |captures: &regex::Captures| {
+                match captures[1].parse::<usize>() {
+                    Ok(line) if line > 0 => {
+                        format!("{} ({}:{})", &captures[0], self.file, self.line + line - 1)
+                    }
+                    _ => captures[0].to_string(),
+                }
+            })
+            .into_owned()
+    }
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// One of several graphs in a code block
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct SubGraph {
+    pub index: usize,
+    pub id: Option<String>,
+}
+
+impl GraphvizBlock {
+    pub fn file_name(&self) -> String {
+        self.file_name_with_suffix("")
+    }
+
+    /// The scaled down copy of the image for blocks with a `thumbnail`
+    pub fn thumbnail_file_name(&self) -> String {
+        self.file_name_with_suffix(THUMBNAIL_SUFFIX)
+    }
+
+    fn file_name_with_suffix(&self, suffix: &str) -> String {
+        if let Some(ref stem) = self.file_stem {
+            return match self.sub_graph {
+                Some(ref sub_graph) => {
+                    format!("{}_{}{}.{}", stem, sub_graph.index, suffix, self.format)
+                }
+                None => format!("{}{}.{}", stem, suffix, self.format),
+            };
+        }
+
+        let mut image_name = self
+            .filename_template
+            .expand(|placeholder| match placeholder {
+                p if p == CHAPTER => self.chapter_id.clone(),
+                p if p == NAME => normalize_id(&self.graph_name),
+                p if p == INDEX && self.stable_names && self.graph_name.is_empty() => {
+                    match self.duplicate {
+                        0 => self.content_hash.clone(),
+                        duplicate => format!("{}_{}", self.content_hash, duplicate + 1),
+                    }
+                }
+                p if p == INDEX
+                    && !self.stable_names
+                    && (self.index_names || self.graph_name.is_empty()) =>
+                {
+                    self.index.to_string()
+                }
+                p if p == HASH => self.content_hash.clone(),
+                p if p == FORMAT => self.format.clone(),
+                _ => String::new(),
+            });
+        if let Some(ref sub_graph) = self.sub_graph {
+            image_name.push_str(&format!("_{}", sub_graph.index));
+        }
+
+        format!("{}{}.generated.{}", image_name, suffix, self.format)
+    }
+
+    /// The graph's own ID for one of several graphs in a block, otherwise the block's name
+    pub fn title(&self) -> String {
+        match self.sub_graph {
+            Some(SubGraph {
+                id: Some(ref id), ..
+            }) => id.clone(),
+            Some(SubGraph { index, .. }) => {
+                format!("{} {}", self.graph_name, index + 1).trim().into()
+            }
+            None => self.graph_name.clone(),
+        }
+    }
+
+    /// Relative links are kept as is so they work wherever the book is hosted
+    pub fn link_target(&self) -> Option<String> {
+        match self.link.as_deref() {
+            Some(link) if link == LINK_SELF => Some(self.image_link()),
+            // thumbnails open the full size image
+            None if self.thumbnail.is_some() => Some(self.image_link()),
+            link => link.map(String::from),
+        }
+    }
+
+    /// The link set with `link`, unless it's to the image itself
+    pub fn external_link(&self) -> Option<String> {
+        self.link.clone().filter(|link| link != LINK_SELF)
+    }
+
+    pub fn alt_text(&self) -> String {
+        match (&self.alt, self.alt_template.as_deref()) {
+            (Some(alt), _) => alt.clone(),
+            (None, Some(template)) if !template.is_empty() => template
+                .replace(ALT_NAME, &self.title())
+                .replace(ALT_CHAPTER, &self.chapter_name)
+                .replace(ALT_INDEX, &self.index.to_string()),
+            (None, _) => self.title(),
+        }
+    }
+
+    /// How the chapter refers to the image file
+    pub fn image_link(&self) -> String {
+        format!("{}{}", self.link_dir, encode_link(&self.file_name()))
+    }
+
+    /// The image shown in the chapter, which is the thumbnail if there is one
+    pub fn shown_image_link(&self) -> String {
+        match self.thumbnail {
+            Some(_) => format!(
+                "{}{}",
+                self.link_dir,
+                encode_link(&self.thumbnail_file_name())
+            ),
+            None => self.image_link(),
+        }
+    }
+
+    pub fn output_path(&self) -> PathBuf {
+        self.output_dir.join(self.file_name())
+    }
+
+    pub fn thumbnail_path(&self) -> PathBuf {
+        self.output_dir.join(self.thumbnail_file_name())
+    }
+
+    /// Where the code is kept with `keep-dot`, e.g. `chapter_flow.generated.dot`
+    pub fn dot_path(&self) -> PathBuf {
+        self.output_path().with_extension("dot")
+    }
+
+    /// The files this block's images may be written to, and its code when it's kept
+    pub fn image_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.output_path()];
+        paths.extend(self.thumbnail.map(|_| self.thumbnail_path()));
+        if self.keep_dot {
+            paths.push(self.dot_path());
+        }
+
+        paths
+    }
+}
+
+/// The carousel a block is shown in, its name ends up in html ids
+fn group(info_string: &InfoString) -> Result<Option<String>> {
+    match info_string.get(GROUP) {
+        Some(group) if normalize_id(group).is_empty() => Err(Error::msg(format!(
+            "group must name the carousel the block is shown in, found: {:?}",
+            group
+        ))),
+        group => Ok(group.map(String::from)),
+    }
+}
+
+/// The block's `dpi`, falling back to the configured one. Graphviz only uses it for raster images,
+/// so it's left out of svgs
+fn dpi(info_string: &InfoString, config: &GraphvizConfig, format: &str) -> Result<Option<u32>> {
+    let dpi = match info_string.get(DPI) {
+        Some(dpi) => match dpi.parse() {
+            Ok(dpi) if dpi > 0 => {
+                if format == SVG {
+                    warn!(
+                        "dpi={} is ignored for {}, svgs have no resolution",
+                        dpi,
+                        describe_graph(info_string.name())
+                    );
+                }
+                Some(dpi)
+            }
+            _ => {
+                return Err(Error::msg(format!(
+                    "dpi must be a positive integer, found: {}",
+                    dpi
+                )))
+            }
+        },
+        None => config.dpi,
+    };
+
+    Ok(dpi.filter(|_| format != SVG))
+}
+
+/// What `{chapter}` stands for in file names. The chapter's file tells chapters with the same title
+/// apart, e.g. `part1/setup.md` is `part1_setup`. Chapters without a file use their title
+fn chapter_id(chapter: &Chapter, names: ChapterNames) -> String {
+    let path = chapter
+        .path
+        .as_ref()
+        .filter(|path| path.file_stem().is_some());
+
+    match (names, path) {
+        (ChapterNames::Path, Some(path)) => {
+            let parts: Vec<_> = path
+                .with_extension("")
+                .components()
+                .filter_map(|component| match component {
+                    Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+                    _ => None,
+                })
+                .collect();
+            normalize_id(&parts.join("_"))
+        }
+        _ => normalize_id(chapter.name.trim()),
+    }
+}
+
+fn normalize_id(content: &str) -> String {
+    let mut id = String::with_capacity(content.len());
+    for ch in content.chars() {
+        if ch.is_alphanumeric() {
+            // any script, not just ascii
+            id.extend(ch.to_lowercase());
+        } else if ch.is_whitespace() || ch == '_' || ch == '-' {
+            id.push('_');
+        }
+    }
+
+    // names without a letter or digit, e.g. only emoji, still need to be told apart
+    if !content.is_empty() && id.chars().all(|ch| ch == '_') {
+        return Cache::key(&[], content)[..8].to_string();
+    }
+
+    id
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::renderer::{image_events, inline_events, layout_conflict};
+
+    static CHAPTER_NAME: &str = "Test Chapter";
+    static NORMALIZED_CHAPTER_NAME: &str = "test_chapter";
+
+    struct NoopRenderer;
+
+    impl GraphvizRenderer for NoopRenderer {
+        fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+            let block = context.block;
+            let file_name = block.file_name();
+            let output_path = &context.output_path;
+            Ok(vec![
+                Event::Start(Tag::Paragraph),
+                Event::Text(
+                    format!(
+                        "{}|{:?}|{}|{}",
+                        file_name, output_path, block.graph_name, block.index
+                    )
+                    .into(),
+                ),
+                Event::End(Tag::Paragraph),
+            ]
+            .into())
+        }
+    }
+
+    /// Emits the same events as `CLIGraphviz` without calling out to graphviz
+    struct InlineRenderer;
+
+    impl GraphvizRenderer for InlineRenderer {
+        fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+            Ok(inline_events(format!(
+                "<svg>\n<title>{}</title>\n</svg>",
+                context.block.graph_name
+            ))
+            .into())
+        }
+    }
+
+    /// Emits the same events as `CLIGraphvizToFile` without calling out to graphviz
+    struct FileRenderer;
+
+    impl GraphvizRenderer for FileRenderer {
+        fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+            Ok(image_events(context.block).into())
+        }
+    }
+
+    /// Draws graphs in the `theme` from their info string
+    struct ThemedRenderer;
+
+    impl GraphvizRenderer for ThemedRenderer {
+        fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+            let theme = context.options.get("theme").map_or("light", String::as_str);
+            Ok(inline_events(format!("<svg class=\"{}\"></svg>", theme)).into())
+        }
+
+        fn info_string_options(&self) -> &[&str] {
+            &["theme"]
+        }
+    }
+
+    /// Records how many graphs it's rendering at the same time
+    #[derive(Default)]
+    struct ConcurrencyRenderer {
+        running: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    impl GraphvizRenderer for ConcurrencyRenderer {
+        fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(running, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+
+            NoopRenderer.render_graphviz(context)
+        }
+    }
+
+    /// Fails like `dot` does on a syntax error
+    #[cfg(unix)]
+    struct FailingRenderer;
+
+    #[cfg(unix)]
+    impl GraphvizRenderer for FailingRenderer {
+        fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+            use std::os::unix::process::ExitStatusExt;
+
+            Err(RenderError {
+                graph_name: context.block.graph_name.clone(),
+                status: std::process::ExitStatus::from_raw(1 << 8),
+                stderr: "Error: syntax error in line 3 near '}'".into(),
+            }
+            .into())
+        }
+    }
+
+    #[test]
+    fn only_preprocess_flagged_blocks() {
+        let unflagged = r#"```dot
+digraph Test {
+    a -> b
+}
+```
+
+~~~~dot
+digraph Test { a -> b }
+~~~~
+"#;
+
+        let mut chapter = new_chapter(format!(
+            "# Chapter\n\n```dot process\ndigraph Test {{ a -> b }}\n```\n\n{}",
+            unflagged
+        ));
+
+        process_chapter(&mut chapter).unwrap();
+
+        assert_eq!(
+            chapter.content,
+            format!(
+                "# Chapter\n\n{0}_432a3c80.generated.svg|\"./{0}_432a3c80.generated.svg\"||0\n\n{1}",
+                NORMALIZED_CHAPTER_NAME, unflagged
+            )
+        );
+    }
+
+    #[test]
+    fn chapters_without_graphs_are_untouched() {
+        let content = r#"# Chapter
+
+| Left | Center | Right |
+|:-----|:------:|------:|
+| a    | *b*    | __c__ |
+
+<div class="note">
+  <p>Raw <em>html</em></p>
+</div>
+
+~~~rust
+fn main() {}
+~~~
+
+```dot
+digraph Test { a -> b }
+```
+
+* item
++ other item
+"#;
+
+        let mut chapter = new_chapter(content.into());
+        process_chapter(&mut chapter).unwrap();
+        assert_eq!(chapter.content, content);
+    }
+
+    #[test]
+    fn counts_graphs_up_front() {
+        let content = r#"```dot process
+digraph First { a -> b }
+digraph Second { a -> c }
+```
+
+```dot process skip Draft
+digraph Test { a -> }
+```
+
+```dot
+digraph Test { a -> b }
+```
+
+- ```dot process Nested
+  digraph Test { a -> b }
+  ```
+"#;
+
+        let config = GraphvizConfig::default();
+        assert_eq!(count_graphs(&config, content), 3);
+
+        let progress = Progress::new(3, true);
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(NoopRenderer, config.clone())
+            .with_progress(&progress)
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert_eq!(progress.done(), 3);
+    }
+
+    #[test]
+    fn batching_renderers_get_the_whole_chapter() {
+        struct Batched {
+            batches: Mutex<Vec<usize>>,
+        }
+
+        impl GraphvizRenderer for Batched {
+            fn render_graphviz<'a>(&self, _: &RenderContext) -> RenderResult<'a> {
+                unreachable!("blocks are rendered in batches")
+            }
+
+            fn render_batch<'a>(&self, contexts: &[RenderContext]) -> Vec<RenderResult<'a>> {
+                self.batches.lock().unwrap().push(contexts.len());
+                contexts
+                    .iter()
+                    .map(|context| match context.block.code.contains("->") {
+                        true => {
+                            Ok(vec![Event::Text(context.block.graph_name.clone().into())].into())
+                        }
+                        false => Err(Error::msg("syntax error").into()),
+                    })
+                    .collect()
+            }
+
+            fn batches(&self) -> bool {
+                true
+            }
+        }
+
+        let config = GraphvizConfig::default();
+        let renderer = Batched {
+            batches: Mutex::new(vec![]),
+        };
+        let graphviz = Graphviz::new(renderer, config.clone());
+        let mut chapter = new_chapter(
+            "```dot process First\ndigraph { a -> b }\n```\n\n```dot process Broken\ndigraph { a }\n```\n\n```dot process Last\ndigraph { b -> c }\n```\n"
+                .into(),
+        );
+
+        let error = graphviz
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(*graphviz.renderer.batches.lock().unwrap(), vec![3]);
+        // only the broken graph is reported
+        assert!(
+            error.starts_with("Couldn't render graph 'Broken' at ./:6, block 2 of chapter"),
+            "{}",
+            error
+        );
+        assert!(
+            !error.contains("First") && !error.contains("Last"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn render_all_dot_blocks() {
+        let content = r#"```dot Plain
+digraph Test { a -> b }
+```
+
+```dot process Flagged
+digraph Test { a -> b }
+```
+
+```rust
+fn main() {}
+```
+"#;
+
+        // only flagged blocks by default
+        let mut chapter = new_chapter(content.into());
+        process_chapter(&mut chapter).unwrap();
+        assert!(chapter.content.starts_with("```dot Plain\n"));
+
+        let mut chapter = new_chapter(content.into());
+        let config = GraphvizConfig {
+            render_all_dot: true,
+            ..GraphvizConfig::default()
+        };
+        process_chapter_with_config(&mut chapter, config).unwrap();
+
+        let expected = format!(
+            r#"{0}_plain.generated.svg|"./{0}_plain.generated.svg"|Plain|0
+
+{0}_flagged.generated.svg|"./{0}_flagged.generated.svg"|Flagged|1
+
+```rust
+fn main() {{}}
+```
+"#,
+            NORMALIZED_CHAPTER_NAME
+        );
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[test]
+    fn similar_info_strings_are_ignored() {
+        let expected = r#"# Chapter
+
+```dot processing-notes
+digraph Test {
+    a -> b
+}
+```
+"#;
+
+        let mut chapter = new_chapter(expected.into());
+
+        process_chapter(&mut chapter).unwrap();
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[test]
+    fn tilde_fences() {
+        let mut chapter = new_chapter(
+            r#"# Chapter
+
+~~~dot process Name
+digraph Test { a -> b }
+~~~
+
+~~~python
+print("```")
+~~~
+
+- ~~~python
+  nested()
+  ~~~
+"#
+            .into(),
+        );
+
+        let expected = format!(
+            r#"# Chapter
+
+{0}_name.generated.svg|"./{0}_name.generated.svg"|Name|0
+
+~~~python
+print("```")
+~~~
+
+- ~~~python
+  nested()
+  ~~~
+"#,
+            NORMALIZED_CHAPTER_NAME
+        );
+
+        assert_idempotent(NoopRenderer, &mut chapter, GraphvizConfig::default());
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[test]
+    fn no_name() {
+        let mut chapter = new_chapter(
+            r#"# Chapter
+```dot process
+digraph Test {
+    a -> b
+}
+```
+"#
+            .into(),
+        );
+
+        let expected = format!(
+            r#"# Chapter
+
+{}_925fe698.generated.svg|"./{}_925fe698.generated.svg"||0
+"#,
+            NORMALIZED_CHAPTER_NAME, NORMALIZED_CHAPTER_NAME
+        );
+
+        process_chapter(&mut chapter).unwrap();
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[test]
+    fn named_blocks() {
+        let mut chapter = new_chapter(
+            r#"# Chapter
+```dot process Graph Name
+digraph Test {
+    a -> b
+}
+```
+"#
+            .into(),
+        );
+
+        let expected = format!(
+            r#"# Chapter
+
+{}_graph_name.generated.svg|"./{}_graph_name.generated.svg"|Graph Name|0
+"#,
+            NORMALIZED_CHAPTER_NAME, NORMALIZED_CHAPTER_NAME
+        );
+
+        process_chapter(&mut chapter).unwrap();
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[test]
+    fn carousels() {
+        let content = r#"# Variants
+
+```dot process group=variants Wide
+digraph Test { rankdir=LR; a -> b }
+```
+
+```dot process group=variants Tall
+digraph Test { a -> b }
+```
+
+Between the groups.
+
+```dot process group=variants Again
+digraph Test { a -> c }
+```
+```dot process group="variants" Again2
+digraph Test { a -> d }
+```
+
+```dot process group=other Alone
+digraph Test { b -> c }
+```
+"#;
+
+        let config = GraphvizConfig {
+            carousels: true,
+            ..GraphvizConfig::default()
+        };
+        // the print version puts both on one page
+        let mut chapter = new_chapter(content.into());
+        let mut other = Chapter::new("Other", content.into(), PathBuf::from("./"), vec![]);
+        process_chapter_with_config(&mut chapter, config.clone()).unwrap();
+        process_chapter_with_config(&mut other, config).unwrap();
+
+        let mut html = String::new();
+        for chapter in [&chapter, &other] {
+            pulldown_cmark::html::push_html(&mut html, Parser::new(&chapter.content));
+        }
+        assert_eq!(html.matches("<div class=\"graphviz-carousel\"").count(), 4);
+        let ids: Vec<&str> = html
+            .split(" id=\"")
+            .skip(1)
+            .map(|rest| &rest[..rest.find('"').unwrap()])
+            .collect();
+        assert_eq!(ids.len(), 12);
+        assert_eq!(
+            ids.iter().collect::<std::collections::HashSet<_>>().len(),
+            ids.len(),
+            "{:?}",
+            ids
+        );
+        assert!(ids.contains(&"graphviz-test_chapter-variants-2-slide-1"));
+        assert!(ids.contains(&"graphviz-other-variants-slide-2"));
+        // every control points at a slide
+        for target in html.split("<label for=\"").skip(1) {
+            assert!(
+                ids.contains(&&target[..target.find('"').unwrap()]),
+                "{}",
+                target
+            );
+        }
+
+        // the graphs are still markdown inside the slides
+        assert!(
+            html.contains(
+                "<div class=\"graphviz-slide\">\n<p>test_chapter_wide.generated.svg|&quot;./test_chapter_wide.generated.svg&quot;|Wide|0</p>\n<p class=\"graphviz-carousel-controls\">"
+            ),
+            "{}",
+            html
+        );
+        // a group of one is just the graph
+        assert!(
+            chapter.content.ends_with(
+                "</div>\n</div>\n\ntest_chapter_alone.generated.svg|\"./test_chapter_alone.generated.svg\"|Alone|4\n"
+            ),
+            "{}",
+            chapter.content
+        );
+
+        // shown one after another for other renderers
+        let mut chapter = new_chapter(content.into());
+        process_chapter(&mut chapter).unwrap();
+        assert!(!chapter.content.contains("graphviz-carousel"));
+        assert!(chapter.content.contains("test_chapter_tall.generated.svg"));
+    }
+
+    #[test]
+    fn group_needs_a_name() {
+        let mut chapter =
+            new_chapter("```dot process group=\"!\" Flow\ndigraph { a }\n```\n".into());
+
+        let error = process_chapter(&mut chapter).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "group must name the carousel the block is shown in, found: \"!\" in the graphviz \
+                 block at ./:2 in chapter '{}'",
+                CHAPTER_NAME
+            )
+        );
+    }
+
+    #[test]
+    fn named_blocks_without_index() {
+        let mut chapter = new_chapter(
+            r#"```dot process Graph Name
+digraph Test { a -> b }
+```
+
+```dot process
+digraph Test { a -> b }
+```"#
+                .into(),
+        );
+
+        process_chapter(&mut chapter).unwrap();
+
+        assert_eq!(
+            chapter.content,
+            format!(
+                r#"{0}_graph_name.generated.svg|"./{0}_graph_name.generated.svg"|Graph Name|0
+
+{0}_432a3c80.generated.svg|"./{0}_432a3c80.generated.svg"||1"#,
+                NORMALIZED_CHAPTER_NAME
+            )
+        );
+    }
+
+    #[test]
+    fn identical_blocks_are_numbered() {
+        let block = "```dot process\ndigraph Test { a -> b }\n```";
+        let other = "```dot process\ndigraph Test { b -> c }\n```";
+        let mut chapter = new_chapter([block, other, block].join("\n\n"));
+        process_chapter(&mut chapter).unwrap();
+
+        let names: Vec<&str> = chapter
+            .content
+            .split("\n\n")
+            .map(|line| line.split('|').next().unwrap())
+            .collect();
+        assert_eq!(names[0], "test_chapter_432a3c80.generated.svg");
+        assert_eq!(names[2], "test_chapter_432a3c80_2.generated.svg");
+
+        // moving a block around leaves the names of the others alone
+        let mut moved = new_chapter([other, block, block].join("\n\n"));
+        process_chapter(&mut moved).unwrap();
+        assert!(moved.content.starts_with(&format!("{}|", names[1])));
+        assert!(moved.content.contains(names[0]));
+        assert!(moved.content.contains(names[2]));
+    }
+
+    #[test]
+    fn named_blocks_without_index_collide() {
+        let positional = GraphvizConfig {
+            stable_names: Some(false),
+            index_names: Some(false),
+            ..GraphvizConfig::default()
+        };
+        for config in vec![GraphvizConfig::default(), positional] {
+            let mut chapter = new_chapter(
+                r#"```dot process Overview
+digraph Test { a -> b }
+```
+
+```dot process Overview
+digraph Test { b -> c }
+```"#
+                    .into(),
+            );
+
+            let error = process_chapter_with_config(&mut chapter, config)
+                .unwrap_err()
+                .to_string();
+
+            assert_eq!(
+                error,
+                format!(
+                    "Graph blocks 1 ('Overview') at ./:2 and 2 ('Overview') at ./:6 in chapter 'Test Chapter' both render to {}_overview.generated.svg",
+                    NORMALIZED_CHAPTER_NAME
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn index_names_only_matter_for_positional_names() {
+        let content = "```dot process Flow\ndigraph Test { a -> b }\n```";
+        let name = |config: GraphvizConfig| {
+            let mut chapter = new_chapter(content.into());
+            process_chapter_with_config(&mut chapter, config).unwrap();
+            chapter.content.split('|').next().unwrap().to_string()
+        };
+
+        assert_eq!(
+            name(GraphvizConfig::default()),
+            "test_chapter_flow.generated.svg"
+        );
+        assert_eq!(
+            name(GraphvizConfig {
+                stable_names: Some(false),
+                ..GraphvizConfig::default()
+            }),
+            "test_chapter_flow_0.generated.svg"
+        );
+        assert_eq!(
+            name(GraphvizConfig {
+                stable_names: Some(false),
+                index_names: Some(false),
+                ..GraphvizConfig::default()
+            }),
+            "test_chapter_flow.generated.svg"
+        );
+    }
+
+    #[test]
+    fn preserve_source() {
+        let with_source = format!(
+            r#"````dot skip
+digraph Test {{ a -> b }}
+````
+
+{0}_flow.generated.svg|"./{0}_flow.generated.svg"|Flow|0"#,
+            NORMALIZED_CHAPTER_NAME
+        );
+        let without_source = format!(
+            r#"{0}_flow.generated.svg|"./{0}_flow.generated.svg"|Flow|0"#,
+            NORMALIZED_CHAPTER_NAME
+        );
+
+        for (preserve_source, flag, expected) in &[
+            (false, "", &without_source),
+            (false, "source ", &with_source),
+            (false, "nosource ", &without_source),
+            (true, "", &with_source),
+            (true, "source ", &with_source),
+            (true, "nosource ", &without_source),
+        ] {
+            let mut chapter = new_chapter(format!(
+                "```dot process {}Flow\ndigraph Test {{ a -> b }}\n```",
+                flag
+            ));
+            let config = GraphvizConfig {
+                preserve_source: *preserve_source,
+                ..GraphvizConfig::default()
+            };
+            process_chapter_with_config(&mut chapter, config.clone()).unwrap();
+            assert_eq!(
+                &&chapter.content, expected,
+                "preserve-source = {}, {}",
+                preserve_source, flag
+            );
+
+            // the shown source stays as it is
+            let first_pass = chapter.content.clone();
+            Graphviz::new(FileRenderer, config.clone())
+                .process_chapter(&mut chapter, Path::new("./"))
+                .unwrap();
+            assert_eq!(chapter.content, first_pass);
+        }
+
+        let mut chapter =
+            new_chapter("```dot process source nosource\ndigraph Test { a -> b }\n```".into());
+        assert!(process_chapter(&mut chapter).is_err());
+    }
+
+    #[test]
+    fn alt_text_from_a_template() {
+        let content = r#"```dot process Flow
+digraph Test { a -> b }
+```
+
+```dot process alt="Hand written" Other
+digraph Test { a -> b }
+```"#;
+
+        let config = GraphvizConfig {
+            alt_template: Some("Diagram {index}: {name} from {chapter}".into()),
+            ..GraphvizConfig::default()
+        };
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(FileRenderer, config.clone())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert_eq!(
+            chapter.content,
+            format!(
+                r#"![Diagram 0: Flow from Test Chapter]({0}_flow.generated.svg "Flow")
+
+![Hand written]({0}_other.generated.svg "Other")"#,
+                NORMALIZED_CHAPTER_NAME
+            )
+        );
+
+        // an empty template is the same as none
+        let config = GraphvizConfig {
+            alt_template: Some("".into()),
+            ..GraphvizConfig::default()
+        };
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(FileRenderer, config.clone())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert!(
+            chapter
+                .content
+                .starts_with(&format!("![Flow]({}_flow.", NORMALIZED_CHAPTER_NAME)),
+            "{}",
+            chapter.content
+        );
+    }
+
+    #[test]
+    fn generated_markers() {
+        let mut chapter = new_chapter(
+            r#"# Chapter
+```dot process Graph "Name"
+digraph Test {
+    a -> b
+}
+```
+
+Some text
+
+```dot process
+digraph Test {
+    a -> b
+}
+```
+"#
+            .into(),
+        );
+
+        let expected = format!(
+            r#"# Chapter
+
+<!-- graphviz:generated name="Graph &quot;Name&quot;" -->
+
+{0}_graph_name.generated.svg|"./{0}_graph_name.generated.svg"|Graph "Name"|0
+
+Some text
+
+<!-- graphviz:generated name="" -->
+
+{0}_925fe698.generated.svg|"./{0}_925fe698.generated.svg"||1
+"#,
+            NORMALIZED_CHAPTER_NAME
+        );
+
+        let config = GraphvizConfig {
+            emit_markers: true,
+            ..GraphvizConfig::default()
+        };
+        process_chapter_with_config(&mut chapter, config).unwrap();
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn expected_errors_are_shown() {
+        let mut chapter = new_chapter(
+            r#"# Chapter
+```dot process expect-error=true Broken
+digraph Test {
+    a -> 
+}
+```
+"#
+            .into(),
+        );
+
+        let expected = r#"# Chapter
+
+````text
+Graphviz failed with exit status: 1
+Error: syntax error in line 3 near '}'
+````
+"#;
+
+        Graphviz::new(FailingRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, &PathBuf::from("./"))
+            .unwrap();
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unexpected_errors_still_fail() {
+        let mut chapter = new_chapter(
+            r#"```dot process Broken
+digraph Test {
+```
+"#
+            .into(),
+        );
+
+        let error = Graphviz::new(FailingRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, &PathBuf::from("./"))
+            .unwrap_err();
+
+        assert!(error.to_string().contains("syntax error in line 3"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn errors_point_at_the_source_line() {
+        let mut chapter = Chapter::new(
+            CHAPTER_NAME,
+            r#"# Chapter
+
+Some text.
+
+```dot process Broken
+
+digraph First { a -> b }
+
+digraph Second {
+    a ->
+}
+```
+"#
+            .into(),
+            PathBuf::from("guide/intro.md"),
+            vec![],
+        );
+
+        let error = Graphviz::new(FailingRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, &PathBuf::from("./"))
+            .unwrap_err()
+            .to_string();
+        // both graphs fail, line 3 of the first is line 9 of the file
+        assert!(
+            error.contains("Couldn't render graph 'First' at guide/intro.md:7, block 1"),
+            "{}",
+            error
+        );
+        assert!(
+            error.contains("syntax error in line 3 (guide/intro.md:9) near '}'"),
+            "{}",
+            error
+        );
+        assert!(
+            error.contains("syntax error in line 3 (guide/intro.md:11) near '}'"),
+            "{}",
+            error
+        );
+        // with the code it's about
+        assert!(
+            error.ends_with("   9 | digraph Second {\n  10 |     a ->\n> 11 | }"),
+            "{}",
+            error
+        );
+
+        let source = SourceLine {
+            file: "intro.md".into(),
+            line: 7,
+        };
+        assert_eq!(source.below("digraph First { a -> b }\n\n").line, 9);
+        assert_eq!(
+            source.locate_lines("Warning: syntax error in line 1, near line 0"),
+            "Warning: syntax error in line 1 (intro.md:7), near line 0"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn empty_blocks_are_left_out() {
+        let content = r#"# Chapter
+
+```dot process Draft
+
+```
+
+Some text."#;
+
+        let config = GraphvizConfig::default();
+        assert_eq!(count_graphs(&config, content), 0);
+
+        // graphviz is never called, it would fail
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(FailingRenderer, config.clone())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert_eq!(chapter.content, "# Chapter\n\nSome text.");
+    }
+
+    #[test]
+    fn nested_fences_are_an_error() {
+        let content = r#"# Chapter
+
+```dot process Nested
+digraph { a -> b }
+```text
+inner
+```
+```"#;
+
+        let error =
+            process_chapter_with_config(&mut new_chapter(content.into()), Default::default())
+                .unwrap_err()
+                .to_string();
+        assert_eq!(
+            error,
+            format!(
+                "The graphviz block at ./:4 in chapter '{}' holds a code fence on line ./:5, \
+                 blocks can't be nested. Use a longer fence (````) around the outer block",
+                CHAPTER_NAME
+            )
+        );
+    }
+
+    /// Keeps the code of every block it renders
+    struct CodeRenderer(Arc<Mutex<Vec<String>>>);
+
+    impl GraphvizRenderer for CodeRenderer {
+        fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+            self.0.lock().unwrap().push(context.block.code.clone());
+            NoopRenderer.render_graphviz(context)
+        }
+    }
+
+    #[test]
+    fn shorter_fences_in_a_block() {
+        let code =
+            "digraph Markdown {\n    fence [label=\"\n```rust\nfn main() {}\n```\n~~~~~\n\"]\n}\n";
+        let content = format!(
+            "# Chapter\n\n````dot process Markdown\n{}````\n\nAfter\n",
+            code
+        );
+
+        let codes = Arc::new(Mutex::new(vec![]));
+        let mut chapter = new_chapter(content);
+        Graphviz::new(CodeRenderer(codes.clone()), GraphvizConfig::default())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert_eq!(*codes.lock().unwrap(), vec![code.trim_end().to_string()]);
+        assert_eq!(
+            chapter.content,
+            format!(
+                "# Chapter\n\n{0}_markdown.generated.svg|\"./{0}_markdown.generated.svg\"|Markdown|0\n\nAfter\n",
+                NORMALIZED_CHAPTER_NAME
+            )
+        );
+    }
+
+    #[test]
+    fn graphs_from_files() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        let chapter_dir = src_dir.join("part");
+        fs::create_dir_all(chapter_dir.join("diagrams")).unwrap();
+        fs::create_dir_all(src_dir.join("shared")).unwrap();
+        fs::write(
+            chapter_dir.join("diagrams/pipeline.dot"),
+            "digraph Pipeline {\r\n    a -> b\r\n}\r\n",
+        )
+        .unwrap();
+        fs::write(src_dir.join("shared/legend.dot"), "digraph Legend { c }\n").unwrap();
+
+        let content = r#"```dot process file=diagrams/pipeline.dot Pipeline
+```
+
+```dot process file="shared/legend.dot" Legend
+```
+"#;
+        let config = GraphvizConfig::default();
+        assert_eq!(count_graphs(&config, content), 2);
+
+        let codes = Arc::new(Mutex::new(vec![]));
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(CodeRenderer(codes.clone()), config.clone())
+            .with_src_dir(&src_dir)
+            .process_chapter(&mut chapter, &chapter_dir)
+            .unwrap();
+        assert_eq!(
+            *codes.lock().unwrap(),
+            vec!["digraph Pipeline {\n    a -> b\n}", "digraph Legend { c }"]
+        );
+        assert!(
+            chapter.content.contains("|Pipeline|0"),
+            "{}",
+            chapter.content
+        );
+        assert!(chapter.content.contains("|Legend|1"), "{}", chapter.content);
+
+        let error = Graphviz::new(NoopRenderer, config.clone())
+            .with_src_dir(&src_dir)
+            .process_chapter(
+                &mut new_chapter("```dot process file=missing.dot\n```\n".into()),
+                &chapter_dir,
+            )
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            format!(
+                "Couldn't find `file=missing.dot` of the graphviz block at ./:2 in chapter '{}', \
+                 looked for {} and {}",
+                CHAPTER_NAME,
+                chapter_dir.join("missing.dot").display(),
+                src_dir.join("missing.dot").display()
+            )
+        );
+
+        let error = Graphviz::new(NoopRenderer, config.clone())
+            .with_src_dir(&src_dir)
+            .process_chapter(
+                &mut new_chapter(
+                    "```dot process file=diagrams/pipeline.dot\ndigraph { a }\n```\n".into(),
+                ),
+                &chapter_dir,
+            )
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            format!(
+                "The graphviz block at ./:2 in chapter '{}' has code as well as \
+                 `file=diagrams/pipeline.dot`, leave the block empty to render the file or \
+                 remove the option to render the code",
+                CHAPTER_NAME
+            )
+        );
+    }
+
+    #[test]
+    fn links_work_on_the_print_page() {
+        let content = r#"```dot process Plain
+digraph Plain { a }
+```
+
+```dot process width=50% Sized
+digraph Sized { a }
+```
+
+```dot process thumbnail=100px Thumb
+digraph Thumb { a }
+```
+"#;
+        let root = tempfile::tempdir().unwrap();
+        let chapter_dir = root.path().join("src/part1");
+        let printed = |config: &GraphvizConfig| {
+            let mut chapter = new_chapter(content.into());
+            Graphviz::new(FileRenderer, config.clone())
+                .process_chapter(&mut chapter, &chapter_dir)
+                .unwrap();
+            // what mdbook does to a chapter for print.html, which is at the book's root
+            let html = mdbook::utils::render_markdown_with_path(
+                &chapter.content,
+                false,
+                Some(Path::new("part1/chapter.md")),
+            );
+            Regex::new(r#"(?:src|href)="([^"]+)""#)
+                .unwrap()
+                .captures_iter(&html)
+                .map(|captures| captures[1].to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            printed(&GraphvizConfig::default()),
+            vec![
+                format!("part1/{}_plain.generated.svg", NORMALIZED_CHAPTER_NAME),
+                format!("part1/{}_sized.generated.svg", NORMALIZED_CHAPTER_NAME),
+                format!("part1/{}_thumb.generated.svg", NORMALIZED_CHAPTER_NAME),
+                format!(
+                    "part1/{}_thumb_thumb.generated.svg",
+                    NORMALIZED_CHAPTER_NAME
+                ),
+            ]
+        );
+        assert_eq!(
+            printed(&GraphvizConfig {
+                output_dir: Some(root.path().join("src/generated")),
+                ..GraphvizConfig::default()
+            })[0],
+            format!(
+                "part1/../generated/{}_plain.generated.svg",
+                NORMALIZED_CHAPTER_NAME
+            )
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn images_in_a_subdirectory() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(src_dir.join("part1")).unwrap();
+        let dot = fake_dot(root.path());
+        fs::create_dir_all(src_dir.join("part1/_graphviz")).unwrap();
+        fs::write(src_dir.join("part1/_graphviz/old.generated.svg"), "").unwrap();
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            CHAPTER_NAME,
+            "```dot process Flow\ndigraph Test { a -> b }\n```\n".into(),
+            PathBuf::from("part1/setup.md"),
+            vec![],
+        ));
+
+        let config = format!(
+            "dot-command = {:?}\noutput-to-file = true\nimage-subdir = \"_graphviz\"\nprune = true",
+            dot
+        );
+        let book = GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book)
+            .unwrap();
+        let chapter = match book.iter().next() {
+            Some(BookItem::Chapter(chapter)) => chapter,
+            _ => panic!("the chapter is gone"),
+        };
+        assert_eq!(
+            chapter.content,
+            "![Flow](_graphviz/part1_setup_flow.generated.svg \"Flow\")\n"
+        );
+        assert!(src_dir
+            .join("part1/_graphviz/part1_setup_flow.generated.svg")
+            .is_file());
+        assert!(!src_dir.join("part1/_graphviz/old.generated.svg").exists());
+    }
+
+    #[test]
+    fn links_are_percent_encoded() {
+        let mut chapter = Chapter::new(
+            "Ünicode & Spaces",
+            "```dot process Flow\ndigraph Flow { a }\n```\n\n```dot process filename=\"my café & bar\" Bar\ndigraph Bar { b }\n```\n"
+                .into(),
+            PathBuf::from("./"),
+            vec![],
+        );
+        let root = tempfile::tempdir().unwrap();
+        let config = GraphvizConfig {
+            output_dir: Some(root.path().join("src/my images")),
+            ..GraphvizConfig::default()
+        };
+        Graphviz::new(FileRenderer, config.clone())
+            .process_chapter(&mut chapter, &root.path().join("src"))
+            .unwrap();
+
+        assert_eq!(
+            chapter.content,
+            "![Flow](my%20images/%C3%BCnicode__spaces_flow.generated.svg \"Flow\")\n\n\
+             ![Bar](my%20images/my%20caf%C3%A9%20%26%20bar.svg \"Bar\")\n"
+        );
+    }
+
+    /// Fails for the blocks with these names
+    struct FailingFor(&'static [&'static str]);
+
+    impl GraphvizRenderer for FailingFor {
+        fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+            let name = &context.block.graph_name;
+            if self.0.contains(&name.as_str()) {
+                return Err(Error::msg(format!("{} is broken", name)).into());
+            }
+
+            NoopRenderer.render_graphviz(context)
+        }
+    }
+
+    #[test]
+    fn all_failures_are_reported() {
+        let content = r#"```dot process First
+digraph Test { a -> }
+```
+
+```dot process Fine
+digraph Test { a -> b }
+```
+
+```dot process Third
+digraph Test { a -> }
+```"#;
+
+        let renderer = FailingFor(&["First", "Third"]);
+        let config = GraphvizConfig::default();
+        let error = Graphviz::new(renderer, config.clone())
+            .process_chapter(&mut new_chapter(content.into()), Path::new("./"))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                r#"2 graphs failed to render:
+
+Couldn't render graph 'First' at ./:2, block 1 of chapter '{0}': First is broken
+
+Couldn't render graph 'Third' at ./:10, block 3 of chapter '{0}': Third is broken"#,
+                CHAPTER_NAME
+            )
+        );
+
+        // errors from several chapters are listed together
+        let other = Graphviz::new(FailingFor(&["Fine"]), config.clone())
+            .process_chapter(&mut new_chapter(content.into()), Path::new("./"))
+            .unwrap_err();
+        let combined = GraphErrors::combine(vec![error, other]).to_string();
+        assert!(
+            combined.starts_with("3 graphs failed to render:\n\n"),
+            "{}",
+            combined
+        );
+        assert!(combined.ends_with("Fine is broken"), "{}", combined);
+    }
+
+    #[test]
+    fn collisions_are_reported_with_failures() {
+        let content = r#"```dot process Overview
+digraph Test { a -> b }
+```
+
+```dot process Overview
+digraph Test { b -> c }
+```
+
+```dot process Broken
+digraph Test { a -> }
+```"#;
+
+        let error = Graphviz::new(FailingFor(&["Broken"]), GraphvizConfig::default())
+            .process_chapter(&mut new_chapter(content.into()), Path::new("./"))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                r#"2 graphs failed to render:
+
+Graph blocks 1 ('Overview') at ./:2 and 2 ('Overview') at ./:6 in chapter '{0}' both render to {1}_overview.generated.svg
+
+Couldn't render graph 'Broken' at ./:10, block 3 of chapter '{0}': Broken is broken"#,
+                CHAPTER_NAME, NORMALIZED_CHAPTER_NAME
+            )
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn errors_say_where_they_are_from() {
+        let mut chapter = new_chapter(
+            r#"```dot
+digraph Test { a -> b }
+```
+
+```dot process Broken
+digraph Test {
+```
+"#
+            .into(),
+        );
+
+        let error = Graphviz::new(FailingRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, &PathBuf::from("./"))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Couldn't render graph 'Broken' at ./:6, block 1 of chapter '{}': Error response from Graphviz for graph 'Broken' (exit status: 1):\nError: syntax error in line 3 (./:8) near '}}'\n\n  6 | digraph Test {{",
+                CHAPTER_NAME
+            )
+        );
+
+        // for people whose graphs shouldn't end up in logs
+        let config = GraphvizConfig {
+            error_excerpts: Some(false),
+            ..GraphvizConfig::default()
+        };
+        let error = Graphviz::new(FailingRenderer, config.clone())
+            .process_chapter(&mut chapter, &PathBuf::from("./"))
+            .unwrap_err();
+        assert!(error.to_string().ends_with("near '}'"), "{}", error);
+    }
+
+    #[test]
+    fn only_blocks_are_replaced() {
+        let content = r#"# Chapter
+
+| Left | Right |
+|:-----|------:|
+| a    |     b |
+
+Text with a footnote[^note] and <span class="raw">html</span>.
+```dot process Flow
+digraph Test { a -> b }
+```
+Right after.
+
+1. First
+   * nested
+     ~~~dot process Nested
+     digraph Test { a -> b }
+     ~~~
+2. Second
+
+> Quoted
+> ```dot process Quoted
+> digraph Test { a -> b }
+> ```
+
+<div align="center">
+  <b>kept</b>
+</div>
+
+[^note]: The footnote.
+"#;
+
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert_eq!(
+            chapter.content,
+            format!(
+                r#"# Chapter
+
+| Left | Right |
+|:-----|------:|
+| a    |     b |
+
+Text with a footnote[^note] and <span class="raw">html</span>.
+
+![Flow]({0}_flow.generated.svg "Flow")
+
+Right after.
+
+1. First
+   * nested
+
+     ![Nested]({0}_nested.generated.svg "Nested")
+
+2. Second
+
+> Quoted
+>
+> ![Quoted]({0}_quoted.generated.svg "Quoted")
+
+<div align="center">
+  <b>kept</b>
+</div>
+
+[^note]: The footnote.
+"#,
+                NORMALIZED_CHAPTER_NAME
+            )
+        );
+    }
+
+    #[test]
+    fn tables_survive() {
+        let table = r#"| Graph | Engine | Notes          |
+|:------|:------:|---------------:|
+| Flow  | `dot`  | ~~old~~ **new** |"#;
+        let content = format!(
+            "{0}\n\n```dot process Flow\ndigraph Test {{ a -> b }}\n```\n\n{0}\n",
+            table
+        );
+
+        let mut chapter = new_chapter(content);
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert_eq!(
+            chapter.content,
+            format!(
+                "{0}\n\n![Flow]({1}_flow.generated.svg \"Flow\")\n\n{0}\n",
+                table, NORMALIZED_CHAPTER_NAME
+            )
+        );
+
+        // and in captions, which are rendered from events
+        let config = GraphvizConfig::default();
+        let mut chapter = new_chapter(
+            "```dot process caption=\"Flow ~~v1~~ v2\" Flow\ndigraph Test { a -> b }\n```\n"
+                .to_string(),
+        );
+        Graphviz::new(FileRenderer, config.clone())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert!(
+            chapter.content.contains("\n\nFlow ~~v1~~ v2"),
+            "{}",
+            chapter.content
+        );
+    }
+
+    #[test]
+    fn footnotes_survive() {
+        let content = r#"Graphs[^dot] are rendered by graphviz[^gv].
+
+```dot process Flow
+digraph Test { a -> b }
+```
+
+[^dot]: Written in the dot language.
+[^gv]: See <https://graphviz.org>.
+"#;
+
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert_eq!(
+            chapter.content,
+            format!(
+                r#"Graphs[^dot] are rendered by graphviz[^gv].
+
+![Flow]({0}_flow.generated.svg "Flow")
+
+[^dot]: Written in the dot language.
+[^gv]: See <https://graphviz.org>.
+"#,
+                NORMALIZED_CHAPTER_NAME
+            )
+        );
+    }
+
+    #[test]
+    fn raw_html_survives() {
+        let content = r#"<div class="warning">
+  <script>
+    if (a < b && c > d) { widget("x"); }
+  </script>
+</div>
+
+```dot process caption="Press <kbd>Ctrl</kbd> to zoom" Flow
+digraph Test { a -> b }
+```
+
+<!-- keep   this    spacing -->
+Press <kbd>Ctrl</kbd> + <kbd>C</kbd>.
+"#;
+
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert_eq!(
+            chapter.content,
+            format!(
+                r#"<div class="warning">
+  <script>
+    if (a < b && c > d) {{ widget("x"); }}
+  </script>
+</div>
+
+![Flow]({0}_flow.generated.svg "Flow")
+
+Press <kbd>Ctrl</kbd> to zoom
+
+<!-- keep   this    spacing -->
+Press <kbd>Ctrl</kbd> + <kbd>C</kbd>.
+"#,
+                NORMALIZED_CHAPTER_NAME
+            )
+        );
+    }
+
+    #[test]
+    fn blocks_in_lists_and_quotes() {
+        let content = r#"1. Install graphviz.
+2. Write a graph:
+
+   ```dot process Step
+   digraph Step { a -> b }
+   ```
+3. Build the book.
+
+> Note:
+> ```dot process Quoted
+> digraph Quoted { c -> d }
+> ```
+> That's all.
+"#;
+
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert_eq!(
+            chapter.content,
+            format!(
+                r#"1. Install graphviz.
+2. Write a graph:
+
+   ![Step]({0}_step.generated.svg "Step")
+
+3. Build the book.
+
+> Note:
+>
+> ![Quoted]({0}_quoted.generated.svg "Quoted")
+>
+> That's all.
+"#,
+                NORMALIZED_CHAPTER_NAME
+            )
+        );
+
+        // the images stay in the item and the quote they were written in, and the list isn't split
+        let mut containers = vec![];
+        let mut images = vec![];
+        let (mut lists, mut items) = (0, 0);
+        for event in markdown_parser(&chapter.content) {
+            match event {
+                Event::Start(Tag::List(_)) => lists += 1,
+                Event::Start(Tag::Item) => {
+                    items += 1;
+                    containers.push(items);
+                }
+                Event::Start(Tag::BlockQuote) => containers.push(0),
+                Event::End(Tag::Item) | Event::End(Tag::BlockQuote) => {
+                    containers.pop();
+                }
+                Event::Start(Tag::Image(..)) => images.push(containers.clone()),
+                _ => {}
+            }
+        }
+        assert_eq!((lists, items), (1, 3));
+        assert_eq!(images, vec![vec![2], vec![0]]);
+    }
+
+    #[test]
+    fn indented_code_blocks() {
+        let indented = r#"Written like this:
+
+    ```dot process Example
+    digraph Example {
+        a -> b
+    }
+    ```
+
+        nested	indentation and a tab
+"#;
+
+        let mut chapter = new_chapter(format!(
+            "{}\n```dot process Flow\ndigraph Test {{ a -> b }}\n```\n",
+            indented
+        ));
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert_eq!(
+            chapter.content,
+            format!(
+                "{}\n![Flow]({}_flow.generated.svg \"Flow\")\n",
+                indented, NORMALIZED_CHAPTER_NAME
+            )
+        );
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let content = "# Chapter\n\nSome text\n```dot process Flow\ndigraph Test {\n    a [label=\"one\ntwo\"]\n}\n```\nMore text\n\n```dot process skip\ndigraph Skipped {}\n```\n";
+        let config = GraphvizConfig {
+            filenames: Filenames::Hashed,
+            ..GraphvizConfig::default()
+        };
+        let graphviz = Graphviz::new(NoopRenderer, config.clone());
+
+        let mut lf = new_chapter(content.into());
+        graphviz.process_chapter(&mut lf, Path::new("./")).unwrap();
+        let mut crlf = new_chapter(content.replace('\n', "\r\n"));
+        graphviz
+            .process_chapter(&mut crlf, Path::new("./"))
+            .unwrap();
+
+        // the same file name, so the same code was hashed
+        assert!(lf.content.contains(".generated.svg"), "{}", lf.content);
+        assert_eq!(crlf.content, lf.content.replace('\n', "\r\n"));
+    }
+
+    #[test]
+    fn strikethrough_and_task_lists_survive() {
+        let content = r#"The ~~old~~ new flow:
+
+- [x] draw it
+- [ ] ~~render it~~ check it
+
+  ```dot process caption="The ~~old~~ new flow" Flow
+  digraph Flow { a -> b }
+  ```
+- [ ] ship it
+"#;
+
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert_eq!(
+            chapter.content,
+            format!(
+                r#"The ~~old~~ new flow:
+
+- [x] draw it
+- [ ] ~~render it~~ check it
+
+  ![Flow]({0}_flow.generated.svg "Flow")
+
+  The ~~old~~ new flow
+
+- [ ] ship it
+"#,
+                NORMALIZED_CHAPTER_NAME
+            )
+        );
+
+        let mut tasks = vec![];
+        let mut struck = 0;
+        for event in markdown_parser(&chapter.content) {
+            match event {
+                Event::TaskListMarker(done) => tasks.push(done),
+                Event::Start(Tag::Strikethrough) => struck += 1,
+                _ => {}
+            }
+        }
+        assert_eq!(tasks, vec![true, false, false]);
+        assert_eq!(struck, 3);
+    }
+
+    #[test]
+    fn punctuation_is_left_alone() {
+        let prose =
+            "\"Straight\" and “curly” quotes, 'single' and ‘curly’ ones -- dashes --- and … or ...";
+        let content = format!(
+            "{0}\n\n```dot process caption=\"It's -- well... 'done'\" Flow\ndigraph Flow {{ a -> b }}\n```\n\n{0}\n",
+            prose
+        );
+
+        let mut chapter = new_chapter(content);
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert_eq!(
+            chapter.content,
+            format!(
+                "{0}\n\n![Flow]({1}_flow.generated.svg \"Flow\")\n\nIt's -- well... 'done'\n\n{0}\n",
+                prose, NORMALIZED_CHAPTER_NAME
+            )
+        );
+    }
+
+    #[test]
+    fn names_without_letters() {
+        assert_eq!(normalize_id("第3章"), "第3章");
+        assert_eq!(normalize_id("Глава Первая"), "глава_первая");
+        assert_eq!(normalize_id("→ Flow"), "_flow");
+        assert_eq!(normalize_id(""), "");
+
+        // they fall back to a hash of the name
+        let stems: HashSet<String> = ["🎉", "🎉🚀", "!!!", "???", "-"]
+            .iter()
+            .map(|name| normalize_id(name))
+            .collect();
+        assert_eq!(stems.len(), 5);
+        for stem in &stems {
+            assert!(
+                stem.len() == 8 && stem.chars().all(|ch| ch.is_ascii_hexdigit()),
+                "{}",
+                stem
+            );
+        }
+
+        let mut chapter = Chapter::new(
+            "第3章",
+            "```dot process 🎉\ndigraph Test { a -> b }\n```\n\n```dot process ???\ndigraph Test { a -> b }\n```\n"
+                .into(),
+            PathBuf::from("chapter3.md"),
+            vec![],
+        );
+        let config = GraphvizConfig {
+            chapter_names: ChapterNames::Title,
+            ..GraphvizConfig::default()
+        };
+        process_chapter_with_config(&mut chapter, config).unwrap();
+        assert!(
+            chapter
+                .content
+                .contains(&format!("第3章_{}.generated.svg", normalize_id("🎉"))),
+            "{}",
+            chapter.content
+        );
+        assert!(
+            chapter
+                .content
+                .contains(&format!("第3章_{}.generated.svg", normalize_id("???"))),
+            "{}",
+            chapter.content
+        );
+    }
+
+    #[test]
+    fn excerpts() {
+        let source = SourceLine {
+            file: "intro.md".into(),
+            line: 5,
+        };
+        let code: Vec<String> = (1..=30).map(|line| format!("a{} -> b", line)).collect();
+        let code = code.join("\n");
+
+        assert_eq!(
+            source.excerpt(&code, "Error: syntax error in line 12 near '->'"),
+            r#"     | ...
+  12 | a8 -> b
+  13 | a9 -> b
+  14 | a10 -> b
+  15 | a11 -> b
+> 16 | a12 -> b
+  17 | a13 -> b
+  18 | a14 -> b
+  19 | a15 -> b
+  20 | a16 -> b
+  21 | a17 -> b
+     | ... 13 more lines"#
+        );
+
+        // without a line it starts at the top, long lines are cut off
+        let code = format!("digraph {{\n{}\n}}", "a -> ".repeat(100));
+        assert_eq!(
+            source.excerpt(&code, "Error: out of memory"),
+            format!(
+                "  5 | digraph {{\n  6 | {}...\n  7 | }}",
+                &"a -> ".repeat(24)
+            )
+        );
+    }
+
+    #[test]
+    fn expected_errors_must_fail() {
+        let mut chapter = new_chapter(
+            r#"```dot process expect-error Fixed
+digraph Test {
+    a -> b
+}
+```
+"#
+            .into(),
+        );
+
+        let error = process_chapter(&mut chapter).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Couldn't render graph 'Fixed' at ./:2, block 1 of chapter '{}': Expected graph 'Fixed' to fail, but Graphviz rendered it (remove `expect-error` if it's fixed)",
+                CHAPTER_NAME
+            )
+        );
+    }
+
+    #[test]
+    fn multiple_graphs_in_a_block() {
+        let mut chapter = new_chapter(
+            r#"# Chapter
+```dot process Variants
+digraph First {
+    a -> b
+}
+
+digraph {
+    a -> c
+}
+```
+"#
+            .into(),
+        );
+
+        let expected = format!(
+            r#"# Chapter
+
+![First]({0}_variants_0.generated.svg "First")
+
+![Variants 2]({0}_variants_1.generated.svg "Variants 2")
+"#,
+            NORMALIZED_CHAPTER_NAME
+        );
+
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, &PathBuf::from("./"))
+            .unwrap();
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[test]
+    fn alt_text() {
+        let mut chapter = new_chapter(
+            r#"```dot process alt="Request flow [between] *services*" Request Flow
+digraph Test { a -> b }
+```
+
+```dot process Fallback
+digraph Test { a -> b }
+```
+"#
+            .into(),
+        );
+
+        let expected = format!(
+            r#"![Request flow \[between\] \*services\*]({0}_request_flow.generated.svg "Request Flow")
+
+![Fallback]({0}_fallback.generated.svg "Fallback")
+"#,
+            NORMALIZED_CHAPTER_NAME
+        );
+
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, &PathBuf::from("./"))
+            .unwrap();
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[test]
+    fn captions() {
+        let content = r#"```dot process caption="The *whole* flow" Flow
+digraph Test { a -> b }
+```
+
+```dot process Plain
+digraph Test { a -> b }
+```
+"#;
+
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, &PathBuf::from("./"))
+            .unwrap();
+
+        // the name still names the file and is the title
+        let expected = format!(
+            r#"![Flow]({0}_flow.generated.svg "Flow")
+
+The *whole* flow
+
+![Plain]({0}_plain.generated.svg "Plain")
+"#,
+            NORMALIZED_CHAPTER_NAME
+        );
+        assert_eq!(chapter.content, expected);
+
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(InlineRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, &PathBuf::from("./"))
+            .unwrap();
+
+        let expected = r#"<div><svg><title>Flow</title></svg></div>
+
+The *whole* flow
+
+<div><svg><title>Plain</title></svg></div>
+"#;
+        assert_eq!(chapter.content, expected);
+
+        assert_eq!(
+            caption_events("The *whole* flow"),
+            vec![
+                Event::Start(Tag::Paragraph),
+                Event::Text("The ".into()),
+                Event::Start(Tag::Emphasis),
+                Event::Text("whole".into()),
+                Event::End(Tag::Emphasis),
+                Event::Text(" flow".into()),
+                Event::End(Tag::Paragraph),
+            ]
+        );
+    }
+
+    #[test]
+    fn sized_images() {
+        let mut chapter = new_chapter(
+            r#"```dot process width="60%" alt="A & B" Tall "Graph"
+digraph Test { a -> b }
+```
+
+```dot process height=400px
+digraph Test { a -> b }
+```
+"#
+            .into(),
+        );
+
+        let expected = format!(
+            r#"<img src="{0}_tall_graph.generated.svg" alt="A &amp; B" title="Tall &quot;Graph&quot;" style="width:60%">
+
+<img src="{0}_432a3c80.generated.svg" alt="" style="height:400px">
+"#,
+            NORMALIZED_CHAPTER_NAME
+        );
+
+        assert_idempotent(FileRenderer, &mut chapter, GraphvizConfig::default());
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[test]
+    fn linked_images() {
+        let mut chapter = new_chapter(
+            r#"```dot process link=self Big
+digraph Test { a -> b }
+```
+
+```dot process link=../architecture.html width=50%
+digraph Test { a -> b }
+```
+"#
+            .into(),
+        );
+
+        let expected = format!(
+            r#"[![Big]({0}_big.generated.svg "Big")]({0}_big.generated.svg)
+
+<a href="../architecture.html"><img src="{0}_432a3c80.generated.svg" alt="" style="width:50%"></a>
+"#,
+            NORMALIZED_CHAPTER_NAME
+        );
+
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, &PathBuf::from("./"))
+            .unwrap();
+
+        assert_eq!(chapter.content, expected);
+
+        let info_string = InfoString::parse("dot process link=\"a b\"", INFO_STRING_PREFIX)
+            .unwrap()
+            .unwrap();
+        assert!(GraphvizBlockBuilder::new(
+            &info_string,
+            "",
+            "".into(),
+            String::new(),
+            &GraphvizConfig::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn file_names() {
+        let mut chapter = new_chapter(
+            r#"```dot process filename=scheduler-overview Scheduler
+digraph Test { a -> b }
+```
+
+```dot process filename=variants
+digraph First { a -> b }
+digraph Second { a -> b }
+```
+"#
+            .into(),
+        );
+
+        let expected = r#"![Scheduler](scheduler-overview.svg "Scheduler")
+
+![First](variants_0.svg "First")
+
+![Second](variants_1.svg "Second")
+"#;
+
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, &PathBuf::from("./"))
+            .unwrap();
+
+        assert_eq!(chapter.content, expected);
+
+        for invalid in &["../escape", "dir/name", "dir\\name", ".."] {
+            let info_string = InfoString::parse(
+                &format!("dot process filename={}", invalid),
+                INFO_STRING_PREFIX,
+            )
+            .unwrap()
+            .unwrap();
+            assert!(
+                GraphvizBlockBuilder::new(
+                    &info_string,
+                    "",
+                    "".into(),
+                    String::new(),
+                    &GraphvizConfig::default()
+                )
+                .is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn colliding_file_names() {
+        let mut chapter = new_chapter(
+            r#"```dot process filename=overview First
+digraph Test { a -> b }
+```
+
+```dot process
+digraph Test { a -> b }
+```
+
+```dot process filename=overview Third
+digraph Test { a -> b }
+```
+"#
+            .into(),
+        );
+
+        let error = process_chapter(&mut chapter).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Graph blocks 1 ('First') at ./:2 and 3 ('Third') at ./:10 in chapter 'Test Chapter' both render to overview.svg"
+        );
+    }
+
+    #[test]
+    fn skipped_blocks() {
+        let mut chapter = new_chapter(
+            r#"```dot process skip Draft
+digraph Test {
+    a ->
+```
+
+```dot process skip=false Done
+digraph Test { a -> b }
+```
+"#
+            .into(),
+        );
+
+        let expected = format!(
+            r#"```dot skip
+digraph Test {{
+    a ->
+```
+
+{0}_done.generated.svg|"./{0}_done.generated.svg"|Done|0
+"#,
+            NORMALIZED_CHAPTER_NAME
+        );
+
+        process_chapter(&mut chapter).unwrap();
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[test]
+    fn titles_are_escaped() {
+        let name = r#"The "fast" <path> (v2) \o/"#;
+        let mut chapter = new_chapter(format!(
+            "```dot process {0}\ndigraph {{ a }}\n```\n\n```dot process width=10% {0}\ndigraph {{ a }}\n```\n",
+            name
+        ));
+        let config = GraphvizConfig {
+            stable_names: Some(false),
+            ..GraphvizConfig::default()
+        };
+
+        Graphviz::new(FileRenderer, config.clone())
+            .process_chapter(&mut chapter, &PathBuf::from("./"))
+            .unwrap();
+
+        // the markdown image reads back as the same title
+        let titles: Vec<String> = Parser::new(&chapter.content)
+            .filter_map(|e| match e {
+                Event::Start(Tag::Image(_, _, title)) => Some(title.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(titles, vec![name.to_string()]);
+
+        assert!(chapter
+            .content
+            .contains(r#"title="The &quot;fast&quot; &lt;path&gt; (v2) \o/" style="width:10%">"#));
+    }
+
+    #[test]
+    fn strict_info_strings() {
+        let content = "```dot process fromat=png Name\ndigraph { a }\n```\n";
+
+        // just a warning by default
+        let mut chapter = new_chapter(content.into());
+        process_chapter(&mut chapter).unwrap();
+
+        let mut chapter = new_chapter(content.into());
+        let config = GraphvizConfig {
+            strict_info_string: true,
+            ..GraphvizConfig::default()
+        };
+        let error = process_chapter_with_config(&mut chapter, config).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Invalid options for graph 'Name' in chapter 'Test Chapter': unknown option \
+             `fromat=png`, did you mean `format`?"
+        );
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        for info_string in &[
+            r#"dot process width="60%;color:red""#,
+            r#"dot process height="1px\" onload=\"""#,
+            r#"dot process width="""#,
+        ] {
+            let info_string = InfoString::parse(info_string, INFO_STRING_PREFIX)
+                .unwrap()
+                .unwrap();
+            assert!(GraphvizBlockBuilder::new(
+                &info_string,
+                "",
+                "".into(),
+                String::new(),
+                &GraphvizConfig::default()
+            )
+            .is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn engine_and_format_per_block() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        let args = root.path().join("args");
+        let dot = crate::renderer::test::fake_command(
+            root.path(),
+            "dot",
+            &format!(
+                r#"case "$1" in -V) echo "dot - graphviz version 2.43.0" >&2;; *) echo "$@" >> '{}'; cat > /dev/null; echo '<svg/>';; esac"#,
+                args.display()
+            ),
+        );
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            CHAPTER_NAME,
+            "```dot process engine=neato Spring\ngraph { a -- b }\n```\n\n\
+             ```dot process format=png Raster\ndigraph { c }\n```\n\n\
+             ```dot process Plain\ndigraph { d }\n```\n"
+                .into(),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
+
+        let config = format!(
+            "dot-command = {:?}\nengine = \"circo\"\njobs = 1\nmanifest = \"manifest.json\"",
+            dot
+        );
+        let book = GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book)
+            .unwrap();
+        let manifest = fs::read_to_string(root.path().join("manifest.json")).unwrap();
+        assert!(
+            manifest.contains("\"file\": \"src/intro_raster.generated.png\""),
+            "{}",
+            manifest
+        );
+        assert_eq!(
+            fs::read_to_string(&args).unwrap(),
+            "-Tsvg -Kneato\n-Tpng -Kcirco\n-Tsvg -Kcirco\n"
+        );
+        // png can't be inlined
+        assert!(src_dir.join("intro_raster.generated.png").exists());
+        match book.iter().next() {
+            Some(BookItem::Chapter(chapter)) => assert!(
+                chapter.content.contains("(intro_raster.generated.png"),
+                "{}",
+                chapter.content
+            ),
+            _ => panic!("the chapter is gone"),
+        }
+    }
+
+    #[test]
+    fn layout_overriding_the_info_string_engine() {
+        let code = "digraph {\n    layout=fdp\n    a -> b\n}";
+        let info_string = InfoString::parse("dot process engine=neato Spring", INFO_STRING_PREFIX)
+            .unwrap()
+            .unwrap();
+        let mut builder = GraphvizBlockBuilder::new(
+            &info_string,
+            "",
+            "".into(),
+            String::new(),
+            &GraphvizConfig::default(),
+        )
+        .unwrap();
+        builder.append_code(code);
+        let block = builder.build(0).remove(0);
+
+        assert_eq!(
+            layout_conflict(&block, "fdp"),
+            Some("graph 'Spring' sets layout=fdp which overrides the engine 'neato'".into())
+        );
+        assert_eq!(layout_conflict(&block, "neato"), None);
+    }
+
+    #[test]
+    fn seed_per_block() {
+        let config = GraphvizConfig {
+            seed: Some(7),
+            ..GraphvizConfig::default()
+        };
+        let block = |info_string: &str| -> Result<GraphvizBlock> {
+            let info_string = InfoString::parse(info_string, INFO_STRING_PREFIX)
+                .unwrap()
+                .unwrap();
+            let mut builder =
+                GraphvizBlockBuilder::new(&info_string, "", "".into(), String::new(), &config)?;
+            builder.append_code("graph { a -- b }");
+            Ok(builder.build(0).remove(0))
+        };
+
+        let configured = block("dot process").unwrap();
+        let seeded = block("dot process seed=42").unwrap();
+        assert_eq!(configured.seed, Some(7));
+        assert_eq!(seeded.seed, Some(42));
+        // a new start is a new layout
+        assert_ne!(configured.content_hash, seeded.content_hash);
+
+        assert_eq!(
+            block("dot process seed=-1").err().unwrap().to_string(),
+            "seed must be a non-negative integer, found: -1"
+        );
+    }
+
+    #[test]
+    fn unflatten_per_block() {
+        let config = GraphvizConfig {
+            unflatten: Some(Unflatten {
+                leaf_stagger: Some(2),
+                fanout: true,
+            }),
+            ..GraphvizConfig::default()
+        };
+        let block = |info_string: &str, config: &GraphvizConfig| -> Result<GraphvizBlock> {
+            let info_string = InfoString::parse(info_string, INFO_STRING_PREFIX)
+                .unwrap()
+                .unwrap();
+            let mut builder =
+                GraphvizBlockBuilder::new(&info_string, "", "".into(), String::new(), config)?;
+            builder.append_code("digraph { a -> {b c d} }");
+            Ok(builder.build(0).remove(0))
+        };
+
+        let plain = block("dot process", &GraphvizConfig::default()).unwrap();
+        let flagged = block("dot process unflatten", &GraphvizConfig::default()).unwrap();
+        assert_eq!(plain.unflatten, None);
+        assert_eq!(flagged.unflatten, Some(Unflatten::default()));
+        assert_ne!(plain.content_hash, flagged.content_hash);
+
+        assert_eq!(
+            block("dot process", &config).unwrap().unflatten,
+            config.unflatten
+        );
+        assert_eq!(
+            block("dot process unflatten=false", &config)
+                .unwrap()
+                .unflatten,
+            None
+        );
+        let staggered = block("dot process unflatten=5", &config).unwrap();
+        assert_eq!(
+            staggered.unflatten,
+            Some(Unflatten {
+                leaf_stagger: Some(5),
+                fanout: true,
+            })
+        );
+        assert_ne!(
+            staggered.content_hash,
+            block("dot process", &config).unwrap().content_hash
+        );
+
+        assert_eq!(
+            block("dot process unflatten=0", &config)
+                .err()
+                .unwrap()
+                .to_string(),
+            "unflatten must be true, false or a positive leaf stagger, found: 0"
+        );
+    }
+
+    #[test]
+    fn invalid_engines_and_formats() {
+        for (info_string, expected) in [
+            (
+                "dot process engine=nato",
+                "engine must be one of dot, neato, fdp, sfdp, circo, twopi, osage, patchwork, \
+                 found: nato",
+            ),
+            (
+                "dot process format=bmp",
+                "format must be one of svg, png, jpg, gif, found: bmp",
+            ),
+        ] {
+            let info_string = InfoString::parse(info_string, INFO_STRING_PREFIX)
+                .unwrap()
+                .unwrap();
+            let error = GraphvizBlockBuilder::new(
+                &info_string,
+                "",
+                "".into(),
+                String::new(),
+                &GraphvizConfig::default(),
+            )
+            .err()
+            .unwrap();
+            assert_eq!(error.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn split_top_level_graphs() {
+        assert_eq!(
+            split_graphs("digraph { a -> b }"),
+            vec!["digraph { a -> b }"]
+        );
+        assert_eq!(
+            split_graphs("graph A { a }\n\n/* } */ strict digraph \"B\" { b }"),
+            vec!["graph A { a }", "/* } */ strict digraph \"B\" { b }"]
+        );
+
+        // braces that don't delimit a graph
+        assert_eq!(
+            split_graphs(
+                r#"digraph {
+    subgraph cluster { a [label="}"] }
+    b [label=<<b>}</b>>]
+# }
+    c // }
+}"#
+            )
+            .len(),
+            1
+        );
+
+        // leftovers stay with the last graph for dot to report
+        assert_eq!(
+            split_graphs("digraph { a } digraph { b } c"),
+            vec!["digraph { a }", "digraph { b } c"]
+        );
+        assert_eq!(split_graphs("digraph { a"), vec!["digraph { a"]);
+    }
+
+    #[test]
+    fn renderers_have_options_of_their_own() {
+        let content = "```dot process theme=dark Flow\ndigraph { a }\n```\n\n\
+                       ```dot process Plain\ndigraph { a }\n```\n";
+        let config = GraphvizConfig {
+            strict_info_string: true,
+            ..GraphvizConfig::default()
+        };
+
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(ThemedRenderer, config.clone())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+        assert!(
+            chapter.content.contains("<svg class=\"dark\">"),
+            "{}",
+            chapter.content
+        );
+        assert!(
+            chapter.content.contains("<svg class=\"light\">"),
+            "{}",
+            chapter.content
+        );
+
+        // unknown to other renderers
+        let error = process_chapter_with_config(&mut new_chapter(content.into()), config)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown option `theme=dark`"), "{}", error);
+    }
+
+    #[test]
+    fn graph_ids() {
+        assert_eq!(graph_id("digraph Test { a }"), Some("Test".into()));
+        assert_eq!(
+            graph_id("// comment\nstrict graph \"My \\\"graph\\\"\" {}"),
+            Some("My \"graph\"".into())
+        );
+        assert_eq!(graph_id("DiGraph{a}"), None);
+    }
+
+    #[test]
+    fn reprocessing_is_idempotent() {
+        let content = r#"# Chapter
+```dot process Graph Name
+digraph Test {
+    a -> b
+}
+```
+
+Some text
+
+```dot process
+digraph Test {
+    a -> b
+}
+```
+
+````dot
+digraph Plain {
+    a -> b
+}
+````
+
+```dot process skip Draft
+digraph Test {
+    a ->
+```
+
+```dot process source group=variants Wide
+digraph Test { rankdir=LR; a -> b }
+```
+
+```dot process group=variants Tall
+digraph Test { a -> b }
+```
+"#;
+
+        let configs = [
+            GraphvizConfig::default(),
+            GraphvizConfig {
+                emit_markers: true,
+                ..GraphvizConfig::default()
+            },
+            GraphvizConfig {
+                render_all_dot: true,
+                ..GraphvizConfig::default()
+            },
+            GraphvizConfig {
+                preserve_source: true,
+                render_all_dot: true,
+                ..GraphvizConfig::default()
+            },
+            GraphvizConfig {
+                carousels: true,
+                preserve_source: true,
+                ..GraphvizConfig::default()
+            },
+        ];
+        for config in configs {
+            let mut inline = new_chapter(content.into());
+            assert_idempotent(InlineRenderer, &mut inline, config.clone());
+
+            let mut file = new_chapter(content.into());
+            assert_idempotent(FileRenderer, &mut file, config.clone());
+
+            let mut client = new_chapter(content.into());
+            let client_config = GraphvizConfig {
+                backend: Backend::Client,
+                ..config.clone()
+            };
+            assert_idempotent(ClientSide, &mut client, client_config);
+
+            #[cfg(unix)]
+            {
+                let dir = tempfile::tempdir().unwrap();
+                let command = GraphvizCommand {
+                    dot: crate::renderer::test::fake_command(
+                        dir.path(),
+                        "dot",
+                        "cat > /dev/null; echo '<svg></svg>'",
+                    ),
+                    ..GraphvizCommand::default()
+                };
+                let mut embedded = new_chapter(content.into());
+                let embed_config = GraphvizConfig {
+                    embed_images: true,
+                    ..config
+                };
+                assert_idempotent(
+                    CLIGraphvizEmbedded::new(command),
+                    &mut embedded,
+                    embed_config,
+                );
+            }
+        }
+    }
+
+    fn assert_idempotent<R: GraphvizRenderer>(
+        renderer: R,
+        chapter: &mut Chapter,
+        config: GraphvizConfig,
+    ) {
+        let graphviz = Graphviz::new(renderer, config.clone());
+
+        graphviz
+            .process_chapter(chapter, &PathBuf::from("./"))
+            .unwrap();
+        let first_pass = chapter.content.clone();
+        graphviz
+            .process_chapter(chapter, &PathBuf::from("./"))
+            .unwrap();
+
+        assert_eq!(chapter.content, first_pass);
+    }
+
+    #[test]
+    fn dpi_for_raster_images() {
+        let config = GraphvizConfig {
+            dpi: Some(150),
+            ..GraphvizConfig::default()
+        };
+
+        let info_string = |info_string: &str| {
+            InfoString::parse(info_string, INFO_STRING_PREFIX)
+                .unwrap()
+                .unwrap()
+        };
+
+        assert_eq!(
+            dpi(&info_string("dot process"), &config, "png").unwrap(),
+            Some(150)
+        );
+        assert_eq!(
+            dpi(&info_string("dot process dpi=300"), &config, "png").unwrap(),
+            Some(300)
+        );
+        // svgs have no resolution
+        assert_eq!(
+            dpi(&info_string("dot process"), &config, SVG).unwrap(),
+            None
+        );
+        assert_eq!(
+            dpi(&info_string("dot process dpi=300"), &config, SVG).unwrap(),
+            None
+        );
+
+        for invalid in ["0", "-96", "high"] {
+            assert_eq!(
+                dpi(
+                    &info_string(&format!("dot process dpi={}", invalid)),
+                    &config,
+                    "png"
+                )
+                .unwrap_err()
+                .to_string(),
+                format!("dpi must be a positive integer, found: {}", invalid)
+            );
+        }
+
+        // the option isn't part of the graph's name
+        let mut chapter = new_chapter("```dot process dpi=300 Print\ndigraph { a }\n```\n".into());
+        process_chapter(&mut chapter).unwrap();
+        assert_eq!(
+            chapter.content,
+            format!(
+                "{0}_print.generated.svg|\"./{0}_print.generated.svg\"|Print|0",
+                NORMALIZED_CHAPTER_NAME
+            )
+        );
+    }
+
+    #[test]
+    fn relative_src_dir() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("src")).unwrap();
+
+        assert_eq!(
+            resolve_src_dir(root.path(), Path::new("src")).unwrap(),
+            root.path().join("src")
+        );
+    }
+
+    #[test]
+    fn absolute_src_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let src = tempfile::tempdir().unwrap();
+
+        assert_eq!(
+            resolve_src_dir(root.path(), src.path()).unwrap(),
+            src.path()
+        );
+    }
+
+    #[test]
+    fn missing_src_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+
+        let error = resolve_src_dir(root.path(), Path::new("src"))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains(&src_dir.display().to_string()), "{}", error);
+
+        // a file isn't a directory either
+        fs::write(&src_dir, "").unwrap();
+        assert!(resolve_src_dir(root.path(), Path::new("src")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn warms_graphviz_up_once() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("src")).unwrap();
+        // a file per call
+        let inputs = root.path().join("inputs");
+        fs::create_dir(&inputs).unwrap();
+        let dot = crate::renderer::test::fake_command(
+            root.path(),
+            "dot",
+            &format!(
+                r#"case "$1" in -V) echo "dot - graphviz version 2.43.0" >&2;; *) cat > "$(mktemp '{}/input.XXXXXX')"; echo '<svg/>';; esac"#,
+                inputs.display()
+            ),
+        );
+        let book = || {
+            let mut book = Book::new();
+            book.push_item(Chapter::new(
+                CHAPTER_NAME,
+                "```dot process\ndigraph { a }\n```\n\n\
+                 ```dot process\ndigraph { b }\n```\n\n\
+                 ```dot process\ndigraph { c }\n```\n"
+                    .into(),
+                PathBuf::from("intro.md"),
+                vec![],
+            ));
+            book
+        };
+        let inputs = || -> Vec<String> {
+            fs::read_dir(&inputs)
+                .unwrap()
+                .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+                .collect()
+        };
+        let warm_ups = || {
+            inputs()
+                .iter()
+                .filter(|input| *input == "digraph{}")
+                .count()
+        };
+
+        let config = |warm_up: usize| format!("dot-command = {:?}\nwarm-up = {}", dot, warm_up);
+        GraphvizPreprocessor
+            .run(&new_context(root.path(), &config(2)), book())
+            .unwrap();
+        assert_eq!(warm_ups(), 1);
+        assert_eq!(inputs().len(), 4);
+
+        // not worth it for so few graphs
+        GraphvizPreprocessor
+            .run(&new_context(root.path(), &config(3)), book())
+            .unwrap();
+        assert_eq!(warm_ups(), 1);
+        assert_eq!(inputs().len(), 7);
+    }
+
+    #[test]
+    fn run_fails_without_src_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let ctx = new_context(root.path(), "");
+
+        let error = GraphvizPreprocessor
+            .run(&ctx, Book::new())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("src directory"), "{}", error);
+    }
+
+    #[test]
+    fn other_renderers_leave_the_book_untouched() {
+        let root = tempfile::tempdir().unwrap();
+        let ctx = new_context(root.path(), r#"renderers = ["epub"]"#);
+        let mut book = Book::new();
+        book.push_item(new_chapter(
+            "```dot process\ndigraph Test { a -> b }\n```\n".into(),
+        ));
+
+        let processed = GraphvizPreprocessor.run(&ctx, book.clone()).unwrap();
+        assert_eq!(processed, book);
+    }
+
+    #[test]
+    fn downgrades_in_the_summary() {
+        let mut epub = GraphvizConfig::default();
+        let downgrades = epub.adapt_to_renderer("epub");
+        assert_eq!(
+            downgrade_summary("epub", &downgrades).unwrap(),
+            "Graphs were adapted to the epub renderer: the epub renderer can't display inline \
+             svgs, writing graphs to image files instead"
+        );
+
+        for renderer in ["html", "my-custom-renderer"] {
+            let downgrades = GraphvizConfig::default().adapt_to_renderer(renderer);
+            assert_eq!(downgrade_summary(renderer, &downgrades), None);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn renderers_get_what_they_can_display() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        let dot = fake_dot(root.path());
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            CHAPTER_NAME,
+            "```dot process group=flows Flow\ndigraph { a }\n```\n\n\
+             ```dot process group=flows Steps\ndigraph { b }\n```\n"
+                .into(),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
+        let config = format!("dot-command = {:?}", dot);
+        let content = |renderer: &str| {
+            let mut ctx = new_context(root.path(), &config);
+            ctx.renderer = renderer.into();
+            match GraphvizPreprocessor
+                .run(&ctx, book.clone())
+                .unwrap()
+                .iter()
+                .next()
+            {
+                Some(BookItem::Chapter(chapter)) => chapter.content.clone(),
+                _ => panic!("the chapter is gone"),
+            }
+        };
+
+        let html = content("html");
+        assert!(html.contains("<svg/>"), "{}", html);
+        assert!(html.contains("graphviz-carousel"), "{}", html);
+
+        let epub = content("epub");
+        assert!(epub.contains("(intro_flow.generated.svg"), "{}", epub);
+        assert!(!epub.contains("graphviz-carousel"), "{}", epub);
+
+        // a renderer we don't know gets what's configured, without our stylesheet
+        let custom = content("my-custom-renderer");
+        assert!(custom.contains("<svg/>"), "{}", custom);
+        assert!(!custom.contains("graphviz-carousel"), "{}", custom);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn prunes_stale_images() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(src_dir.join("nested")).unwrap();
+        let dot = fake_dot(root.path());
+        let leftovers = ["old.generated.svg", "nested/old_0.generated.svg.sha256"];
+        let hand_made = ["logo.svg", "nested/diagram.png"];
+        // from a build for a renderer drawing pngs
+        let other_format = "nested/old_0.generated.png";
+        for file in leftovers.iter().chain(&hand_made).chain([&other_format]) {
+            fs::write(src_dir.join(file), "").unwrap();
+        }
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            CHAPTER_NAME,
+            "```dot process\ndigraph Test { a -> b }\n```\n".into(),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
+
+        // only when asked to
+        let config = format!("dot-command = {:?}\noutput-to-file = true", dot);
+        GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book.clone())
+            .unwrap();
+        assert!(src_dir.join(leftovers[0]).exists());
+
+        let config = format!(
+            "{}\nprune = true\n[preprocessor.graphviz.output]\nepub = \"png\"",
+            config
+        );
+        GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book.clone())
+            .unwrap();
+        for file in &leftovers {
+            assert!(!src_dir.join(file).exists(), "{}", file);
+        }
+        for file in &hand_made {
+            assert!(src_dir.join(file).exists(), "{}", file);
+        }
+        assert!(src_dir.join(other_format).exists());
+        assert!(src_dir.join("intro_432a3c80.generated.svg").exists());
+
+        // the epub build only prunes pngs, the html build's images stay
+        let mut ctx = new_context(root.path(), &config);
+        ctx.renderer = "epub".into();
+        GraphvizPreprocessor.run(&ctx, book).unwrap();
+        let mut generated = HashSet::new();
+        generated_files(&src_dir, &mut generated);
+        let mut generated: Vec<_> = generated
+            .iter()
+            .map(|path| {
+                path.strip_prefix(&src_dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        generated.sort();
+        assert_eq!(
+            generated,
+            vec![
+                "intro_432a3c80.generated.svg",
+                "intro_6181cff2.generated.png",
+                "intro_6181cff2.generated.png.sha256"
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_dot_files() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        let dot = fake_dot(root.path());
+        // the filter's output is what dot sees
+        let filter = crate::renderer::test::fake_command(root.path(), "filter", "sed 's/ a / x /'");
+        fs::write(src_dir.join("intro_old.generated.dot"), "").unwrap();
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            CHAPTER_NAME,
+            "```dot process Flow\ndigraph Test { a -> b }\n```\n".into(),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
+
+        let config = format!(
+            "dot-command = {:?}\nfilters = [{:?}]\noutput-to-file = true\nkeep-dot = true\nprune = true",
+            dot, filter
+        );
+        GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book)
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(src_dir.join("intro_flow.generated.dot")).unwrap(),
+            "digraph Test { x -> b }"
+        );
+        assert!(src_dir.join("intro_flow.generated.svg").exists());
+        assert!(!src_dir.join("intro_old.generated.dot").exists());
+    }
+
+    #[test]
+    fn dumps_dot_files() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        // an image from an earlier build is kept, one no graph is named like is pruned
+        fs::write(src_dir.join("intro_flow.generated.svg"), "<svg/>").unwrap();
+        fs::write(src_dir.join("intro_old.generated.dot"), "").unwrap();
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            CHAPTER_NAME,
+            "```dot process Flow\ndigraph Test { a -> b }\n```\n".into(),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
+
+        let config = "backend = \"dump\"\ndot-command = \"/does/not/exist/dot\"\nprune = true";
+        let book = GraphvizPreprocessor
+            .run(&new_context(root.path(), config), book)
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(src_dir.join("intro_flow.generated.dot")).unwrap(),
+            "digraph Test { a -> b }"
+        );
+        assert!(src_dir.join("intro_flow.generated.svg").exists());
+        assert!(!src_dir.join("intro_old.generated.dot").exists());
+        match book.iter().next() {
+            Some(BookItem::Chapter(chapter)) => assert!(
+                chapter.content.contains("(intro_flow.generated.svg"),
+                "{}",
+                chapter.content
+            ),
+            _ => panic!("the chapter is gone"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn post_processes_image_files() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        let dot = crate::renderer::test::fake_command(
+            root.path(),
+            "dot",
+            r#"case "$1" in -V) echo "dot - graphviz version 2.43.0" >&2;; *) cat > /dev/null; echo '<svg></svg>';; esac"#,
+        );
+        let hook = crate::renderer::test::fake_command(
+            root.path(),
+            "hook",
+            r#"sed -i 's/<svg>/<svg class="small">/' "$1"; echo "$1" >> hook.log"#,
+        );
+        let run = |hook: &Path| {
+            let mut book = Book::new();
+            book.push_item(Chapter::new(
+                CHAPTER_NAME,
+                "```dot process Flow\ndigraph Test { a -> b }\n```\n".into(),
+                PathBuf::from("intro.md"),
+                vec![],
+            ));
+            let config = format!(
+                "dot-command = {:?}\npost-process = [{:?}, \"{{file}}\"]\noutput-to-file = true\ncache-dir = \"cache\"",
+                dot, hook
+            );
+            GraphvizPreprocessor.run(&new_context(root.path(), &config), book)
+        };
+        let image = src_dir.join("intro_flow.generated.svg");
+
+        run(&hook).unwrap();
+        let svg = fs::read_to_string(&image).unwrap();
+        assert!(svg.starts_with("<svg class=\"small\"></svg>"), "{}", svg);
+        // the hook got a copy in the image's directory
+        let log = fs::read_to_string(root.path().join("hook.log")).unwrap();
+        assert!(log.starts_with(src_dir.to_str().unwrap()), "{}", log);
+        assert!(log.trim_end().ends_with(".svg"), "{}", log);
+        assert!(!log.contains("intro_flow.generated.svg"), "{}", log);
+
+        // the cache has the processed image
+        fs::remove_file(&image).unwrap();
+        run(&hook).unwrap();
+        assert_eq!(fs::read_to_string(&image).unwrap(), svg);
+        let log = fs::read_to_string(root.path().join("hook.log")).unwrap();
+        assert_eq!(log.lines().count(), 1, "{}", log);
+
+        let failing = crate::renderer::test::fake_command(
+            root.path(),
+            "failing",
+            "echo 'out of memory' >&2; exit 1",
+        );
+        let error = run(&failing).unwrap_err().to_string();
+        assert!(
+            error.contains(&format!("post-process failed for {}", image.display())),
+            "{}",
+            error
+        );
+        assert!(error.contains("out of memory"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_post_processes_are_run_again() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        let dot = crate::renderer::test::fake_command(
+            root.path(),
+            "dot",
+            r#"case "$1" in -V) echo "dot - graphviz version 2.43.0" >&2;; *) cat > /dev/null; echo '<svg></svg>';; esac"#,
+        );
+        // fails the first time it's run
+        let hook = crate::renderer::test::fake_command(
+            root.path(),
+            "hook",
+            r#"if [ ! -e failed ]; then touch failed; exit 1; fi; sed -i 's/<svg>/<svg class="small">/' "$1""#,
+        );
+        let run = || {
+            let mut book = Book::new();
+            book.push_item(Chapter::new(
+                CHAPTER_NAME,
+                "```dot process Flow\ndigraph Test { a -> b }\n```\n".into(),
+                PathBuf::from("intro.md"),
+                vec![],
+            ));
+            let config = format!(
+                "dot-command = {:?}\npost-process = [{:?}, \"{{file}}\"]\noutput-to-file = true\ncache-dir = \"cache\"",
+                dot, hook
+            );
+            GraphvizPreprocessor.run(&new_context(root.path(), &config), book)
+        };
+
+        assert!(run().is_err());
+        run().unwrap();
+        let svg = fs::read_to_string(src_dir.join("intro_flow.generated.svg")).unwrap();
+        assert!(svg.starts_with("<svg class=\"small\"></svg>"), "{}", svg);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn inline_renders_dont_share_post_processed_images() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        let dot = crate::renderer::test::fake_command(
+            root.path(),
+            "dot",
+            r#"case "$1" in -V) echo "dot - graphviz version 2.43.0" >&2;; *) cat > /dev/null; echo '<svg></svg>';; esac"#,
+        );
+        let hook = crate::renderer::test::fake_command(
+            root.path(),
+            "hook",
+            r#"sed -i 's/<svg>/<svg class="small">/' "$1""#,
+        );
+        let run = |output_to_file: bool| {
+            let mut book = Book::new();
+            book.push_item(Chapter::new(
+                CHAPTER_NAME,
+                "```dot process Flow\ndigraph Test { a -> b }\n```\n".into(),
+                PathBuf::from("intro.md"),
+                vec![],
+            ));
+            let config = format!(
+                "dot-command = {:?}\npost-process = [{:?}, \"{{file}}\"]\noutput-to-file = {}\ncache-dir = \"cache\"",
+                dot, hook, output_to_file
+            );
+            GraphvizPreprocessor
+                .run(&new_context(root.path(), &config), book)
+                .unwrap()
+        };
+        let content = |book: Book| match book.iter().next() {
+            Some(BookItem::Chapter(chapter)) => chapter.content.clone(),
+            _ => panic!("the chapter is gone"),
+        };
+
+        // graphviz's output is cached for the inline render...
+        let inline = content(run(false));
+        assert!(inline.contains("<svg></svg>"), "{}", inline);
+        // ...but the image file still goes through the hook
+        run(true);
+        let svg = fs::read_to_string(src_dir.join("intro_flow.generated.svg")).unwrap();
+        assert!(svg.starts_with("<svg class=\"small\"></svg>"), "{}", svg);
+        // and the processed image doesn't come back inline
+        let inline = content(run(false));
+        assert!(inline.contains("<svg></svg>"), "{}", inline);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn manifest_lists_spilled_svgs() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        let dot = fake_dot(root.path());
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Intro",
+            "```dot process Flow\ndigraph { a }\n```\n\n```dot process Steps\ndigraph { b }\n```\n"
+                .into(),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
+
+        // room for one svg
+        let config = format!(
+            "dot-command = {:?}\nmax-inline-total-bytes = 20\n\
+             manifest = \"graphviz-manifest.json\"",
+            dot
+        );
+        GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book)
+            .unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(root.path().join("graphviz-manifest.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            manifest,
+            serde_json::json!([
+                {
+                    "chapter": "intro.md",
+                    "chapter_title": "Intro",
+                    "name": "Flow",
+                    "file": null,
+                    "format": "svg",
+                    "hash": "87787629",
+                },
+                {
+                    "chapter": "intro.md",
+                    "chapter_title": "Intro",
+                    "name": "Steps",
+                    "file": "src/intro_steps.generated.svg",
+                    "format": "svg",
+                    "hash": "23f2605e",
+                    "spilled": true,
+                },
+            ])
+        );
+        assert!(src_dir.join("intro_steps.generated.svg").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn svgs_are_spilled_in_the_chapters_order() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        // the first graphs finish last
+        let dot = crate::renderer::test::fake_command(
+            root.path(),
+            "dot",
+            r#"case "$1" in -V) echo "dot - graphviz version 2.43.0" >&2; exit;; esac
+code=$(cat)
+case "$code" in *Slow*) sleep 0.2;; esac
+printf '<svg><!--%4000s--></svg>\n' ''"#,
+        );
+        let content: Vec<String> = (0..40)
+            .map(|index| {
+                let graph = if index < 4 { "Slow" } else { "Fast" };
+                format!(
+                    "```dot process Graph {}\ndigraph {} {{ a }}\n```",
+                    index, graph
+                )
+            })
+            .collect();
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Intro",
+            content.join("\n\n"),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
+
+        // room for two svgs
+        let config = format!(
+            "dot-command = {:?}\nmax-inline-total-bytes = 10000\njobs = 8",
+            dot
+        );
+        let book = GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book)
+            .unwrap();
+        let chapter = match book.sections[0] {
+            BookItem::Chapter(ref chapter) => chapter,
+            _ => panic!("expected a chapter"),
+        };
+
+        assert_eq!(chapter.content.matches("<svg>").count(), 2);
+        assert!(
+            chapter.content.len() < 10000 + 40 * 100,
+            "{}",
+            chapter.content.len()
+        );
+        for index in 0..40 {
+            let spilled = src_dir.join(format!("intro_graph_{}.generated.svg", index));
+            assert_eq!(spilled.exists(), index >= 2, "{}", index);
+            if index >= 2 {
+                // written like any other image file, so the next build recognizes it
+                assert!(fs::read_to_string(&spilled)
+                    .unwrap()
+                    .contains("<!-- mdbook-graphviz: sha256="));
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_a_manifest() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        let dot = fake_dot(root.path());
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Intro",
+            "```dot process Flow\ndigraph Test { a -> b }\n```\n".into(),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
+        book.push_item(Chapter::new(
+            "Setup",
+            "```dot process\ndigraph Test { a -> b }\n```\n\n\
+             ```dot process Steps\ndigraph One { a }\ndigraph Two { b }\n```\n"
+                .into(),
+            PathBuf::from("part1/setup.md"),
+            vec![],
+        ));
+
+        let config = format!(
+            "dot-command = {:?}\noutput-to-file = true\noutput-dir = \"src/generated\"\n\
+             manifest = \"graphviz-manifest.json\"",
+            dot
+        );
+        let expected = serde_json::json!([
+            {
+                "chapter": "intro.md",
+                "chapter_title": "Intro",
+                "name": "Flow",
+                "file": "src/generated/intro_flow.generated.svg",
+                "format": "svg",
+                "hash": "432a3c80",
+            },
+            {
+                "chapter": "part1/setup.md",
+                "chapter_title": "Setup",
+                "name": "",
+                "file": "src/generated/part1_setup_432a3c80.generated.svg",
+                "format": "svg",
+                "hash": "432a3c80",
+            },
+            {
+                "chapter": "part1/setup.md",
+                "chapter_title": "Setup",
+                "name": "One",
+                "file": "src/generated/part1_setup_steps_0.generated.svg",
+                "format": "svg",
+                "hash": "0b39fef4",
+            },
+            {
+                "chapter": "part1/setup.md",
+                "chapter_title": "Setup",
+                "name": "Two",
+                "file": "src/generated/part1_setup_steps_1.generated.svg",
+                "format": "svg",
+                "hash": "558363f1",
+            },
+        ]);
+        let manifest = || -> serde_json::Value {
+            serde_json::from_str(
+                &fs::read_to_string(root.path().join("graphviz-manifest.json")).unwrap(),
+            )
+            .unwrap()
+        };
+
+        GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book.clone())
+            .unwrap();
+        assert_eq!(manifest(), expected);
+
+        // everything comes from the cache the second time
+        fs::remove_file(root.path().join("graphviz-manifest.json")).unwrap();
+        GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book)
+            .unwrap();
+        assert_eq!(manifest(), expected);
+    }
+
+    #[test]
+    fn client_graphs_are_in_the_manifest() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("src")).unwrap();
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Intro",
+            "```dot process Flow\ndigraph Test { a -> b }\n```\n".into(),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
 
-        let src_dir = ctx.root.clone().join(&ctx.config.book.src);
-        let mut error = Ok(());
+        let config = "backend = \"client\"\nmanifest = \"graphviz-manifest.json\"";
+        GraphvizPreprocessor
+            .run(&new_context(root.path(), config), book)
+            .unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(root.path().join("graphviz-manifest.json")).unwrap(),
+        )
+        .unwrap();
+        // the browser draws it, there's no file
+        assert_eq!(
+            manifest,
+            serde_json::json!([
+                {
+                    "chapter": "intro.md",
+                    "chapter_title": "Intro",
+                    "name": "Flow",
+                    "file": null,
+                    "format": "svg",
+                    "hash": "432a3c80",
+                },
+            ])
+        );
+    }
 
-        book.for_each_mut(|item: &mut BookItem| {
-            // only continue editing the book if we don't have any errors
-            if error.is_ok() {
-                if let BookItem::Chapter(ref mut chapter) = item {
-                    let path = chapter.path.as_ref().unwrap();
-                    let mut full_path = src_dir.join(path);
+    #[test]
+    fn updates_the_gitignore() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        let gitignore = root.path().join(".gitignore");
+        let config = GraphvizConfig {
+            update_gitignore: true,
+            ..GraphvizConfig::default()
+        };
 
-                    // remove the chapter filename
-                    full_path.pop();
+        // a missing file is created
+        update_gitignore(root.path(), &src_dir, &config).unwrap();
+        assert_eq!(fs::read_to_string(&gitignore).unwrap(), "*.generated.*\n");
 
-                    error = if !output_to_file {
-                        Graphviz::<CLIGraphviz>::new().process_chapter(chapter, &full_path)
-                    } else {
-                        Graphviz::<CLIGraphvizToFile>::new().process_chapter(chapter, &full_path)
-                    };
-                }
-            }
-        });
+        // the rest of the file is kept as it is
+        fs::write(&gitignore, "book\r\n# build output\r\ntarget").unwrap();
+        update_gitignore(root.path(), &src_dir, &config).unwrap();
+        let updated = fs::read_to_string(&gitignore).unwrap();
+        assert_eq!(
+            updated,
+            "book\r\n# build output\r\ntarget\r\n*.generated.*\r\n"
+        );
 
-        error.map(|_| book)
-    }
+        // and it's only added once
+        update_gitignore(root.path(), &src_dir, &config).unwrap();
+        assert_eq!(fs::read_to_string(&gitignore).unwrap(), updated);
 
-    fn supports_renderer(&self, _renderer: &str) -> bool {
-        // since we're just outputting markdown images or inline html, this "should" support any renderer
-        true
+        // the output-dir is ignored as a whole, in src/.gitignore when that's the only one
+        fs::remove_file(&gitignore).unwrap();
+        fs::write(src_dir.join(".gitignore"), "*.bak\n").unwrap();
+        let config = GraphvizConfig {
+            output_dir: Some(src_dir.join("generated")),
+            ..config
+        };
+        update_gitignore(root.path(), &src_dir, &config).unwrap();
+        assert_eq!(
+            fs::read_to_string(src_dir.join(".gitignore")).unwrap(),
+            "*.bak\n/generated/\n"
+        );
+        assert!(!gitignore.exists());
     }
-}
 
+    #[cfg(unix)]
+    #[test]
+    fn gitignore_is_only_updated_when_asked() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("src")).unwrap();
+        let dot = fake_dot(root.path());
+        let config = format!("dot-command = {:?}", dot);
 
-impl<R: GraphvizRenderer> Graphviz<R> {
-    fn new() -> Graphviz<R> {
-        Graphviz {
-            _phantom: PhantomData,
-        }
+        GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), Book::new())
+            .unwrap();
+        assert!(!root.path().join(".gitignore").exists());
+
+        let config = format!("{}\nupdate-gitignore = true", config);
+        GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), Book::new())
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(root.path().join(".gitignore")).unwrap(),
+            "*.generated.*\n"
+        );
     }
 
-    fn process_chapter(&self, chapter: &mut Chapter, chapter_path: &PathBuf) -> Result<()> {
-        let mut buf = String::with_capacity(chapter.content.len());
-        let mut graphviz_block_builder: Option<GraphvizBlockBuilder> = None;
-        let mut image_index = 0;
+    #[cfg(feature = "native-layout")]
+    #[test]
+    fn rust_backend_needs_no_graphviz() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("src")).unwrap();
+        let mut book = Book::new();
+        book.push_item(new_chapter(
+            "```dot process Flow\ndigraph Test { a -> b }\n```\n".into(),
+        ));
 
-        let event_results: Result<Vec<Vec<Event>>> = Parser::new(&chapter.content)
-            .map(|e| {
-                if let Some(mut builder) = graphviz_block_builder.take() {
-                    match e {
-                        Event::Text(ref text) => {
-                            builder.append_code(&**text);
-                            graphviz_block_builder = Some(builder);
+        let config = r#"backend = "rust"
+dot-command = "/does/not/exist/dot""#;
+        let book = GraphvizPreprocessor
+            .run(&new_context(root.path(), config), book)
+            .unwrap();
+        let chapter = match book.iter().next() {
+            Some(BookItem::Chapter(chapter)) => chapter,
+            _ => panic!("the chapter is gone"),
+        };
+        assert!(chapter.content.contains("<svg"), "{}", chapter.content);
+    }
 
-                            Ok(vec![])
-                        }
-                        Event::End(Tag::CodeBlock(ref info_string)) => {
-                            assert_eq!(
-                                Some(0),
-                                (&**info_string).find(INFO_STRING_PREFIX),
-                                "We must close our graphviz block"
-                            );
+    #[test]
+    fn other_backends_need_no_graphviz() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("src")).unwrap();
+        let content = "```dot process Flow\ndigraph Test {\n    a -> b\n\n    b -> c\n}\n```\n";
 
-                            // finish our digraph
-                            let block = builder.build(image_index);
-                            image_index += 1;
+        let run = |backend: &str| {
+            let mut book = Book::new();
+            book.push_item(new_chapter(content.into()));
+            let config = format!(
+                "backend = \"{}\"\ndot-command = \"/does/not/exist/dot\"",
+                backend
+            );
+            let book = GraphvizPreprocessor
+                .run(&new_context(root.path(), &config), book)
+                .unwrap();
+            match book.iter().next() {
+                Some(BookItem::Chapter(chapter)) => chapter.content.clone(),
+                _ => panic!("the chapter is gone"),
+            }
+        };
 
-                            R::render_graphviz(block)
-                        }
-                        _ => {
-                            graphviz_block_builder = Some(builder);
+        assert_eq!(
+            run("client"),
+            "<pre class=\"graphviz-client\" title=\"Flow\" aria-label=\"Flow\">digraph Test {\n    \
+             a -&gt; b\n\n    b -&gt; c\n}</pre>\n"
+        );
+        assert_eq!(run("none"), content);
+    }
 
-                            Ok(vec![])
-                        }
-                    }
-                } else {
-                    match e {
-                        Event::Start(Tag::CodeBlock(ref info_string))
-                            if (&**info_string).find(INFO_STRING_PREFIX) == Some(0) =>
-                        {
-                            graphviz_block_builder = Some(GraphvizBlockBuilder::new(
-                                &**info_string,
-                                &chapter.name.clone(),
-                                chapter_path.clone(),
-                            ));
-
-                            Ok(vec![])
-                        }
-                        _ => Ok(vec![e]),
-                    }
-                }
-            })
-            .collect();
+    #[cfg(unix)]
+    #[test]
+    fn check_mode() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        let dot = crate::renderer::test::fake_command(
+            root.path(),
+            "dot",
+            r#"case "$1" in -V) echo "dot - graphviz version 2.43.0" >&2;; *) if grep -q Broken; then echo "Error: syntax error in line 1" >&2; exit 1; fi; echo '<svg/>';; esac"#,
+        );
+        let mut book = Book::new();
+        for (name, content) in [
+            (
+                "First",
+                "```dot process Fine\ndigraph Fine { a -> b }\n```\n",
+            ),
+            ("Second", "```dot process\ndigraph Broken { a -> }\n```\n"),
+        ] {
+            book.push_item(Chapter::new(
+                name,
+                content.into(),
+                PathBuf::from(format!("{}.md", name)),
+                vec![],
+            ));
+        }
+        let config = format!(
+            "dot-command = {:?}\ncheck = true\noutput-dir = \"src/generated\"\ncache-dir = \"cache\"",
+            dot
+        );
 
-        // get our result and combine our internal Vecs
-        let events = event_results?.into_iter().flatten();
+        let error = GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book.clone())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with(
+                "Couldn't render an unnamed graph at Second.md:2, block 1 of chapter 'Second'"
+            ),
+            "{}",
+            error
+        );
 
-        cmark(events, &mut buf, None)?;
+        // the book's left as it is and nothing is written
+        book.sections.pop();
+        let checked = GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book.clone())
+            .unwrap();
+        assert_eq!(checked, book);
+        assert_eq!(fs::read_dir(&src_dir).unwrap().count(), 0);
+        assert!(!root.path().join("cache").exists());
+    }
 
-        chapter.content = buf;
+    #[cfg(unix)]
+    #[test]
+    fn embedded_images_leave_src_alone() {
+        let root = tempfile::tempdir().unwrap();
+        let src_dir = root.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        let dot = fake_dot(root.path());
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            CHAPTER_NAME,
+            "```dot process thumbnail=100px Flow\ndigraph Flow { a -> b }\n```\n".into(),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
 
-        Ok(())
+        let config = format!(
+            "dot-command = {:?}\noutput-to-file = true\nembed-images = true\nprune = true",
+            dot
+        );
+        let book = GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book)
+            .unwrap();
+        match book.sections[0] {
+            BookItem::Chapter(ref chapter) => assert!(
+                chapter
+                    .content
+                    .starts_with("![Flow](data:image/svg+xml;base64,"),
+                "{}",
+                chapter.content
+            ),
+            _ => unreachable!(),
+        }
+        assert_eq!(fs::read_dir(&src_dir).unwrap().count(), 0);
     }
-}
 
-struct GraphvizBlockBuilder {
-    chapter_name: String,
-    graph_name: String,
-    code: String,
-    path: PathBuf,
-}
+    #[cfg(unix)]
+    #[test]
+    fn draft_chapters() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("src")).unwrap();
+        let dot = fake_dot(root.path());
+        let mut draft = Chapter::new_draft("Draft", vec![]);
+        draft.sub_items.push(BookItem::Chapter(Chapter::new(
+            "Child",
+            "```dot process Flow\ndigraph Flow { a -> b }\n```\n".into(),
+            PathBuf::from("child.md"),
+            vec!["Draft".into()],
+        )));
+        let mut book = Book::new();
+        book.push_item(draft);
 
-impl GraphvizBlockBuilder {
-    fn new<S: Into<String>>(
-        info_string: S,
-        chapter_name: S,
-        path: PathBuf,
-    ) -> GraphvizBlockBuilder {
-        let info_string: String = info_string.into();
-
-        let chapter_name = chapter_name.into();
-
-        // check if we can have a name at the end of our info string
-        let graph_name = if Some(' ') == info_string.chars().nth(INFO_STRING_PREFIX.len()) {
-            &info_string[INFO_STRING_PREFIX.len() + 1..].trim()
-        } else {
-            ""
+        let book = GraphvizPreprocessor
+            .run(
+                &new_context(root.path(), &format!("dot-command = {:?}", dot)),
+                book,
+            )
+            .unwrap();
+        let child = match book.sections[0] {
+            BookItem::Chapter(ref draft) => match draft.sub_items[0] {
+                BookItem::Chapter(ref child) => child,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
         };
+        assert!(
+            child.content.starts_with("<div><svg/></div>"),
+            "{}",
+            child.content
+        );
+    }
 
-        GraphvizBlockBuilder {
-            chapter_name: chapter_name.trim().into(),
-            graph_name: graph_name.into(),
-            code: String::new(),
-            path,
+    #[cfg(unix)]
+    #[test]
+    fn chapters_with_the_same_name_collide() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("src/part2")).unwrap();
+        let dot = fake_dot(root.path());
+        let mut book = Book::new();
+        for path in ["intro.md", "part2/intro.md"] {
+            book.push_item(Chapter::new(
+                "Introduction",
+                "```dot process Flow\ndigraph Test { a -> b }\n```\n".into(),
+                PathBuf::from(path),
+                vec![],
+            ));
         }
+
+        // next to their chapters they're in different directories
+        let config = format!("dot-command = {:?}\noutput-to-file = true", dot);
+        GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book.clone())
+            .unwrap();
+
+        // in one directory they're told apart by their chapter's file
+        let config = format!("{}\noutput-dir = \"src/generated\"", config);
+        let rendered = GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book.clone())
+            .unwrap();
+        let links: Vec<&str> = rendered
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(chapter.content.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                "![Flow](generated/intro_flow.generated.svg \"Flow\")\n",
+                "![Flow](../generated/part2_intro_flow.generated.svg \"Flow\")\n",
+            ]
+        );
+
+        // unless they're named by their title
+        let config = format!("{}\nchapter-names = \"title\"", config);
+        let error = GraphvizPreprocessor
+            .run(&new_context(root.path(), &config), book)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            format!(
+                "Two graphs render to {}: graph 'Flow' at intro.md:2 in chapter 'Introduction' and \
+                 graph 'Flow' at part2/intro.md:2 in chapter 'Introduction'. Give one of them a \
+                 `filename` or rename a chapter",
+                root.path()
+                    .join("src/generated/introduction_flow.generated.svg")
+                    .display()
+            )
+        );
     }
 
-    fn append_code<S: Into<String>>(&mut self, code: S) {
-        self.code.push_str(&code.into());
+    #[test]
+    fn creates_the_output_dir() {
+        let root = tempfile::tempdir().unwrap();
+
+        let output_dir = resolve_output_dir(root.path(), Path::new("generated/graphviz")).unwrap();
+        assert_eq!(output_dir, root.path().join("generated/graphviz"));
+        assert!(output_dir.is_dir());
+
+        // it's fine if it's already there
+        assert!(resolve_output_dir(root.path(), Path::new("generated/graphviz")).is_ok());
     }
 
-    fn build(self, index: usize) -> GraphvizBlock {
-        let GraphvizBlockBuilder {
-            chapter_name,
-            graph_name,
-            code,
-            path,
-        } = self;
-        let cleaned_code = code.trim();
+    #[test]
+    fn relative_links() {
+        assert_eq!(
+            relative_link(Path::new("/book/src"), Path::new("/book/src")),
+            ""
+        );
+        assert_eq!(
+            relative_link(Path::new("/book/src"), Path::new("/book/src/images")),
+            "images/"
+        );
+        assert_eq!(
+            relative_link(
+                Path::new("/book/src/guide/nested"),
+                Path::new("/book/src/generated")
+            ),
+            "../../generated/"
+        );
+        assert_eq!(
+            relative_link(
+                Path::new("/book/src/my guide"),
+                Path::new("/book/src/my images")
+            ),
+            "../my%20images/"
+        );
 
-        GraphvizBlock {
-            graph_name,
-            code: cleaned_code.into(),
-            chapter_name,
-            chapter_path: path,
-            index,
-        }
+        // joined with the platform's separator, linked with `/`
+        let src = Path::new("/book").join("src");
+        assert_eq!(
+            relative_link(
+                &src.join(".").join("part1").join("nested"),
+                &src.join("part2")
+                    .join("..")
+                    .join("generated")
+                    .join("graphs")
+            ),
+            "../../generated/graphs/"
+        );
     }
-}
 
-pub struct GraphvizBlock {
-    pub graph_name: String,
-    pub code: String,
-    pub chapter_name: String,
-    pub chapter_path: PathBuf,
-    pub index: usize,
-}
+    #[test]
+    fn images_in_the_output_dir() {
+        let mut chapter = new_chapter(
+            r#"```dot process link=self Name
+digraph Test { a -> b }
+```"#
+                .into(),
+        );
 
-impl GraphvizBlock {
-    pub fn file_name(&self) -> String {
-        let image_name = if !self.graph_name.is_empty() {
+        let config = GraphvizConfig {
+            output_dir: Some("/book/src/generated".into()),
+            ..GraphvizConfig::default()
+        };
+        Graphviz::new(FileRenderer, config.clone())
+            .process_chapter(&mut chapter, Path::new("/book/src/guide/nested"))
+            .unwrap();
+
+        assert_eq!(
+            chapter.content,
             format!(
-                "{}_{}_{}.generated",
-                normalize_id(&self.chapter_name),
-                normalize_id(&self.graph_name),
-                self.index
+                "[![Name](../../generated/{0}_name.generated.svg \"Name\")](../../generated/{0}_name.generated.svg)",
+                NORMALIZED_CHAPTER_NAME
             )
-        } else {
+        );
+
+        let mut chapter = new_chapter("```dot process Name\ndigraph Test { a -> b }\n```".into());
+        Graphviz::new(NoopRenderer, config.clone())
+            .process_chapter(&mut chapter, Path::new("/book/src/guide"))
+            .unwrap();
+
+        assert_eq!(
+            chapter.content,
+            format!(
+                r#"{0}_name.generated.svg|"/book/src/generated/{0}_name.generated.svg"|Name|0"#,
+                NORMALIZED_CHAPTER_NAME
+            )
+        );
+    }
+
+    #[test]
+    fn image_format_in_file_names() {
+        let mut chapter = new_chapter(
+            r#"```dot process Name
+digraph Test { a -> b }
+```
+
+```dot process filename=overview
+digraph Test { a -> b }
+```"#
+                .into(),
+        );
+
+        let config = GraphvizConfig {
+            output_to_file: true,
+            image_format: Some("png".into()),
+            ..GraphvizConfig::default()
+        };
+        Graphviz::new(FileRenderer, config.clone())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+
+        assert_eq!(
+            chapter.content,
             format!(
-                "{}_{}.generated",
-                normalize_id(&self.chapter_name),
-                self.index
+                "![Name]({0}_name.generated.png \"Name\")\n\n![](overview.png)",
+                NORMALIZED_CHAPTER_NAME
             )
+        );
+    }
+
+    #[test]
+    fn blocks_stay_code_without_graphviz() {
+        let mut chapter = new_chapter(
+            r#"```dot process Name
+digraph Test { a -> b }
+```
+
+```dot process expect-error Broken
+digraph Test { a -> }
+```
+"#
+            .into(),
+        );
+
+        let config = GraphvizConfig {
+            graphviz_missing: true,
+            ..GraphvizConfig::default()
         };
+        Graphviz::new(NoopRenderer, config.clone())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+
+        assert_eq!(
+            chapter.content,
+            r#"```dot skip
+digraph Test { a -> b }
+```
 
-        format!("{}.svg", image_name)
+```dot skip
+digraph Test { a -> }
+```
+"#
+        );
     }
 
-    pub fn output_path(&self) -> PathBuf {
-        self.chapter_path.join(self.file_name())
+    #[test]
+    fn parallel_rendering_is_capped() {
+        let content: Vec<String> = (0..20)
+            .map(|index| format!("```dot process Graph {}\ndigraph {{ a -> b }}\n```", index))
+            .collect();
+        let mut chapter = new_chapter(content.join("\n\n"));
+
+        let config = GraphvizConfig {
+            jobs: Some(3),
+            ..GraphvizConfig::default()
+        };
+        let graphviz = Graphviz::new(ConcurrencyRenderer::default(), config.clone());
+        graphviz
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
+
+        let peak = graphviz.renderer.peak.load(Ordering::SeqCst);
+        assert!(peak <= 3, "{}", peak);
+
+        // the graphs still come out in order
+        let expected: Vec<String> = (0..20)
+            .map(|index| {
+                format!(
+                    r#"{0}_graph_{1}.generated.svg|"./{0}_graph_{1}.generated.svg"|Graph {1}|{1}"#,
+                    NORMALIZED_CHAPTER_NAME, index
+                )
+            })
+            .collect();
+        assert_eq!(chapter.content, expected.join("\n\n"));
     }
-}
 
-fn normalize_id(content: &str) -> String {
-    content
-        .chars()
-        .filter_map(|ch| {
-            if ch.is_alphanumeric() {
-                Some(ch.to_ascii_lowercase())
-            } else if ch.is_whitespace() || ch == '_' || ch == '-' {
-                Some('_')
-            } else {
-                None
-            }
-        })
-        .collect::<String>()
-}
+    #[test]
+    fn thumbnails_link_to_the_full_size_image() {
+        let mut chapter = new_chapter(
+            r#"```dot process thumbnail=400px Big
+digraph Test { a -> b }
+```
 
-#[cfg(test)]
-mod test {
-    use super::*;
+```dot process thumbnail=400 link=../architecture.html filename=overview
+digraph Test { a -> b }
+```"#
+                .into(),
+        );
 
-    static CHAPTER_NAME: &str = "Test Chapter";
-    static NORMALIZED_CHAPTER_NAME: &str = "test_chapter";
+        Graphviz::new(FileRenderer, GraphvizConfig::default())
+            .process_chapter(&mut chapter, Path::new("./"))
+            .unwrap();
 
-    struct NoopRenderer;
+        assert_eq!(
+            chapter.content,
+            format!(
+                r#"[![Big]({0}_big_thumb.generated.svg "Big")]({0}_big.generated.svg)
 
-    impl GraphvizRenderer for NoopRenderer {
-        fn render_graphviz<'a>(block: GraphvizBlock) -> Result<Vec<Event<'a>>> {
-            let file_name = block.file_name();
-            let output_path = block.output_path();
-            let GraphvizBlock {
-                graph_name, index, ..
-            } = block;
+[![](overview_thumb.svg)](../architecture.html)"#,
+                NORMALIZED_CHAPTER_NAME
+            )
+        );
 
-            Ok(vec![Event::Text(
-                format!("{}|{:?}|{}|{}", file_name, output_path, graph_name, index).into(),
-            )])
+        for invalid in &["0", "400em", "-1", "wide"] {
+            let info_string =
+                InfoString::parse(&format!("dot process thumbnail={}", invalid), "dot process")
+                    .unwrap()
+                    .unwrap();
+            assert!(
+                GraphvizBlockBuilder::new(
+                    &info_string,
+                    "",
+                    "".into(),
+                    String::new(),
+                    &GraphvizConfig::default()
+                )
+                .is_err(),
+                "{}",
+                invalid
+            );
         }
     }
 
     #[test]
-    fn only_preprocess_flagged_blocks() {
-        let expected = r#"# Chapter
+    fn file_names_from_a_template() {
+        let content = r#"```dot process Flow
+digraph Test { a -> b }
+```
 
-````dot
-digraph Test {
-    a -> b
-}
-````"#;
+```dot process
+digraph Test { a -> c }
+```"#;
 
-        let mut chapter = new_chapter(expected.into());
+        let config = GraphvizConfig {
+            filename_template: FilenameTemplate::parse("{name}-{index}-{format}").unwrap(),
+            stable_names: Some(false),
+            ..GraphvizConfig::default()
+        };
+        let mut chapter = new_chapter(content.into());
+        process_chapter_with_config(&mut chapter, config).unwrap();
+        assert_eq!(
+            chapter.content,
+            r#"flow-0-svg.generated.svg|"./flow-0-svg.generated.svg"|Flow|0
 
-        process_chapter(&mut chapter).unwrap();
+1-svg.generated.svg|"./1-svg.generated.svg"||1"#
+        );
 
-        assert_eq!(chapter.content, expected);
+        // the hash follows the code, not the position
+        let config = GraphvizConfig {
+            filename_template: FilenameTemplate::parse("{hash}").unwrap(),
+            ..GraphvizConfig::default()
+        };
+        let mut first = new_chapter(content.into());
+        process_chapter_with_config(&mut first, config.clone()).unwrap();
+        let mut blocks: Vec<&str> = content.split("\n\n").collect();
+        blocks.reverse();
+        let mut swapped = new_chapter(blocks.join("\n\n"));
+        process_chapter_with_config(&mut swapped, config).unwrap();
+
+        let first_name = first.content.split('|').next().unwrap().to_string();
+        assert_eq!(first_name.len(), "12345678.generated.svg".len());
+        assert!(swapped.content.contains(&first_name), "{}", swapped.content);
     }
 
     #[test]
-    fn no_name() {
-        let mut chapter = new_chapter(
-            r#"# Chapter
-```dot process
-digraph Test {
-    a -> b
-}
+    fn hashed_file_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = r#"```dot process Flow
+digraph Test { a -> b }
 ```
-"#
-            .into(),
-        );
 
-        let expected = format!(
-            r#"# Chapter
+```dot process
+digraph Test { a -> c }
+```"#;
+        let config = GraphvizConfig {
+            filenames: Filenames::Hashed,
+            ..GraphvizConfig::default()
+        };
+        for old in &[
+            "test_chapter_flow.0123abcd.generated.svg",
+            "test_chapter_flow.0123abcd.generated.svg.sha256",
+            "test_chapter.0123abcd.generated.svg",
+            // other graphs and files that aren't ours
+            "test_chapter_flowchart.0123abcd.generated.svg",
+            "test_chapter_flow.0123abcd.svg",
+            "test_chapter_flow.generated.svg",
+        ] {
+            fs::write(dir.path().join(old), "").unwrap();
+        }
 
-{}_0.generated.svg|"./{}_0.generated.svg"||0"#,
-            NORMALIZED_CHAPTER_NAME, NORMALIZED_CHAPTER_NAME
+        let mut chapter = new_chapter(content.into());
+        Graphviz::new(FileRenderer, config.clone())
+            .process_chapter(&mut chapter, dir.path())
+            .unwrap();
+        let names = Regex::new(r"\]\(([^ )]+)").unwrap();
+        let names: Vec<&str> = names
+            .captures_iter(&chapter.content)
+            .map(|captures| captures.get(1).unwrap().as_str())
+            .collect();
+        assert_eq!(names.len(), 2, "{}", chapter.content);
+        assert!(
+            Regex::new(r"^test_chapter_flow\.[0-9a-f]{8}\.generated\.svg$")
+                .unwrap()
+                .is_match(names[0]),
+            "{}",
+            names[0]
+        );
+        assert!(
+            Regex::new(r"^test_chapter\.[0-9a-f]{8}\.generated\.svg$")
+                .unwrap()
+                .is_match(names[1]),
+            "{}",
+            names[1]
         );
 
-        process_chapter(&mut chapter).unwrap();
+        let mut left: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            vec![
+                "test_chapter_flow.0123abcd.svg",
+                "test_chapter_flow.generated.svg",
+                "test_chapter_flowchart.0123abcd.generated.svg",
+            ]
+        );
 
-        assert_eq!(chapter.content, expected);
+        // the hash changes with the options, not just the code
+        let neato = GraphvizConfig {
+            engine: Some("neato".into()),
+            ..config.clone()
+        };
+        let mut again = new_chapter(content.into());
+        Graphviz::new(FileRenderer, neato)
+            .process_chapter(&mut again, dir.path())
+            .unwrap();
+        assert!(!again.content.contains(names[0]), "{}", again.content);
     }
 
     #[test]
-    fn named_blocks() {
+    fn configured_info_string() {
         let mut chapter = new_chapter(
-            r#"# Chapter
-```dot process Graph Name
-digraph Test {
-    a -> b
-}
+            r#"```graphviz Name
+digraph Test { a -> b }
+```
+
+```dot process
+digraph Test { a -> b }
 ```
 "#
             .into(),
         );
 
         let expected = format!(
-            r#"# Chapter
+            r#"{0}_name.generated.svg|"./{0}_name.generated.svg"|Name|0
 
-{}_graph_name_0.generated.svg|"./{}_graph_name_0.generated.svg"|Graph Name|0"#,
-            NORMALIZED_CHAPTER_NAME, NORMALIZED_CHAPTER_NAME
+```dot process
+digraph Test {{ a -> b }}
+```
+"#,
+            NORMALIZED_CHAPTER_NAME
         );
 
+        let config = GraphvizConfig {
+            info_string: Some("graphviz".into()),
+            ..GraphvizConfig::default()
+        };
+        process_chapter_with_config(&mut chapter, config).unwrap();
+
+        assert_eq!(chapter.content, expected);
+    }
+
+    #[test]
+    fn graphviz_fences() {
+        let content = r#"```graphviz First
+digraph Test { a -> b }
+```
+
+```dot process Second
+digraph Test { a -> b }
+```
+"#;
+
+        // plain code unless enabled
+        let mut chapter = new_chapter(content.into());
         process_chapter(&mut chapter).unwrap();
+        assert!(chapter.content.starts_with("```graphviz First\n"));
+
+        let mut chapter = new_chapter(content.into());
+        let config = GraphvizConfig {
+            accept_graphviz_fence: true,
+            ..GraphvizConfig::default()
+        };
+        process_chapter_with_config(&mut chapter, config).unwrap();
+
+        let expected = format!(
+            r#"{0}_first.generated.svg|"./{0}_first.generated.svg"|First|0
 
+{0}_second.generated.svg|"./{0}_second.generated.svg"|Second|1
+"#,
+            NORMALIZED_CHAPTER_NAME
+        );
         assert_eq!(chapter.content, expected);
     }
 
     fn process_chapter(chapter: &mut Chapter) -> Result<()> {
-        let graphviz = Graphviz::<NoopRenderer>::new();
+        process_chapter_with_config(chapter, GraphvizConfig::default())
+    }
+
+    fn process_chapter_with_config(chapter: &mut Chapter, config: GraphvizConfig) -> Result<()> {
+        let graphviz = Graphviz::new(NoopRenderer, config.clone());
+
+        graphviz
+            .process_chapter(chapter, &PathBuf::from("./"))
+            .map(|_| ())
+    }
+
+    /// Writes a `dot` that reports a version and renders every graph as an
+    /// empty svg.
+    #[cfg(unix)]
+    fn fake_dot(root: &Path) -> PathBuf {
+        crate::renderer::test::fake_command(
+            root,
+            "dot",
+            r#"case "$1" in -V) echo "dot - graphviz version 2.43.0" >&2;; *) cat > /dev/null; echo '<svg/>';; esac"#,
+        )
+    }
+
+    fn new_context(root: &Path, preprocessor_config: &str) -> PreprocessorContext {
+        let config: mdbook::Config = format!("[preprocessor.graphviz]\n{}", preprocessor_config)
+            .parse()
+            .unwrap();
 
-        graphviz.process_chapter(chapter, &PathBuf::from("./"))
+        serde_json::from_value(serde_json::json!({
+            "root": root,
+            "config": config,
+            "renderer": "html",
+            "mdbook_version": mdbook::MDBOOK_VERSION,
+        }))
+        .unwrap()
     }
 
     fn new_chapter(content: String) -> Chapter {
-        Chapter::new(CHAPTER_NAME, content.into(), PathBuf::from("./"), vec![])
+        Chapter::new(CHAPTER_NAME, content, PathBuf::from("./"), vec![])
     }
 }
@@ -0,0 +1,180 @@
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{debug, info, log_enabled, Level};
+
+use crate::cache::Cache;
+use crate::renderer::describe_graph;
+
+/// how many of the slowest graphs the stats list
+static SLOWEST: usize = 5;
+
+/// How far along rendering the book's graphs we are, so long builds don't look hung, and how
+/// long each graph took
+#[derive(Debug)]
+pub struct Progress {
+    total: usize,
+    done: AtomicUsize,
+    quiet: bool,
+    /// the counter is updated in place, which only makes sense on a terminal
+    live: bool,
+    /// how long each graph took, with its chapter and name
+    timings: Mutex<Vec<(Duration, String, String)>>,
+    /// how many svgs were written to files past `max-inline-total-bytes`
+    spilled: AtomicUsize,
+}
+
+impl Progress {
+    pub fn new(total: usize, quiet: bool) -> Progress {
+        Progress {
+            total,
+            done: AtomicUsize::new(0),
+            quiet,
+            live: io::stderr().is_terminal(),
+            timings: Mutex::new(vec![]),
+            spilled: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn done(&self) -> usize {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    pub fn graph_done(&self, chapter: &str, graph_name: &str, elapsed: Duration) {
+        self.timings
+            .lock()
+            .unwrap()
+            .push((elapsed, chapter.into(), graph_name.into()));
+        debug!(
+            "Rendered {} in chapter '{}', {}ms",
+            describe_graph(graph_name),
+            chapter,
+            elapsed.as_millis()
+        );
+        let done = self.done.fetch_add(1, Ordering::SeqCst) + 1;
+        if !self.quiet && self.live && log_enabled!(Level::Info) {
+            eprint!("\rgraphviz: {}/{}", done, self.total);
+        }
+    }
+
+    pub fn graphs_spilled(&self, graphs: usize) {
+        self.spilled.fetch_add(graphs, Ordering::SeqCst);
+    }
+
+    pub fn chapter_done(&self, chapter: &str, graphs: usize, elapsed: Duration) {
+        if !self.quiet {
+            // starting over the line of the live counter
+            if self.live && log_enabled!(Level::Info) {
+                eprint!("\r");
+            }
+            info!("{}", self.chapter_summary(chapter, graphs, elapsed));
+        }
+    }
+
+    /// What `stats = true` prints at the end of the build
+    pub fn stats(&self, elapsed: Duration, cache: Option<&Cache>) -> String {
+        let mut timings = self.timings.lock().unwrap().clone();
+        timings.sort_by_key(|(elapsed, _, _)| std::cmp::Reverse(*elapsed));
+        let rendering: Duration = timings.iter().map(|(elapsed, _, _)| *elapsed).sum();
+
+        let mut stats = format!(
+            "Graphviz stats: {} graphs in {}ms, {}ms spent rendering",
+            timings.len(),
+            elapsed.as_millis(),
+            rendering.as_millis()
+        );
+        if let Some(cache) = cache {
+            stats.push_str(&format!(", {} from the cache", cache.hits()));
+        }
+        match self.spilled.load(Ordering::SeqCst) {
+            0 => {}
+            spilled => stats.push_str(&format!(
+                ", {} written to files past max-inline-total-bytes",
+                spilled
+            )),
+        }
+        if !timings.is_empty() {
+            stats.push_str("\nSlowest graphs:");
+        }
+        for (elapsed, chapter, graph_name) in timings.iter().take(SLOWEST) {
+            stats.push_str(&format!(
+                "\n  {}ms {} in chapter '{}'",
+                elapsed.as_millis(),
+                describe_graph(graph_name),
+                chapter
+            ));
+        }
+
+        stats
+    }
+
+    fn chapter_summary(&self, chapter: &str, graphs: usize, elapsed: Duration) -> String {
+        format!(
+            "Rendered {} graph{} in {}, {}ms ({}/{})",
+            graphs,
+            if graphs == 1 { "" } else { "s" },
+            chapter,
+            elapsed.as_millis(),
+            self.done(),
+            self.total
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summaries() {
+        let progress = Progress::new(3, true);
+        progress.graph_done("Intro", "Flow", Duration::from_millis(3));
+        assert_eq!(
+            progress.chapter_summary("intro.md", 1, Duration::from_millis(430)),
+            "Rendered 1 graph in intro.md, 430ms (1/3)"
+        );
+
+        progress.graph_done("Setup", "", Duration::from_millis(5));
+        progress.graph_done("Setup", "Steps", Duration::from_millis(4));
+        assert_eq!(
+            progress.chapter_summary("guide/setup.md", 2, Duration::from_millis(12)),
+            "Rendered 2 graphs in guide/setup.md, 12ms (3/3)"
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let progress = Progress::new(7, true);
+        assert_eq!(
+            progress.stats(Duration::from_millis(1), None),
+            "Graphviz stats: 0 graphs in 1ms, 0ms spent rendering"
+        );
+
+        for millis in 1..=7 {
+            progress.graph_done(
+                "Intro",
+                &format!("Graph {}", millis),
+                Duration::from_millis(millis),
+            );
+        }
+        let cache = Cache::new("cache".into());
+        assert_eq!(
+            progress.stats(Duration::from_millis(20), Some(&cache)),
+            r#"Graphviz stats: 7 graphs in 20ms, 28ms spent rendering, 0 from the cache
+Slowest graphs:
+  7ms graph 'Graph 7' in chapter 'Intro'
+  6ms graph 'Graph 6' in chapter 'Intro'
+  5ms graph 'Graph 5' in chapter 'Intro'
+  4ms graph 'Graph 4' in chapter 'Intro'
+  3ms graph 'Graph 3' in chapter 'Intro'"#
+        );
+
+        progress.graphs_spilled(2);
+        assert!(progress.stats(Duration::from_millis(20), None).starts_with(
+            "Graphviz stats: 7 graphs in 20ms, 28ms spent rendering, 2 written to files past \
+                 max-inline-total-bytes\n"
+        ));
+    }
+}
@@ -1,92 +1,1675 @@
-use std::io;
-use std::io::Write;
-use std::process::{Child, Command, Stdio};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use mdbook::errors::Result;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::{debug, warn};
+use mdbook::errors::{Error, Result};
 use pulldown_cmark::{Event, LinkType, Tag};
 use regex::Regex;
 
-use crate::preprocessor::GraphvizBlock;
+use crate::cache::Cache;
+use crate::config::{Backend, SVG};
+use crate::native;
+use crate::preprocessor::{escape_html, GraphvizBlock, GENERATED};
 
-pub trait GraphvizRenderer {
-    fn render_graphviz<'a>(block: GraphvizBlock) -> Result<Vec<Event<'a>>>;
+static DEFAULT_DOT: &str = "dot";
+pub static ENGINES: &[&str] = &[
+    "dot",
+    "neato",
+    "fdp",
+    "sfdp",
+    "circo",
+    "twopi",
+    "osage",
+    "patchwork",
+];
+/// added to an image file's path for the file recording what it was rendered from
+static HASH_EXTENSION: &str = "sha256";
+/// svgs record what they were rendered from in a trailing comment instead
+static HASH_COMMENT_START: &str = "<!-- mdbook-graphviz: sha256=";
+static HASH_COMMENT_END: &str = " -->";
+static SEEDED_ENGINES: &[&str] = &["neato", "fdp", "sfdp"];
+/// how often we try to start a process when the system is temporarily out of processes or files
+static SPAWN_ATTEMPTS: u32 = 5;
+/// replaced in a `render-command` by the image format, e.g. `svg`
+pub static FORMAT_PLACEHOLDER: &str = "{format}";
+/// replaced in a `render-command` by a file the command writes the image to, for tools that can't
+/// print it
+pub static OUTPUT_PLACEHOLDER: &str = "{output}";
+/// replaced in a `post-process` command by the image file it's run on
+pub static FILE_PLACEHOLDER: &str = "{file}";
+static INSTALL_HINT: &str =
+    "Install graphviz or point preprocessor.graphviz.dot-command at its 'dot' binary";
+
+/// What a renderer is lent for each graph: the block with its name, chapter, index, format and
+/// options, and the file its image goes to
+#[non_exhaustive]
+pub struct RenderContext<'b> {
+    pub block: &'b GraphvizBlock,
+    /// always the block's [`output_path`](GraphvizBlock::output_path), so the file matches the
+    /// link in the chapter
+    pub output_path: PathBuf,
+    /// the block's info string options we don't know ourselves, e.g. `theme=dark` for a
+    /// renderer listing `theme` in its [`info_string_options`](GraphvizRenderer::info_string_options)
+    pub options: &'b BTreeMap<String, String>,
+}
+
+impl<'b> RenderContext<'b> {
+    pub fn new(block: &'b GraphvizBlock) -> RenderContext<'b> {
+        RenderContext {
+            block,
+            output_path: block.output_path(),
+            options: &block.options,
+        }
+    }
+}
+
+/// The markdown events a block is replaced with
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub struct Rendered<'a> {
+    pub events: Vec<Event<'a>>,
+}
+
+impl<'a> From<Vec<Event<'a>>> for Rendered<'a> {
+    fn from(events: Vec<Event<'a>>) -> Rendered<'a> {
+        Rendered { events }
+    }
+}
+
+/// Why a block couldn't be rendered
+#[derive(Debug)]
+pub enum RendererError {
+    /// graphviz ran but rejected the graph, blocks marked `expect-error` show this
+    Rejected(RenderError),
+    /// anything else, e.g. graphviz couldn't be started or the image couldn't be written
+    Other(Error),
+}
+
+impl From<Error> for RendererError {
+    fn from(error: Error) -> RendererError {
+        match error.downcast::<RenderError>() {
+            Ok(rejected) => RendererError::Rejected(rejected),
+            Err(error) => RendererError::Other(error),
+        }
+    }
+}
+
+impl From<io::Error> for RendererError {
+    fn from(error: io::Error) -> RendererError {
+        RendererError::Other(error.into())
+    }
+}
+
+impl From<RenderError> for RendererError {
+    fn from(error: RenderError) -> RendererError {
+        RendererError::Rejected(error)
+    }
+}
+
+impl fmt::Display for RendererError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RendererError::Rejected(error) => error.fmt(f),
+            RendererError::Other(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RendererError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RendererError::Rejected(error) => Some(error),
+            RendererError::Other(error) => error.source(),
+        }
+    }
+}
+
+pub type RenderResult<'a> = std::result::Result<Rendered<'a>, RendererError>;
+
+/// Blocks of a chapter are rendered from several threads at once. A renderer writes to the
+/// context's `output_path`, so the file always matches the link in the chapter
+pub trait GraphvizRenderer: Sync {
+    fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a>;
+
+    /// Render all of a chapter's blocks in one go, with a result for each block in their order.
+    /// Only called when [`batches`](GraphvizRenderer::batches) is set, by default it renders them
+    /// one after the other
+    fn render_batch<'a>(&self, contexts: &[RenderContext]) -> Vec<RenderResult<'a>> {
+        contexts
+            .iter()
+            .map(|context| self.render_graphviz(context))
+            .collect()
+    }
+
+    /// Whether a chapter's blocks go to `render_batch` together, for backends with a high cost per
+    /// call. Otherwise they're rendered one by one, `jobs` at a time
+    fn batches(&self) -> bool {
+        false
+    }
+
+    /// Info string options of the renderer's own. They aren't reported as unknown, and reach it in
+    /// the context's [`options`](RenderContext::options)
+    fn info_string_options(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Called with each block's result in the chapter's order once all of its blocks are
+    /// rendered, for decisions that mustn't depend on which graph finished first. The result is
+    /// passed on as it is by default
+    fn settle<'a>(&self, _context: &RenderContext, rendered: RenderResult<'a>) -> RenderResult<'a> {
+        rendered
+    }
+
+    /// The image files written since the last call for blocks that were meant to go into the
+    /// chapter, e.g. past `max-inline-total-bytes`
+    fn spilled(&self) -> Vec<PathBuf> {
+        vec![]
+    }
+}
+
+/// Lets the renderer be picked from the configuration at runtime
+impl<R: GraphvizRenderer + ?Sized> GraphvizRenderer for Box<R> {
+    fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+        (**self).render_graphviz(context)
+    }
+
+    fn render_batch<'a>(&self, contexts: &[RenderContext]) -> Vec<RenderResult<'a>> {
+        (**self).render_batch(contexts)
+    }
+
+    fn batches(&self) -> bool {
+        (**self).batches()
+    }
+
+    fn info_string_options(&self) -> &[&str] {
+        (**self).info_string_options()
+    }
+
+    fn settle<'a>(&self, context: &RenderContext, rendered: RenderResult<'a>) -> RenderResult<'a> {
+        (**self).settle(context, rendered)
+    }
+
+    fn spilled(&self) -> Vec<PathBuf> {
+        (**self).spilled()
+    }
+}
+
+/// Options for piping a graph through Graphviz's `unflatten` tool before laying it out
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Unflatten {
+    /// `-l`: stagger the length of leaf edges between 1 and this value
+    pub leaf_stagger: Option<u32>,
+    /// `-f`: also stagger fanout edges, only used together with `leaf_stagger`
+    pub fanout: bool,
+}
+
+impl Unflatten {
+    pub(crate) fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(leaf_stagger) = self.leaf_stagger {
+            args.push(format!("-l{}", leaf_stagger));
+        }
+        if self.fanout {
+            args.push("-f".into());
+        }
+
+        args
+    }
+}
+
+/// The numeric components of a graphviz version, e.g. `2.43.0`
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GraphvizVersion(Vec<u32>);
+
+impl GraphvizVersion {
+    /// Find the version in either a plain `2.43.0` or in `dot -V` output like
+    /// `dot - graphviz version 2.43.0 (0)`
+    pub fn parse(version: &str) -> Option<GraphvizVersion> {
+        lazy_static! {
+            static ref VERSION_RE: Regex = Regex::new(r"(\d+(?:\.\d+)*)").unwrap();
+        }
+
+        let version = match version.find("version") {
+            Some(index) => &version[index..],
+            None => version,
+        };
+        let mut components: Vec<u32> = VERSION_RE
+            .find(version)?
+            .as_str()
+            .split('.')
+            .map(str::parse)
+            .collect::<std::result::Result<_, _>>()
+            .ok()?;
+
+        // 2.40 and 2.40.0 are the same release
+        while components.len() > 1 && components.last() == Some(&0) {
+            components.pop();
+        }
+
+        Some(GraphvizVersion(components))
+    }
+}
+
+impl fmt::Display for GraphvizVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let components: Vec<String> = self.0.iter().map(u32::to_string).collect();
+        write!(f, "{}", components.join("."))
+    }
+}
+
+/// A user supplied command that receives DOT on stdin and writes DOT to stdout, e.g. `gvpr`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+    pub command: String,
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl Filter {
+    pub fn parse(command: &str) -> Result<Filter> {
+        let mut words = shlex::split(command)
+            .ok_or_else(|| Error::msg(format!("Couldn't parse the filter `{}`", command)))?
+            .into_iter();
+        let program = words
+            .next()
+            .ok_or_else(|| Error::msg("Filters can't be empty commands"))?;
+
+        Ok(Filter {
+            command: command.into(),
+            program,
+            args: words.collect(),
+        })
+    }
+}
+
+/// Rendered before any graph of the book when there are enough of them, see `warm-up`
+const WARM_UP_GRAPH: &str = "digraph{}";
+
+/// Whether graphviz has been warmed up in this build
+#[derive(Debug)]
+pub struct WarmUp(Once);
+
+impl Default for WarmUp {
+    fn default() -> WarmUp {
+        WarmUp(Once::new())
+    }
+}
+
+impl PartialEq for WarmUp {
+    fn eq(&self, other: &WarmUp) -> bool {
+        self.0.is_completed() == other.0.is_completed()
+    }
+}
+
+/// How we call out to graphviz, including any stages the code is piped through before `dot`
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphvizCommand {
+    /// the resolved `dot` binary, see [`find_dot`]
+    pub dot: PathBuf,
+    /// what `dot -V` reported, so images from another graphviz aren't taken from the cache
+    pub version: Option<GraphvizVersion>,
+    /// graphs are drawn in rust instead of with `dot` when it's `Backend::Rust`
+    pub backend: Backend,
+    /// run instead of `dot`, getting the code on stdin and printing the image or writing it to
+    /// `{output}`
+    pub render_command: Option<Vec<String>>,
+    /// `dot` or the render command is killed when a graph takes longer than this
+    pub timeout: Option<Duration>,
+    /// run on every image file we write, with `{file}` replaced by its path
+    pub post_process: Option<Vec<String>>,
+    pub filters: Vec<Filter>,
+    /// where filters and the render command are run from, so their relative paths resolve against the book root
+    pub working_dir: PathBuf,
+    /// shared between the chapters' renderers, so graphviz is only warmed up once per build
+    pub warm_up: Option<Arc<WarmUp>>,
+    /// shared by every chapter's renderer so we can report on it at the end
+    pub cache: Option<Arc<Cache>>,
+    /// leave image files alone when their recorded hash shows they were rendered from the same
+    /// code and options
+    pub skip_unchanged: bool,
+}
+
+impl Default for GraphvizCommand {
+    fn default() -> GraphvizCommand {
+        GraphvizCommand {
+            dot: PathBuf::from(DEFAULT_DOT),
+            version: None,
+            backend: Backend::Dot,
+            render_command: None,
+            timeout: None,
+            post_process: None,
+            filters: vec![],
+            working_dir: PathBuf::from("."),
+            warm_up: None,
+            cache: None,
+            skip_unchanged: false,
+        }
+    }
+}
+
+impl GraphvizCommand {
+    /// Run `dot -V` to check we can actually call graphviz and find out which version it is
+    pub fn version(&self) -> Result<GraphvizVersion> {
+        let output = Command::new(&self.dot)
+            .arg("-V")
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| {
+                Error::msg(format!(
+                    "Couldn't run graphviz '{}': {}. {}",
+                    self.dot.display(),
+                    e,
+                    INSTALL_HINT
+                ))
+            })?;
+
+        // dot prints its version to stderr
+        let reported = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            return Err(Error::msg(format!(
+                "graphviz '{}' failed to report its version ({}): {}. {}",
+                self.dot.display(),
+                output.status,
+                reported.trim(),
+                INSTALL_HINT
+            )));
+        }
+
+        GraphvizVersion::parse(&reported).ok_or_else(|| {
+            Error::msg(format!(
+                "Couldn't understand the version reported by graphviz '{}': {}",
+                self.dot.display(),
+                reported.trim()
+            ))
+        })
+    }
+
+    /// Render a trivial graph before the first real one, if we're asked to, so graphviz has loaded
+    /// its libraries and filled its font cache by then
+    fn warm_up(&self) {
+        if let Some(ref warm_up) = self.warm_up {
+            warm_up.0.call_once(|| {
+                // a broken graphviz is reported by the graph we're about to render
+                let _ = pipe_through(
+                    Command::new(&self.dot).arg("-Tsvg"),
+                    WARM_UP_GRAPH,
+                    "graphviz",
+                    "",
+                );
+            });
+        }
+    }
+
+    /// A hash of everything that goes into rendering `code` with `args`
+    fn render_key(&self, args: &[&str], code: &str) -> String {
+        let mut options = args.to_vec();
+        // the same graph looks different drawn in rust
+        if self.backend == Backend::Rust {
+            options.push("backend=rust");
+        }
+        // layouts change between graphviz releases
+        let version = self
+            .version
+            .as_ref()
+            .map(|version| format!("graphviz={}", version));
+        if let Some(ref version) = version {
+            options.push(version);
+        }
+        let render_command = self
+            .render_command
+            .as_ref()
+            .map(|command| format!("render-command={}", command.join(" ")));
+        if let Some(ref render_command) = render_command {
+            options.push(render_command);
+        }
+
+        Cache::key(&options, code)
+    }
+
+    /// The key of an image file, which also depends on what `post-process` makes of the output
+    fn file_key(&self, args: &[&str], code: &str) -> String {
+        match self.post_process {
+            Some(ref post_process) => {
+                let post_process = format!("post-process={}", post_process.join(" "));
+                let mut options = args.to_vec();
+                options.push(&post_process);
+                self.render_key(&options, code)
+            }
+            None => self.render_key(args, code),
+        }
+    }
+
+    /// What graphviz outputs for `code`, taken from the cache if it's been rendered before
+    fn render(&self, graph_name: &str, args: &[&str], code: &str) -> Result<Vec<u8>> {
+        let key = self.cache.as_ref().map(|_| self.render_key(args, code));
+        if let (Some(cache), Some(key)) = (&self.cache, &key) {
+            if let Some(output) = cache.get(key) {
+                // a broken entry is rendered again, which replaces it
+                match check_output(graph_name, args, &output) {
+                    Ok(()) => {
+                        debug!("Took {} from the cache", describe_graph(graph_name));
+                        return Ok(output);
+                    }
+                    Err(e) => warn!("{}, rendering it again", e),
+                }
+            }
+        }
+
+        let output = match (self.backend, &self.render_command) {
+            (Backend::Rust, _) => native::render(graph_name, code)?,
+            (_, Some(render_command)) => {
+                self.call_render_command(graph_name, render_command, args, code)?
+            }
+            (_, None) => {
+                let output = self.call_graphviz(graph_name, args, code)?;
+                check_graphviz_output(graph_name, &output)?;
+                output.stdout
+            }
+        };
+        check_output(graph_name, args, &output)?;
+
+        if let (Some(cache), Some(key)) = (&self.cache, &key) {
+            cache.put(key, &output);
+        }
+
+        Ok(output)
+    }
+
+    /// A block's image in its format, kept in memory for renderers that put it into the chapter
+    /// or only check it. `args` come after the format, e.g. to scale the graph down
+    fn render_bytes(&self, block: &GraphvizBlock, args: &[&str]) -> Result<Vec<u8>> {
+        let code = self.layout_input(block)?;
+        let format_args = format_args(block);
+        let layout_args = layout_args(block, &code);
+        let mut render_args: Vec<&str> = format_args.iter().map(String::as_str).collect();
+        render_args.extend(layout_args.iter().map(String::as_str));
+        render_args.extend_from_slice(args);
+
+        self.render(&block.graph_name, &render_args, &code)
+    }
+
+    /// Render a block to `output_path` in its image format, unless it's already there from an
+    /// earlier build
+    fn render_to_file(
+        &self,
+        block: &GraphvizBlock,
+        output_path: &Path,
+        args: &[&str],
+        code: &str,
+    ) -> Result<()> {
+        create_parent_dir(output_path)?;
+        let format_args = format_args(block);
+        let layout_args = layout_args(block, code);
+        let mut args = args.to_vec();
+        args.extend(format_args.iter().map(String::as_str));
+        args.extend(layout_args.iter().map(String::as_str));
+
+        let key = self.file_key(&args, code);
+        let is_svg = block.format == SVG;
+        if output_path.exists() && !is_ours(output_path, is_svg) {
+            return Err(Error::msg(format!(
+                "Rendering {} would overwrite {}, which wasn't generated from a graph. Give the \
+                 block another `filename`, or remove the file if it's an old copy of the graph",
+                describe_graph(&block.graph_name),
+                output_path.display()
+            )));
+        }
+        // files named with `filename` are only told apart from hand made ones by their hash
+        let record_hash = self.skip_unchanged || !is_generated_name(output_path);
+        if self.skip_unchanged && recorded_hash(output_path, is_svg).as_deref() == Some(&key) {
+            debug!(
+                "{} is unchanged in {}",
+                describe_graph(&block.graph_name),
+                output_path.display()
+            );
+            return Ok(());
+        }
+
+        let mut output = match self.post_process {
+            // what the hook made of the output is cached under its own key, once the hook
+            // succeeded, so graphviz's output is never mistaken for it and the other way round
+            Some(ref post_process) => match self.cache.as_ref().and_then(|cache| cache.get(&key)) {
+                Some(output) if check_output(&block.graph_name, &args, &output).is_ok() => {
+                    debug!("Took {} from the cache", describe_graph(&block.graph_name));
+                    output
+                }
+                _ => {
+                    let output = self.render(&block.graph_name, &args, code)?;
+                    let output = self.post_process(block, post_process, output_path, &output)?;
+                    if let Some(ref cache) = self.cache {
+                        cache.put(&key, &output);
+                    }
+                    output
+                }
+            },
+            None => self.render(&block.graph_name, &args, code)?,
+        };
+        if record_hash && is_svg {
+            output.extend_from_slice(
+                format!("{}{}{}\n", HASH_COMMENT_START, key, HASH_COMMENT_END).as_bytes(),
+            );
+        } else if record_hash {
+            write_if_changed(&hash_path(output_path), key.as_bytes())?;
+        }
+        let written = write_if_changed(output_path, &output).and_then(|_| {
+            match fs::metadata(output_path)?.len() {
+                0 => Err(io::Error::other("the file is empty")),
+                _ => Ok(()),
+            }
+        });
+        if let Err(e) = written {
+            // a broken image would only show up as a blank one in the book
+            let _ = fs::remove_file(output_path);
+            return Err(Error::msg(format!(
+                "Couldn't write {} to {}: {}",
+                describe_graph(&block.graph_name),
+                output_path.display(),
+                e
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Run the `post-process` hook on a copy of `output` next to `output_path`, returning what it
+    /// made of it. The hash is recorded afterwards, so hooks dropping comments don't lose it
+    fn post_process(
+        &self,
+        block: &GraphvizBlock,
+        post_process: &[String],
+        output_path: &Path,
+        output: &[u8],
+    ) -> Result<Vec<u8>> {
+        // keep the extension, tools like optipng go by it
+        let path =
+            output_path.with_extension(format!("{}.post-process.{}", process::id(), block.format));
+        let file = path.to_string_lossy();
+        let mut words = post_process
+            .iter()
+            .map(|arg| arg.replace(FILE_PLACEHOLDER, &file));
+        let program = words
+            .next()
+            .expect("post-process is validated not to be empty");
+
+        let processed = fs::write(&path, output)
+            .map_err(Error::from)
+            .and_then(|_| {
+                spawn(
+                    Command::new(&program)
+                        .args(words)
+                        .current_dir(&self.working_dir)
+                        .stdin(Stdio::null())
+                        .stdout(Stdio::piped())
+                        .stderr(Stdio::piped()),
+                )
+                .and_then(|child| child.wait_with_output())
+                .map_err(|e| {
+                    Error::msg(format!(
+                        "Couldn't run the post-process '{}': {}",
+                        program, e
+                    ))
+                })
+            })
+            .and_then(|hook| {
+                if !hook.status.success() {
+                    return Err(Error::msg(format!(
+                        "post-process failed for {} ({}):\n{}",
+                        output_path.display(),
+                        hook.status,
+                        String::from_utf8_lossy(&hook.stderr).trim_end()
+                    )));
+                }
+                match fs::read(&path)? {
+                    processed if processed.iter().all(u8::is_ascii_whitespace) => Err(Error::msg(
+                        format!("post-process left {} empty", output_path.display()),
+                    )),
+                    processed => Ok(processed),
+                }
+            });
+        let _ = fs::remove_file(&path);
+
+        processed
+    }
+
+    fn call_graphviz(&self, graph_name: &str, args: &[&str], code: &str) -> Result<Output> {
+        self.warm_up();
+        let child = spawn(
+            Command::new(&self.dot)
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )
+        .map_err(|e| {
+            Error::msg(format!(
+                "Couldn't run graphviz '{}' for {}: {}",
+                self.dot.display(),
+                describe_graph(graph_name),
+                e
+            ))
+        })?;
+
+        feed(child, code, self.timeout).map_err(|e| {
+            Error::msg(format!(
+                "graphviz '{}' failed for {}: {}",
+                self.dot.display(),
+                describe_graph(graph_name),
+                e
+            ))
+        })
+    }
+
+    /// Run the configured `render-command` instead of `dot`. It gets the code on stdin and what it
+    /// prints is the image, unless it's told to write it to `{output}`
+    fn call_render_command(
+        &self,
+        graph_name: &str,
+        render_command: &[String],
+        args: &[&str],
+        code: &str,
+    ) -> Result<Vec<u8>> {
+        static OUTPUTS: AtomicUsize = AtomicUsize::new(0);
+
+        let format = args
+            .iter()
+            .find_map(|arg| arg.strip_prefix("-T"))
+            .unwrap_or(SVG);
+        let output_path = render_command
+            .iter()
+            .any(|arg| arg.contains(OUTPUT_PLACEHOLDER))
+            .then(|| {
+                env::temp_dir().join(format!(
+                    "mdbook-graphviz.{}.{}.{}",
+                    process::id(),
+                    OUTPUTS.fetch_add(1, Ordering::SeqCst),
+                    format
+                ))
+            });
+        let output_arg = output_path
+            .as_ref()
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let words = render_command.iter().map(|arg| {
+            arg.replace(FORMAT_PLACEHOLDER, format)
+                .replace(OUTPUT_PLACEHOLDER, &output_arg)
+        });
+        let mut words = words.collect::<Vec<_>>().into_iter();
+        let program = words
+            .next()
+            .expect("render-command is validated not to be empty");
+
+        let rendered = spawn(
+            Command::new(&program)
+                .args(words)
+                .current_dir(&self.working_dir)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )
+        .map_err(|e| {
+            Error::msg(format!(
+                "Couldn't run the render-command '{}' for {}: {}",
+                program,
+                describe_graph(graph_name),
+                e
+            ))
+        })
+        .and_then(|child| {
+            feed(child, code, self.timeout).map_err(|e| {
+                Error::msg(format!(
+                    "The render-command '{}' failed for {}: {}",
+                    program,
+                    describe_graph(graph_name),
+                    e
+                ))
+            })
+        })
+        .and_then(|output| {
+            check_graphviz_output(graph_name, &output)?;
+            match output_path {
+                Some(ref output_path) => fs::read(output_path).map_err(|e| {
+                    Error::msg(format!(
+                        "The render-command didn't write {} to {}: {}",
+                        describe_graph(graph_name),
+                        output_path.display(),
+                        e
+                    ))
+                }),
+                None => Ok(output.stdout),
+            }
+        });
+        if let Some(ref output_path) = output_path {
+            let _ = fs::remove_file(output_path);
+        }
+
+        rendered
+    }
+
+    /// The code we should hand to `dot`, after running any configured pre-passes
+    fn layout_input<'a>(&self, block: &'a GraphvizBlock) -> Result<Cow<'a, str>> {
+        let mut code = Cow::from(block.code.as_str());
+
+        for filter in &self.filters {
+            code = pipe_through(
+                Command::new(&filter.program)
+                    .args(&filter.args)
+                    .current_dir(&self.working_dir),
+                &code,
+                &format!("the filter `{}`", filter.command),
+                &block.graph_name,
+            )?
+            .into();
+        }
+
+        if let Some(ref unflatten) = block.unflatten {
+            code = pipe_through(
+                Command::new("unflatten").args(unflatten.args()),
+                &code,
+                "Graphviz's 'unflatten' (enabled by the unflatten option)",
+                &block.graph_name,
+            )?
+            .into();
+        }
+
+        Ok(code)
+    }
+}
+
+/// Inlines svgs into the chapter. A renderer is used for a single chapter so that
+/// `max_inline_total_bytes` bounds how much svg each chapter holds in memory
+pub struct CLIGraphviz {
+    command: GraphvizCommand,
+    max_inline_total_bytes: Option<usize>,
+    inlined_bytes: AtomicUsize,
+    /// the files svgs past the budget were written to
+    spilled: Mutex<Vec<PathBuf>>,
+}
+
+impl CLIGraphviz {
+    pub fn new(command: GraphvizCommand, max_inline_total_bytes: Option<usize>) -> CLIGraphviz {
+        CLIGraphviz {
+            command,
+            max_inline_total_bytes,
+            inlined_bytes: AtomicUsize::new(0),
+            spilled: Mutex::new(vec![]),
+        }
+    }
+
+    /// Whether `render_graphviz` put the block's svg into the chapter
+    fn inlines(block: &GraphvizBlock) -> bool {
+        block.thumbnail.is_none() && block.format == SVG
+    }
+
+    /// Inline the svg if it still fits in our budget after the blocks before it, otherwise write
+    /// it to a file the way `CLIGraphvizToFile` does
+    fn embed_or_spill<'a>(
+        &self,
+        context: &RenderContext,
+        rendered: Rendered<'a>,
+    ) -> RenderResult<'a> {
+        let block = context.block;
+        let size: usize = rendered
+            .events
+            .iter()
+            .map(|event| match event {
+                Event::Html(html) => html.len(),
+                _ => 0,
+            })
+            .sum();
+        // only counted if it still fits
+        let fits = self
+            .inlined_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |inlined_bytes| {
+                let inlined_bytes = inlined_bytes + size;
+                match self.max_inline_total_bytes {
+                    Some(max_bytes) if inlined_bytes > max_bytes => None,
+                    _ => Some(inlined_bytes),
+                }
+            })
+            .is_ok();
+
+        match self.max_inline_total_bytes {
+            Some(max_bytes) if !fits => {
+                warn!(
+                    "Chapter '{}' exceeds max-inline-total-bytes ({} bytes), writing {} to {} instead of inlining it",
+                    block.chapter_name,
+                    max_bytes,
+                    describe_graph(&block.graph_name),
+                    context.output_path.display()
+                );
+                // rendered again so the file is checked and hashed like any other image, the
+                // cache makes that cheap
+                let spilled = render_image_file(&self.command, context)?;
+                self.spilled
+                    .lock()
+                    .unwrap()
+                    .push(context.output_path.clone());
+
+                Ok(spilled)
+            }
+            _ => Ok(rendered),
+        }
+    }
+}
+
+impl GraphvizRenderer for CLIGraphviz {
+    fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+        let block = context.block;
+        // thumbnails link to the full size image, so both are files
+        if let Some(width) = block.thumbnail {
+            return render_thumbnail(&self.command, context, width);
+        }
+        // images are only inlined as svgs, a block in another `format` gets a file
+        if block.format != SVG {
+            return render_image_file(&self.command, context);
+        }
+
+        let output = self.command.render_bytes(block, &[])?;
+        let graph_svg = String::from_utf8(output).map_err(Error::from)?;
+
+        Ok(inline_events(graph_svg).into())
+    }
+
+    /// Whether an svg still fits depends on the ones before it, not on which finished first
+    fn settle<'a>(&self, context: &RenderContext, rendered: RenderResult<'a>) -> RenderResult<'a> {
+        match rendered {
+            Ok(rendered) if CLIGraphviz::inlines(context.block) => {
+                self.embed_or_spill(context, rendered)
+            }
+            rendered => rendered,
+        }
+    }
+
+    fn spilled(&self) -> Vec<PathBuf> {
+        std::mem::take(&mut *self.spilled.lock().unwrap())
+    }
+}
+
+pub struct CLIGraphvizToFile {
+    command: GraphvizCommand,
+}
+
+impl CLIGraphvizToFile {
+    pub fn new(command: GraphvizCommand) -> CLIGraphvizToFile {
+        CLIGraphvizToFile { command }
+    }
+}
+
+impl GraphvizRenderer for CLIGraphvizToFile {
+    fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+        if let Some(width) = context.block.thumbnail {
+            return render_thumbnail(&self.command, context, width);
+        }
+
+        render_image_file(&self.command, context)
+    }
+}
+
+/// Write the block's image file and show it
+fn render_image_file<'a>(command: &GraphvizCommand, context: &RenderContext) -> RenderResult<'a> {
+    let block = context.block;
+    let code = command.layout_input(block)?;
+    keep_dot(block, &code)?;
+    command.render_to_file(block, &context.output_path, &[], &code)?;
+
+    Ok(image_events(block).into())
+}
+
+/// Puts images into the chapter as data urls, so nothing is written next to the chapters
+pub struct CLIGraphvizEmbedded {
+    command: GraphvizCommand,
+}
+
+impl CLIGraphvizEmbedded {
+    pub fn new(command: GraphvizCommand) -> CLIGraphvizEmbedded {
+        CLIGraphvizEmbedded { command }
+    }
+}
+
+impl GraphvizRenderer for CLIGraphvizEmbedded {
+    fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+        let block = context.block;
+        // only the thumbnail is shown, browsers won't open a data url as a link to the full size
+        let size = block.thumbnail.map(thumbnail_size);
+        let args: Vec<&str> = size.iter().map(String::as_str).collect();
+        let output = self.command.render_bytes(block, &args)?;
+
+        let data_url = format!(
+            "data:{};base64,{}",
+            media_type(&block.format),
+            BASE64.encode(output)
+        );
+        Ok(image_events_for(block, data_url, block.external_link()).into())
+    }
+}
+
+/// The media type of images in one of our formats, for data urls
+fn media_type(format: &str) -> &'static str {
+    match format {
+        "png" => "image/png",
+        "jpg" => "image/jpeg",
+        "gif" => "image/gif",
+        _ => "image/svg+xml",
+    }
+}
+
+/// Runs every graph through graphviz to find the broken ones, without writing any files
+pub struct CLIGraphvizCheck {
+    command: GraphvizCommand,
+}
+
+impl CLIGraphvizCheck {
+    pub fn new(command: GraphvizCommand) -> CLIGraphvizCheck {
+        CLIGraphvizCheck { command }
+    }
+}
+
+impl GraphvizRenderer for CLIGraphvizCheck {
+    fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+        // the output is thrown away, it's only rendered to see whether graphviz complains
+        self.command.render_bytes(context.block, &[])?;
+
+        Ok(vec![].into())
+    }
+}
+
+/// Leaves drawing to a script in the browser, the chapter gets the block's code in a
+/// `<pre class="graphviz-client">` for it
+pub struct ClientSide;
+
+impl GraphvizRenderer for ClientSide {
+    fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+        let block = context.block;
+        // a <pre> html block only ends at its closing tag, so blank lines in the code are fine
+        let html = format!(
+            "<pre class=\"graphviz-client\" title=\"{}\" aria-label=\"{}\">{}</pre>\n",
+            escape_html(&block.title()),
+            escape_html(&block.alt_text()),
+            escape_html(&block.code)
+        );
+
+        Ok(vec![
+            Event::Start(Tag::HtmlBlock),
+            Event::Html(html.into()),
+            Event::End(Tag::HtmlBlock),
+        ]
+        .into())
+    }
+}
+
+/// Writes the code graphviz would get to the block's `.dot` file without rendering it, linking to
+/// where the image would be so the chapter looks like it does after a real build
+pub struct DumpDot {
+    command: GraphvizCommand,
+}
+
+impl DumpDot {
+    pub fn new(command: GraphvizCommand) -> DumpDot {
+        DumpDot { command }
+    }
+}
+
+impl GraphvizRenderer for DumpDot {
+    fn render_graphviz<'a>(&self, context: &RenderContext) -> RenderResult<'a> {
+        let block = context.block;
+        write_dot(block, &self.command.layout_input(block)?)?;
+
+        Ok(image_events(block).into())
+    }
+}
+
+/// Write the full size image and a copy scaled down to `width` pixels, showing the copy
+fn render_thumbnail<'a>(
+    command: &GraphvizCommand,
+    context: &RenderContext,
+    width: u32,
+) -> RenderResult<'a> {
+    let block = context.block;
+    let code = command.layout_input(block)?;
+    keep_dot(block, &code)?;
+    command.render_to_file(block, &context.output_path, &[], &code)?;
+    command.render_to_file(
+        block,
+        &block.thumbnail_path(),
+        &[&thumbnail_size(width)],
+        &code,
+    )?;
+
+    Ok(image_events(block).into())
+}
+
+/// Write `code` next to the block's image with `keep-dot`, so it can be run through graphviz by hand
+fn keep_dot(block: &GraphvizBlock, code: &str) -> Result<()> {
+    if !block.keep_dot {
+        return Ok(());
+    }
+
+    write_dot(block, code)
+}
+
+/// Write `code` to the block's `.dot` file
+fn write_dot(block: &GraphvizBlock, code: &str) -> Result<()> {
+    let path = block.dot_path();
+    create_parent_dir(&path)?;
+    write_if_changed(&path, code.as_bytes()).map_err(|e| {
+        Error::msg(format!(
+            "Couldn't write the code of {} to {}: {}",
+            describe_graph(&block.graph_name),
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// Graphviz's argument scaling a graph down to fit `width` css pixels. Its sizes are in inches, and
+/// there are 96 css pixels to an inch. It only ever scales down, so small graphs keep their size
+fn thumbnail_size(width: u32) -> String {
+    format!("-Gsize={0},{0}", f64::from(width) / 96.0)
+}
+
+/// Events embedding the svg output of `dot` directly into the chapter
+pub fn inline_events<'a>(graph_svg: String) -> Vec<Event<'a>> {
+    // mirror what pulldown_cmark gives us for html blocks so reprocessing our output is stable
+    vec![
+        Event::Start(Tag::HtmlBlock),
+        Event::Html(format!("{}\n", format_output(graph_svg)).into()),
+        Event::End(Tag::HtmlBlock),
+    ]
+}
+
+/// Events linking to an image file written next to the chapter
+pub fn image_events<'a>(block: &GraphvizBlock) -> Vec<Event<'a>> {
+    image_events_for(block, block.shown_image_link(), block.link_target())
+}
+
+/// Events showing the image at `file_name`, which is a link to `link` if there is one
+fn image_events_for<'a>(
+    block: &GraphvizBlock,
+    file_name: String,
+    link: Option<String>,
+) -> Vec<Event<'a>> {
+    let (title, alt) = (block.title(), block.alt_text());
+
+    // markdown images can't be sized, so those become html
+    if block.width.is_some() || block.height.is_some() {
+        let style: Vec<String> = [("width", &block.width), ("height", &block.height)]
+            .iter()
+            .filter_map(|(property, value)| {
+                value
+                    .as_ref()
+                    .map(|value| format!("{}:{}", property, value))
+            })
+            .collect();
+        let title = if title.is_empty() {
+            String::new()
+        } else {
+            format!(" title=\"{}\"", escape_html(&title))
+        };
+        // mdbook points `src` and `href` of these tags at the chapter's directory on the print
+        // page, as long as they're written like this
+        let mut img = format!(
+            "<img src=\"{}\" alt=\"{}\"{} style=\"{}\">",
+            escape_html(&file_name),
+            escape_html(&alt),
+            title,
+            style.join(";")
+        );
+        if let Some(link) = link {
+            img = format!("<a href=\"{}\">{}</a>", escape_html(&link), img);
+        }
+
+        return vec![
+            Event::Start(Tag::HtmlBlock),
+            Event::Html(format!("{}\n", img).into()),
+            Event::End(Tag::HtmlBlock),
+        ];
+    }
+
+    // titles are written in double quotes as they are
+    let title = title.replace('\\', "\\\\").replace('"', "\\\"");
+    let image_tag = Tag::Image(LinkType::Inline, file_name.into(), title.into());
+    let link_tag = link.map(|link| Tag::Link(LinkType::Inline, link.into(), "".into()));
+
+    let mut events = vec![];
+    if let Some(ref link_tag) = link_tag {
+        events.push(Event::Start(link_tag.clone()));
+    }
+    events.push(Event::Start(image_tag.clone()));
+    if !alt.is_empty() {
+        events.push(Event::Text(escape_markdown(&alt).into()));
+    }
+    events.push(Event::End(image_tag));
+    if let Some(link_tag) = link_tag {
+        events.push(Event::End(link_tag));
+    }
+    events.push(Event::Text("\n\n".into()));
+
+    events
+}
+
+/// Text events are written as is, so keep text from being read as markdown
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if let '\\' | '[' | ']' | '*' | '_' | '`' = ch {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+
+    escaped
+}
+
+/// Start `command`, waiting a little and trying again while the system is out of processes or
+/// open files. Anything else, like a missing binary, fails straight away
+fn spawn(command: &mut Command) -> io::Result<Child> {
+    let mut attempt = 1;
+    loop {
+        match command.spawn() {
+            Err(e) if is_transient(&e) && attempt < SPAWN_ATTEMPTS => {
+                debug!(
+                    "Couldn't start {}, trying again: {}",
+                    command.get_program().to_string_lossy(),
+                    e
+                );
+                // every render has its own thread, so sleeping doesn't hold up the others
+                thread::sleep(Duration::from_millis(50 << attempt));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Spawn failures that go away once other renders have finished
+fn is_transient(error: &io::Error) -> bool {
+    // EAGAIN when out of processes, ENFILE and EMFILE when out of file descriptors
+    error.kind() == ErrorKind::WouldBlock
+        || (cfg!(unix) && matches!(error.raw_os_error(), Some(23) | Some(24)))
+}
+
+/// Write `input` to a child's stdin while collecting what it prints, then wait for it to exit. A
+/// child still running after `timeout` is killed
+fn feed(mut child: Child, input: &str, timeout: Option<Duration>) -> io::Result<Output> {
+    // write from another thread so a process streaming its output can't fill the stdout pipe
+    // while we're still blocked writing its input
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = input.to_string();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+
+    let output = match timeout {
+        Some(timeout) => wait_with_timeout(child, timeout)?,
+        None => child.wait_with_output()?,
+    };
+    // a process is allowed to exit without reading all of its input, its exit status tells us
+    // whether that's a problem
+    let _ = writer.join();
+
+    Ok(output)
+}
+
+/// Like `Child::wait_with_output`, killing the child once it's run for longer than `timeout`
+fn wait_with_timeout(mut child: Child, timeout: Duration) -> io::Result<Output> {
+    // read from other threads, a child blocked on a full pipe would never exit
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait()? {
+            Some(status) => break status,
+            None if started.elapsed() >= timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "it was killed after running longer than render-timeout ({}s)",
+                        timeout.as_secs()
+                    ),
+                ));
+            }
+            None => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    let join = |reader: thread::JoinHandle<io::Result<Vec<u8>>>| {
+        reader
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("reading its output panicked")))
+    };
+    Ok(Output {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
+
+/// Everything a child prints to one of its pipes, read on another thread
+fn read_in_background<R: Read + Send + 'static>(
+    pipe: Option<R>,
+) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buf)?;
+        }
+        Ok(buf)
+    })
+}
+
+/// Run a single stage of our pipeline, feeding it `input` and returning what it printed
+fn pipe_through(
+    command: &mut Command,
+    input: &str,
+    stage_name: &str,
+    graph_name: &str,
+) -> Result<String> {
+    let child = spawn(
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )
+    .map_err(|e| {
+        Error::msg(format!(
+            "Couldn't run {} for {}: {}",
+            stage_name,
+            describe_graph(graph_name),
+            e
+        ))
+    })?;
+
+    let output = feed(child, input, None)?;
+
+    if output.status.success() {
+        Ok(String::from_utf8(output.stdout)?)
+    } else {
+        Err(Error::msg(format!(
+            "{} failed for {} ({}):\n{}",
+            stage_name,
+            describe_graph(graph_name),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )))
+    }
+}
+
+/// Graphviz ran but rejected a graph. Keeps what it told us, so blocks which are expected to
+/// fail can show it in the book
+#[derive(Debug)]
+pub struct RenderError {
+    pub graph_name: String,
+    pub status: ExitStatus,
+    pub stderr: String,
+}
+
+impl RenderError {
+    pub fn failure(&self) -> Failure {
+        Failure::of(self.status)
+    }
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.failure() {
+            Failure::Exit(_) => write!(
+                f,
+                "Error response from Graphviz for {} ({})",
+                describe_graph(&self.graph_name),
+                self.status
+            )?,
+            Failure::Signal(signal) => write!(
+                f,
+                "Graphviz was killed by signal {}{} while rendering {}. There's likely nothing \
+                 wrong with the graph, it may have run out of memory or crashed on its size, \
+                 try splitting it up",
+                signal,
+                signal_name(signal)
+                    .map(|name| format!(" ({})", name))
+                    .unwrap_or_default(),
+                describe_graph(&self.graph_name)
+            )?,
+        }
+        if !self.stderr.is_empty() {
+            write!(f, ":\n{}", self.stderr)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+/// How graphviz stopped when it didn't succeed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// it exited with this code, usually because it rejected the graph
+    Exit(i32),
+    /// it was killed by this signal, e.g. by the OOM killer or when it crashed
+    Signal(i32),
+}
+
+impl Failure {
+    pub fn of(status: ExitStatus) -> Failure {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+
+            if let Some(signal) = status.signal() {
+                return Failure::Signal(signal);
+            }
+        }
+
+        Failure::Exit(status.code().unwrap_or(-1))
+    }
+}
+
+/// The name of the signals graphviz usually dies from
+fn signal_name(signal: i32) -> Option<&'static str> {
+    match signal {
+        4 => Some("SIGILL"),
+        6 => Some("SIGABRT"),
+        7 => Some("SIGBUS"),
+        8 => Some("SIGFPE"),
+        9 => Some("SIGKILL"),
+        11 => Some("SIGSEGV"),
+        15 => Some("SIGTERM"),
+        _ => None,
+    }
+}
+
+/// Fail with a `RenderError` if `dot` didn't succeed, otherwise pass on any warnings it printed
+fn check_graphviz_output(graph_name: &str, output: &Output) -> Result<()> {
+    let stderr = String::from_utf8_lossy(&output.stderr)
+        .trim_end()
+        .to_string();
+
+    if output.status.success() {
+        if !stderr.is_empty() {
+            warn!("{}", stderr);
+        }
+
+        Ok(())
+    } else {
+        Err(RenderError {
+            graph_name: graph_name.into(),
+            status: output.status,
+            stderr,
+        }
+        .into())
+    }
+}
+
+/// Fail on output that can't be a whole image, graphviz can exit successfully with a truncated
+/// file when the disk fills up or it's killed while writing. This only looks at both ends, there's
+/// no need to parse the svg
+fn check_output(graph_name: &str, args: &[&str], output: &[u8]) -> Result<()> {
+    let problem = if output.iter().all(u8::is_ascii_whitespace) {
+        "it's empty"
+    } else if args.contains(&"-Tsvg") && !is_complete_svg(output) {
+        "it isn't a complete svg"
+    } else {
+        return Ok(());
+    };
+
+    Err(Error::msg(format!(
+        "Graphviz's output for {} is broken, {}",
+        describe_graph(graph_name),
+        problem
+    )))
+}
+
+fn is_complete_svg(output: &[u8]) -> bool {
+    let svg = String::from_utf8_lossy(output);
+    let svg = svg.trim();
+    let starts = svg.starts_with("<?xml") || svg.starts_with("<svg");
+    // a lone `<svg/>` is closed as well
+    let ends = svg.ends_with("</svg>")
+        || (svg.starts_with("<svg") && svg.ends_with("/>") && !svg[1..].contains('<'));
+
+    starts && ends
+}
+
+/// Image files can go to a directory that isn't there yet, e.g. a new chapter's under the
+/// output-dir
+fn create_parent_dir(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir).map_err(|e| {
+            Error::msg(format!(
+                "Couldn't create the directory {} for {}: {}",
+                dir.display(),
+                path.display(),
+                e
+            ))
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Leave files with the same content alone, `mdbook serve` watches `src` and would build again
+/// after every build otherwise
+fn write_if_changed(path: &Path, content: &[u8]) -> io::Result<()> {
+    match fs::read(path) {
+        Ok(ref existing) if existing.as_slice() == content => Ok(()),
+        _ => write_atomically(path, content),
+    }
+}
+
+/// Write next to `path` then rename, so a browser reloading during `mdbook serve` never gets a
+/// half written image. The rename only replaces the file in one step on the same file system
+fn write_atomically(path: &Path, content: &[u8]) -> io::Result<()> {
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut partial = path.as_os_str().to_os_string();
+    partial.push(format!(
+        ".{}.{}.partial",
+        process::id(),
+        WRITES.fetch_add(1, Ordering::SeqCst)
+    ));
+    let partial = PathBuf::from(partial);
+
+    let written = fs::write(&partial, content).and_then(|_| fs::rename(&partial, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+
+    written
+}
+
+/// The hash of what the image file at `output_path` was rendered from, if there's one
+fn recorded_hash(output_path: &Path, is_svg: bool) -> Option<String> {
+    if !output_path.is_file() {
+        return None;
+    }
+    if !is_svg {
+        return Some(
+            fs::read_to_string(hash_path(output_path))
+                .ok()?
+                .trim()
+                .into(),
+        );
+    }
+
+    // the comment we append, a hand edited svg won't end in one
+    let svg = fs::read_to_string(output_path).ok()?;
+    let trailer = svg.trim_end().strip_suffix(HASH_COMMENT_END)?;
+    let start = trailer.rfind(HASH_COMMENT_START)?;
+
+    Some(trailer[start + HASH_COMMENT_START.len()..].into())
+}
+
+/// Whether an image file is named the way we name them, rather than with `filename`
+fn is_generated_name(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().contains(GENERATED))
+}
+
+/// Whether an image file came from one of our earlier builds, rather than being made by hand
+fn is_ours(path: &Path, is_svg: bool) -> bool {
+    is_generated_name(path) || recorded_hash(path, is_svg).is_some()
+}
+
+/// Where we record the hash of what an image file was rendered from, e.g. `x.generated.svg.sha256`
+fn hash_path(output_path: &Path) -> PathBuf {
+    let mut path = output_path.as_os_str().to_os_string();
+    path.push(".");
+    path.push(HASH_EXTENSION);
+
+    PathBuf::from(path)
+}
+
+/// Arguments selecting and tuning the block's layout engine, `code` is what graphviz gets
+fn layout_args(block: &GraphvizBlock, code: &str) -> Vec<String> {
+    let engine = engine_for(block, code);
+
+    let mut args = vec![];
+    if let Some(ref engine) = engine {
+        args.push(format!("-K{}", engine));
+    }
+    if let (Some(seed), Some(engine)) = (block.seed, engine.as_deref()) {
+        // the start attribute is only used by force directed layouts
+        if SEEDED_ENGINES.contains(&engine) {
+            args.push(format!("-Gstart={}", seed));
+        }
+    }
+
+    args
+}
+
+/// The engine graphviz will lay the block out with. A `layout` attribute in the graph takes
+/// precedence over `-K`, so it wins over the block's engine
+fn engine_for(block: &GraphvizBlock, code: &str) -> Option<String> {
+    let layout = match layout_attribute(code) {
+        Some(layout) => layout,
+        None => return block.engine.clone(),
+    };
+
+    if !ENGINES.contains(&layout.as_str()) {
+        warn!(
+            "{} uses the layout '{}', which isn't one of {}",
+            describe_graph(&block.graph_name),
+            layout,
+            ENGINES.join(", ")
+        );
+    }
+    if let Some(conflict) = layout_conflict(block, &layout) {
+        warn!("{}", conflict);
+    }
+
+    Some(layout)
 }
 
-pub struct CLIGraphviz;
+/// Graphviz prefers the graph's own `layout=` over `-K`, which silently ignores an `engine` set
+/// in the info string or the config
+pub(crate) fn layout_conflict(block: &GraphvizBlock, layout: &str) -> Option<String> {
+    match block.engine {
+        Some(ref engine) if engine != layout => Some(format!(
+            "{} sets layout={} which overrides the engine '{}'",
+            describe_graph(&block.graph_name),
+            layout,
+            engine
+        )),
+        _ => None,
+    }
+}
 
-impl GraphvizRenderer for CLIGraphviz {
-    fn render_graphviz<'a>(GraphvizBlock { code, .. }: GraphvizBlock) -> Result<Vec<Event<'a>>> {
-        let output = call_graphviz(&["-Tsvg"], &code)?.wait_with_output()?;
-        if output.status.success() {
-            let graph_svg = String::from_utf8(output.stdout)?;
-
-            Ok(vec![
-                Event::Start(Tag::HtmlBlock),
-                Event::Text(format_output(graph_svg).into()),
-                Event::End(Tag::HtmlBlock),
-                Event::Text("\n\n".into()),
-            ])
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Error response from Graphviz",
-            )
-            .into())
-        }
+/// A tolerant look for a `layout=<engine>` attribute, e.g. `graph [layout=neato]` or `layout="fdp";`
+fn layout_attribute(code: &str) -> Option<String> {
+    lazy_static! {
+        static ref LAYOUT_RE: Regex =
+            Regex::new(r#"(?:^|[\s;,\[{])layout\s*=\s*"?([A-Za-z_]+)"?"#).unwrap();
     }
+
+    LAYOUT_RE
+        .captures(code)
+        .map(|captures| captures[1].to_string())
 }
 
-pub struct CLIGraphvizToFile;
+pub fn describe_graph(graph_name: &str) -> String {
+    if graph_name.is_empty() {
+        "an unnamed graph".into()
+    } else {
+        format!("graph '{}'", graph_name)
+    }
+}
 
-impl GraphvizRenderer for CLIGraphvizToFile {
-    fn render_graphviz<'a>(block: GraphvizBlock) -> Result<Vec<Event<'a>>> {
-        let file_name = block.file_name();
-        let output_path = block.output_path();
-        let GraphvizBlock {
-            graph_name, code, ..
-        } = block;
-
-        let output_path_str = output_path.to_str().ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::NotFound,
-                "Couldn't build output path",
-            )
-        })?;
+/// The image format, and the resolution for raster images
+fn format_args(block: &GraphvizBlock) -> Vec<String> {
+    let mut args = vec![format!("-T{}", block.format)];
+    if let Some(dpi) = block.dpi {
+        args.push(format!("-Gdpi={}", dpi));
+    }
 
-        if call_graphviz(&["-Tsvg", "-o", output_path_str], &code)?
-            .wait()?
-            .success()
-        {
-            let image_tag = Tag::Image(LinkType::Inline, file_name.into(), graph_name.into());
+    args
+}
 
-            Ok(vec![
-                Event::Start(image_tag.clone()),
-                Event::End(image_tag),
-                Event::Text("\n\n".into()),
-            ])
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Error response from Graphviz",
-            )
-            .into())
+/// Resolve which `dot` binary to use: the `dot-command` config, then the `GRAPHVIZ_DOT`
+/// environment variable, then `dot` from the `PATH`
+pub fn find_dot(configured: Option<&str>, env_dot: Option<OsString>) -> Result<PathBuf> {
+    find_dot_in(configured, env_dot, env::var_os("PATH"))
+}
+
+fn find_dot_in(
+    configured: Option<&str>,
+    env_dot: Option<OsString>,
+    search_path: Option<OsString>,
+) -> Result<PathBuf> {
+    let candidates = configured
+        .map(|command| ("dot-command", OsString::from(command)))
+        .into_iter()
+        .chain(env_dot.map(|command| ("GRAPHVIZ_DOT", command)))
+        .chain(Some(("PATH", OsString::from(DEFAULT_DOT))));
+
+    let mut tried = vec![];
+    for (source, candidate) in candidates {
+        if let Some(dot) = find_executable(Path::new(&candidate), search_path.as_ref()) {
+            return Ok(dot);
         }
+        tried.push(format!("{} ({})", Path::new(&candidate).display(), source));
     }
-}
 
-fn call_graphviz(args: &[&str], code: &str) -> Result<Child> {
-    let mut child = Command::new("dot")
-        .args(args)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()?;
+    Err(Error::msg(format!(
+        "Couldn't find Graphviz's 'dot', tried: {}. {}",
+        tried.join(", "),
+        INSTALL_HINT
+    )))
+}
 
-    if let Some(mut stdin) = child.stdin.take() {
-        stdin.write_all(code.as_bytes())?;
+fn find_executable(command: &Path, search_path: Option<&OsString>) -> Option<PathBuf> {
+    // anything that looks like a path is used as is, bare names are looked up on the PATH
+    if command.components().count() > 1 {
+        return if command.is_file() {
+            Some(command.to_path_buf())
+        } else {
+            None
+        };
     }
 
-    Ok(child)
+    search_path.and_then(|paths| {
+        env::split_paths(paths)
+            .flat_map(|dir| {
+                let mut candidates = vec![dir.join(command)];
+                if cfg!(windows) {
+                    candidates.push(dir.join(command).with_extension("exe"));
+                }
+                candidates
+            })
+            .find(|candidate| candidate.is_file())
+    })
 }
 
 fn format_output(output: String) -> String {
@@ -107,8 +1690,12 @@ fn format_output(output: String) -> String {
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
+    use std::time::SystemTime;
+
     use super::*;
+    use crate::filename_template::FilenameTemplate;
+    use crate::preprocessor::SourceLine;
 
     #[test]
     fn inline_events() {
@@ -118,17 +1705,1056 @@ mod test {
             graph_name: "Name".into(),
             code: code.into(),
             chapter_name: "".into(),
-            chapter_path: "".into(),
+            chapter_id: String::new(),
+            output_dir: "".into(),
+            link_dir: String::new(),
+            format: "svg".into(),
+            engine: None,
+            seed: None,
+            unflatten: None,
+            filename_template: FilenameTemplate::default(),
+            index_names: true,
+            stable_names: false,
+            duplicate: 0,
+            content_hash: String::new(),
             index: 0,
+            dpi: None,
+            sub_graph: None,
+            alt: None,
+            alt_template: None,
+            caption: None,
+            width: None,
+            height: None,
+            link: None,
+            file_stem: None,
+            expect_error: false,
+            thumbnail: None,
+            keep_dot: false,
+            source: SourceLine::default(),
+            options: BTreeMap::new(),
         };
 
-        let mut events = CLIGraphviz::render_graphviz(block).unwrap().into_iter();
+        let mut events = CLIGraphviz::new(GraphvizCommand::default(), None)
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap()
+            .events
+            .into_iter();
         assert_eq!(events.next(), Some(Event::Start(Tag::HtmlBlock)));
-        if let Some(Event::Text(_)) = events.next() {
+        if let Some(Event::Html(_)) = events.next() {
         } else {
             panic!("Unexpected next event")
         }
         assert_eq!(events.next(), Some(Event::End(Tag::HtmlBlock)));
         assert_eq!(events.next(), None);
     }
+
+    #[test]
+    fn unflatten_args() {
+        assert!(Unflatten::default().args().is_empty());
+
+        let unflatten = Unflatten {
+            leaf_stagger: Some(3),
+            fanout: true,
+        };
+        assert_eq!(unflatten.args(), vec!["-l3", "-f"]);
+    }
+
+    #[test]
+    fn parse_filters() {
+        assert_eq!(
+            Filter::parse("gvpr -c -f 'styles/my style.gvpr'").unwrap(),
+            Filter {
+                command: "gvpr -c -f 'styles/my style.gvpr'".into(),
+                program: "gvpr".into(),
+                args: vec!["-c".into(), "-f".into(), "styles/my style.gvpr".into()],
+            }
+        );
+
+        assert!(Filter::parse("").is_err());
+        assert!(Filter::parse("gvpr -f 'unclosed").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn filters_run_in_order() {
+        let command = GraphvizCommand {
+            filters: vec![
+                Filter::parse("sed s/x/y/").unwrap(),
+                Filter::parse("sed s/y/z/").unwrap(),
+            ],
+            ..GraphvizCommand::default()
+        };
+
+        let block = test_block("strict { x }");
+        let code = command.layout_input(&block).unwrap();
+
+        assert_eq!(code, "strict { z }");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failing_filters_report_stderr() {
+        let command = GraphvizCommand {
+            filters: vec![Filter::parse("sh -c 'echo broken style >&2; exit 3'").unwrap()],
+            ..GraphvizCommand::default()
+        };
+
+        let error = command
+            .layout_input(&test_block("digraph { a }"))
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("graph 'Name'"), "{}", error);
+        assert!(error.contains("broken style"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn render_command_replaces_dot() {
+        let command = GraphvizCommand {
+            dot: PathBuf::from("/nonexistent/dot"),
+            render_command: Some(vec![
+                "sh".into(),
+                "-c".into(),
+                "echo \"<svg><title>$0</title>$(wc -l)</svg>\"".into(),
+                "{format}".into(),
+            ]),
+            ..GraphvizCommand::default()
+        };
+
+        let output = command
+            .render_bytes(&test_block("digraph {\n a\n}\n"), &[])
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "<svg><title>svg</title>3</svg>\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn render_command_writes_to_output() {
+        let command = GraphvizCommand {
+            render_command: Some(vec![
+                "sh".into(),
+                "-c".into(),
+                "echo '<svg/>' > \"$0\"; echo ignored".into(),
+                "{output}".into(),
+            ]),
+            ..GraphvizCommand::default()
+        };
+
+        let output = command
+            .render_bytes(&test_block("digraph { a }"), &[])
+            .unwrap();
+        assert_eq!(output, b"<svg/>\n");
+
+        let failing = GraphvizCommand {
+            render_command: Some(vec![
+                "sh".into(),
+                "-c".into(),
+                "exit 0".into(),
+                "{output}".into(),
+            ]),
+            ..GraphvizCommand::default()
+        };
+        let error = failing
+            .render_bytes(&test_block("digraph { a }"), &[])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with("The render-command didn't write graph 'Name'"),
+            "{}",
+            error
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn slow_renders_are_killed() {
+        let dir = tempfile::tempdir().unwrap();
+        let block = test_block("digraph { a }");
+        let dot = GraphvizCommand {
+            dot: fake_command(dir.path(), "dot", "cat > /dev/null; echo '<svg/>'"),
+            timeout: Some(Duration::from_secs(1)),
+            ..GraphvizCommand::default()
+        };
+        assert_eq!(dot.render_bytes(&block, &[]).unwrap(), b"<svg/>\n");
+
+        let started = Instant::now();
+        let slow_dot = GraphvizCommand {
+            dot: fake_command(dir.path(), "slow-dot", "exec sleep 10"),
+            ..dot.clone()
+        };
+        let error = slow_dot.render_bytes(&block, &[]).unwrap_err().to_string();
+        assert!(
+            error.ends_with("killed after running longer than render-timeout (1s)"),
+            "{}",
+            error
+        );
+
+        let slow_command = GraphvizCommand {
+            render_command: Some(vec!["sleep".into(), "10".into()]),
+            ..dot
+        };
+        let error = slow_command
+            .render_bytes(&block, &[])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with("The render-command 'sleep' failed for graph 'Name': it was killed"),
+            "{}",
+            error
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn graphviz_errors_keep_stderr() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = GraphvizCommand {
+            dot: fake_command(
+                dir.path(),
+                "dot",
+                "cat > /dev/null; echo 'Error: syntax error in line 1' >&2; exit 1",
+            ),
+            ..GraphvizCommand::default()
+        };
+
+        let error = CLIGraphviz::new(command, None)
+            .render_graphviz(&RenderContext::new(&test_block("digraph {")))
+            .unwrap_err();
+        let error = match error {
+            RendererError::Rejected(error) => error,
+            other => panic!("Unexpected error {:?}", other),
+        };
+
+        assert_eq!(error.graph_name, "Name");
+        assert_eq!(error.status.code(), Some(1));
+        assert_eq!(error.failure(), Failure::Exit(1));
+        assert_eq!(error.stderr, "Error: syntax error in line 1");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn killed_graphviz() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let error = RenderError {
+            graph_name: "Huge".into(),
+            status: ExitStatus::from_raw(9),
+            stderr: String::new(),
+        };
+        assert_eq!(error.failure(), Failure::Signal(9));
+        assert_eq!(
+            error.to_string(),
+            "Graphviz was killed by signal 9 (SIGKILL) while rendering graph 'Huge'. There's \
+             likely nothing wrong with the graph, it may have run out of memory or crashed on its \
+             size, try splitting it up"
+        );
+
+        let error = RenderError {
+            stderr: "Error: trouble in init_rank".into(),
+            status: ExitStatus::from_raw(2 << 8),
+            ..error
+        };
+        assert_eq!(error.failure(), Failure::Exit(2));
+        assert_eq!(
+            error.to_string(),
+            "Error response from Graphviz for graph 'Huge' (exit status: 2):\nError: trouble in \
+             init_rank"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn thumbnails_are_scaled_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        // outputs its arguments
+        let command = GraphvizCommand {
+            dot: fake_command(
+                dir.path(),
+                "dot",
+                r#"cat > /dev/null; echo "<svg>$@</svg>""#,
+            ),
+            ..GraphvizCommand::default()
+        };
+        let block = GraphvizBlock {
+            output_dir: dir.path().into(),
+            thumbnail: Some(192),
+            ..test_block("digraph { a -> b }")
+        };
+
+        // thumbnails are written to files even when inlining
+        let events = CLIGraphviz::new(command, None)
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap()
+            .events;
+
+        let full_size = fs::read_to_string(block.output_path()).unwrap();
+        assert!(!full_size.contains("-Gsize"), "{}", full_size);
+        let thumbnail = fs::read_to_string(block.thumbnail_path()).unwrap();
+        assert!(thumbnail.contains("-Gsize=2,2"), "{}", thumbnail);
+
+        assert_eq!(
+            events[..2],
+            [
+                Event::Start(Tag::Link(
+                    LinkType::Inline,
+                    "name_0.generated.svg".into(),
+                    "".into()
+                )),
+                Event::Start(Tag::Image(
+                    LinkType::Inline,
+                    "name_0_thumb.generated.svg".into(),
+                    "Name".into()
+                )),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn embedded_images() {
+        let dir = tempfile::tempdir().unwrap();
+        // outputs its arguments
+        let command = GraphvizCommand {
+            dot: fake_command(
+                dir.path(),
+                "dot",
+                r#"cat > /dev/null; echo "<svg>$@</svg>""#,
+            ),
+            ..GraphvizCommand::default()
+        };
+        let block = GraphvizBlock {
+            output_dir: dir.path().into(),
+            link: Some("self".into()),
+            ..test_block("digraph { a -> b }")
+        };
+
+        let events = CLIGraphvizEmbedded::new(command.clone())
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap()
+            .events;
+        assert_eq!(
+            events[0],
+            Event::Start(Tag::Image(
+                LinkType::Inline,
+                format!(
+                    "data:image/svg+xml;base64,{}",
+                    BASE64.encode("<svg>-Tsvg</svg>\n")
+                )
+                .into(),
+                "Name".into()
+            ))
+        );
+
+        // a thumbnail is shown on its own, and in the block's format
+        let block = GraphvizBlock {
+            format: "png".into(),
+            thumbnail: Some(192),
+            link: None,
+            ..block
+        };
+        let events = CLIGraphvizEmbedded::new(command)
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap()
+            .events;
+        assert_eq!(
+            events[0],
+            Event::Start(Tag::Image(
+                LinkType::Inline,
+                format!(
+                    "data:image/png;base64,{}",
+                    BASE64.encode("<svg>-Tpng -Gsize=2,2</svg>\n")
+                )
+                .into(),
+                "Name".into()
+            ))
+        );
+
+        // nothing is written next to the chapter
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cached_graphs_skip_graphviz() {
+        let dir = tempfile::tempdir().unwrap();
+        let calls = dir.path().join("calls");
+        let command = GraphvizCommand {
+            dot: fake_command(
+                dir.path(),
+                "dot",
+                &format!(
+                    "cat > /dev/null; echo call >> '{}'; echo '<svg/>'",
+                    calls.display()
+                ),
+            ),
+            cache: Some(Arc::new(Cache::new(dir.path().join("cache")))),
+            ..GraphvizCommand::default()
+        };
+        let renderer = CLIGraphvizToFile::new(command.clone());
+        let block = GraphvizBlock {
+            output_dir: dir.path().into(),
+            ..test_block("digraph { a -> b }")
+        };
+
+        renderer
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap();
+        fs::remove_file(block.output_path()).unwrap();
+        renderer
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap();
+        assert_eq!(fs::read_to_string(block.output_path()).unwrap(), "<svg/>\n");
+        assert_eq!(fs::read_to_string(&calls).unwrap(), "call\n");
+
+        // a different graph isn't a hit
+        renderer
+            .render_graphviz(&RenderContext::new(&GraphvizBlock {
+                output_dir: dir.path().into(),
+                ..test_block("digraph { a -> c }")
+            }))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&calls).unwrap(), "call\ncall\n");
+
+        let summary = command.cache.unwrap().summary();
+        assert!(summary.contains("1 hits, 2 misses"), "{}", summary);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unchanged_files_skip_graphviz() {
+        let dir = tempfile::tempdir().unwrap();
+        let calls = dir.path().join("calls");
+        let command = GraphvizCommand {
+            dot: fake_command(
+                dir.path(),
+                "dot",
+                &format!(
+                    "cat > /dev/null; echo call >> '{}'; echo '<svg/>'",
+                    calls.display()
+                ),
+            ),
+            skip_unchanged: true,
+            ..GraphvizCommand::default()
+        };
+        let renderer = CLIGraphvizToFile::new(command.clone());
+        // other formats than svg have their hash in a file next to them
+        let block = GraphvizBlock {
+            output_dir: dir.path().into(),
+            format: "png".into(),
+            ..test_block("digraph { a -> b }")
+        };
+
+        renderer
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap();
+        renderer
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&calls).unwrap(), "call\n");
+        assert!(hash_path(&block.output_path()).is_file());
+
+        // changed code, or a deleted image, is rendered again
+        let changed = GraphvizBlock {
+            code: "digraph { a -> c }".into(),
+            ..block.clone()
+        };
+        renderer
+            .render_graphviz(&RenderContext::new(&changed))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&calls).unwrap(), "call\ncall\n");
+        fs::remove_file(block.output_path()).unwrap();
+        renderer
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&calls).unwrap(), "call\ncall\ncall\n");
+
+        // as are changed options
+        renderer
+            .render_graphviz(&RenderContext::new(&GraphvizBlock {
+                engine: Some("neato".into()),
+                ..block.clone()
+            }))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&calls).unwrap().lines().count(), 4);
+
+        // no-cache renders everything
+        let renderer = CLIGraphvizToFile::new(GraphvizCommand {
+            skip_unchanged: false,
+            ..command
+        });
+        renderer
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&calls).unwrap().lines().count(), 5);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn svgs_record_their_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let calls = dir.path().join("calls");
+        let renderer = CLIGraphvizToFile::new(GraphvizCommand {
+            dot: fake_command(
+                dir.path(),
+                "dot",
+                &format!(
+                    "cat > /dev/null; echo call >> '{}'; echo '<svg/>'",
+                    calls.display()
+                ),
+            ),
+            skip_unchanged: true,
+            ..GraphvizCommand::default()
+        });
+        let block = GraphvizBlock {
+            output_dir: dir.path().into(),
+            ..test_block("digraph { a -> b }")
+        };
+        let calls = || fs::read_to_string(&calls).unwrap().lines().count();
+
+        renderer
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap();
+        let svg = fs::read_to_string(block.output_path()).unwrap();
+        assert!(
+            svg.starts_with("<svg/>\n<!-- mdbook-graphviz: sha256="),
+            "{}",
+            svg
+        );
+        assert!(!hash_path(&block.output_path()).exists());
+
+        // the hash matches
+        renderer
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap();
+        assert_eq!(calls(), 1);
+
+        // it doesn't
+        let changed = GraphvizBlock {
+            code: "digraph { a -> c }".into(),
+            ..block.clone()
+        };
+        renderer
+            .render_graphviz(&RenderContext::new(&changed))
+            .unwrap();
+        assert_eq!(calls(), 2);
+        assert_ne!(fs::read_to_string(block.output_path()).unwrap(), svg);
+
+        // hand edited without the comment
+        fs::write(block.output_path(), "<svg>edited</svg>\n").unwrap();
+        renderer
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap();
+        assert_eq!(calls(), 3);
+        assert_eq!(fs::read_to_string(block.output_path()).unwrap(), svg);
+    }
+
+    /// dot printing a lot before it's read all of its input mustn't leave us both waiting on
+    /// each other's full pipes
+    #[cfg(unix)]
+    #[test]
+    fn large_output_before_reading_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let renderer = CLIGraphvizToFile::new(GraphvizCommand {
+            dot: fake_command(
+                dir.path(),
+                "dot",
+                "head -c 400000 /dev/zero | tr '\\0' x; cat > /dev/null",
+            ),
+            ..GraphvizCommand::default()
+        });
+        let block = GraphvizBlock {
+            output_dir: dir.path().into(),
+            format: "png".into(),
+            ..test_block(&format!("digraph {{ {} }}", "a -> b; ".repeat(50_000)))
+        };
+
+        renderer
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap();
+        assert_eq!(fs::metadata(block.output_path()).unwrap().len(), 400_000);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn broken_output_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(Cache::new(dir.path().join("cache")));
+        let truncated = GraphvizCommand {
+            dot: fake_command(
+                dir.path(),
+                "dot",
+                "cat > /dev/null; printf '<?xml version=\"1.0\"?>\\n<svg><g>'",
+            ),
+            cache: Some(cache.clone()),
+            ..GraphvizCommand::default()
+        };
+        let block = GraphvizBlock {
+            output_dir: dir.path().into(),
+            ..test_block("digraph { a -> b }")
+        };
+
+        let error = CLIGraphvizToFile::new(truncated.clone())
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Graphviz's output for graph 'Name' is broken, it isn't a complete svg"
+        );
+        assert!(!block.output_path().exists());
+
+        // a broken copy in the cache is rendered again
+        let key = truncated.render_key(&["-Tsvg"], &block.code);
+        cache.put(&key, b"");
+        let command = GraphvizCommand {
+            dot: fake_command(dir.path(), "dot", "cat > /dev/null; echo '<svg></svg>'"),
+            ..truncated
+        };
+        CLIGraphvizToFile::new(command)
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(block.output_path()).unwrap(),
+            "<svg></svg>\n"
+        );
+        assert_eq!(cache.get(&key), Some(b"<svg></svg>\n".to_vec()));
+
+        assert!(is_complete_svg(b"<svg/>\n"));
+        assert!(!is_complete_svg(b"<svg><g/>"));
+        assert!(!is_complete_svg(b"Error: something\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn creates_missing_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let block = GraphvizBlock {
+            output_dir: dir.path().join("generated/guide"),
+            format: "png".into(),
+            ..test_block("digraph { a -> b }")
+        };
+
+        CLIGraphvizToFile::new(GraphvizCommand {
+            dot: fake_command(dir.path(), "dot", "cat > /dev/null; echo png"),
+            ..GraphvizCommand::default()
+        })
+        .render_graphviz(&RenderContext::new(&block))
+        .unwrap();
+        assert_eq!(fs::read_to_string(block.output_path()).unwrap(), "png\n");
+
+        // a file where the directory should be
+        fs::write(dir.path().join("taken"), "").unwrap();
+        let error = create_parent_dir(&dir.path().join("taken/graph.generated.svg")).unwrap_err();
+        assert!(
+            error.to_string().starts_with(&format!(
+                "Couldn't create the directory {}",
+                dir.path().join("taken").display()
+            )),
+            "{}",
+            error
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hand_made_files_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let renderer = |skip_unchanged| {
+            CLIGraphvizToFile::new(GraphvizCommand {
+                dot: fake_command(dir.path(), "dot", "cat > /dev/null; echo '<svg/>'"),
+                skip_unchanged,
+                ..GraphvizCommand::default()
+            })
+        };
+        let block = GraphvizBlock {
+            output_dir: dir.path().into(),
+            file_stem: Some("architecture".into()),
+            ..test_block("digraph { a -> b }")
+        };
+        fs::write(block.output_path(), "<svg>drawn by hand</svg>").unwrap();
+
+        let error = renderer(true)
+            .render_graphviz(&RenderContext::new(&block))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Rendering graph 'Name' would overwrite {}, which wasn't generated from a graph. \
+                 Give the block another `filename`, or remove the file if it's an old copy of the \
+                 graph",
+                block.output_path().display()
+            )
+        );
+        assert_eq!(
+            fs::read_to_string(block.output_path()).unwrap(),
+            "<svg>drawn by hand</svg>"
+        );
+
+        // our own files are written again, with or without the cache
+        fs::remove_file(block.output_path()).unwrap();
+        for skip_unchanged in [false, false, true] {
+            renderer(skip_unchanged)
+                .render_graphviz(&RenderContext::new(&block))
+                .unwrap();
+        }
+
+        // images named the way we name them are always ours
+        let generated = GraphvizBlock {
+            file_stem: None,
+            ..block
+        };
+        fs::write(generated.output_path(), "<svg>old</svg>").unwrap();
+        renderer(false)
+            .render_graphviz(&RenderContext::new(&generated))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(generated.output_path()).unwrap(),
+            "<svg/>\n"
+        );
+    }
+
+    #[test]
+    fn unchanged_files_arent_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("graph.generated.svg");
+        let untouched = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        let modified = || fs::metadata(&path).unwrap().modified().unwrap();
+
+        write_if_changed(&path, b"<svg/>").unwrap();
+        fs::File::options()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(untouched)
+            .unwrap();
+        write_if_changed(&path, b"<svg/>").unwrap();
+        assert_eq!(modified(), untouched);
+
+        write_if_changed(&path, b"<svg></svg>").unwrap();
+        assert_ne!(modified(), untouched);
+        assert_eq!(fs::read(&path).unwrap(), b"<svg></svg>");
+    }
+
+    #[test]
+    fn files_are_replaced_in_one_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("graph.generated.svg");
+        let files = || fs::read_dir(dir.path()).unwrap().count();
+
+        fs::write(&path, "<svg>old</svg>").unwrap();
+        write_atomically(&path, b"<svg>new</svg>").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"<svg>new</svg>");
+        assert_eq!(files(), 1);
+
+        // nothing is left behind when the rename fails
+        let taken = dir.path().join("taken.generated.svg");
+        fs::create_dir(&taken).unwrap();
+        fs::write(taken.join("x"), "").unwrap();
+        assert!(write_atomically(&taken, b"<svg/>").is_err());
+        assert_eq!(files(), 2);
+    }
+
+    #[test]
+    fn transient_spawn_failures() {
+        assert!(is_transient(&io::Error::from(ErrorKind::WouldBlock)));
+        #[cfg(unix)]
+        assert!(is_transient(&io::Error::from_raw_os_error(24)));
+        assert!(!is_transient(&io::Error::from(ErrorKind::NotFound)));
+        assert!(!is_transient(&io::Error::from(ErrorKind::PermissionDenied)));
+
+        // a missing binary isn't retried
+        let error = spawn(&mut Command::new("/does/not/exist/dot")).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn inline_output_is_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = GraphvizCommand {
+            dot: fake_command(
+                dir.path(),
+                "dot",
+                &format!("cat > /dev/null; echo '<svg>{}</svg>'", "x".repeat(1024)),
+            ),
+            ..GraphvizCommand::default()
+        };
+        let output_dir = dir.path().join("book");
+        let max_bytes = 10 * 1024;
+        let renderer = CLIGraphviz::new(command, Some(max_bytes));
+
+        let mut inlined_bytes = 0;
+        for index in 0..50 {
+            let block = GraphvizBlock {
+                output_dir: output_dir.clone(),
+                index,
+                ..test_block("")
+            };
+
+            let context = RenderContext::new(&block);
+            let events = renderer
+                .settle(&context, renderer.render_graphviz(&context))
+                .unwrap()
+                .events;
+            match &events[0] {
+                Event::Start(Tag::HtmlBlock) => {
+                    if let Event::Html(html) = &events[1] {
+                        inlined_bytes += html.len();
+                    }
+                }
+                Event::Start(Tag::Image(..)) => assert!(block.output_path().exists()),
+                other => panic!("Unexpected event {:?}", other),
+            }
+        }
+
+        assert!(inlined_bytes > 0);
+        assert!(inlined_bytes <= max_bytes);
+        // everything that didn't fit went to a file instead
+        assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 41);
+        assert_eq!(renderer.spilled().len(), 41);
+    }
+
+    #[test]
+    fn dot_resolution_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let configured = dir.path().join("configured-dot");
+        let from_env = dir.path().join("env-dot");
+        fs::write(&configured, "").unwrap();
+        fs::write(&from_env, "").unwrap();
+
+        assert_eq!(
+            find_dot(
+                Some(configured.to_str().unwrap()),
+                Some(from_env.clone().into())
+            )
+            .unwrap(),
+            configured
+        );
+        assert_eq!(
+            find_dot(None, Some(from_env.clone().into())).unwrap(),
+            from_env
+        );
+        // we fall through to the next location if one doesn't exist
+        assert_eq!(
+            find_dot(
+                Some(dir.path().join("missing").to_str().unwrap()),
+                Some(from_env.clone().into())
+            )
+            .unwrap(),
+            from_env
+        );
+    }
+
+    #[test]
+    fn dot_resolution_reports_what_we_tried() {
+        let dir = tempfile::tempdir().unwrap();
+        let configured = dir.path().join("configured-dot");
+        let from_env = dir.path().join("env-dot");
+
+        let error = find_dot_in(
+            Some(configured.to_str().unwrap()),
+            Some(from_env.clone().into()),
+            Some(dir.path().into()),
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(error.contains(configured.to_str().unwrap()), "{}", error);
+        assert!(error.contains(from_env.to_str().unwrap()), "{}", error);
+        assert!(error.contains("dot (PATH)"), "{}", error);
+    }
+
+    #[test]
+    fn dot_from_the_path() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("dot"), "").unwrap();
+
+        assert_eq!(
+            find_dot_in(None, None, Some(dir.path().into())).unwrap(),
+            dir.path().join("dot")
+        );
+    }
+
+    #[test]
+    fn seeded_layouts() {
+        let neato = GraphvizBlock {
+            engine: Some("neato".into()),
+            seed: Some(42),
+            ..test_block("")
+        };
+        assert_eq!(layout_args(&neato, ""), vec!["-Kneato", "-Gstart=42"]);
+
+        // dot's layout is deterministic already
+        let dot = GraphvizBlock {
+            engine: Some("dot".into()),
+            ..neato.clone()
+        };
+        assert_eq!(layout_args(&dot, ""), vec!["-Kdot"]);
+
+        let seeded = GraphvizBlock {
+            seed: Some(42),
+            ..test_block("")
+        };
+        assert!(layout_args(&seeded, "").is_empty());
+    }
+
+    #[test]
+    fn layout_from_the_graph() {
+        let code = "digraph {\n    layout=fdp\n    a -> b\n}";
+
+        // the graph picks its engine, so it also gets the seed
+        let block = GraphvizBlock {
+            seed: Some(42),
+            ..test_block(code)
+        };
+        assert_eq!(layout_args(&block, code), vec!["-Kfdp", "-Gstart=42"]);
+
+        // graphviz prefers the graph's layout over -K
+        let block = GraphvizBlock {
+            engine: Some("dot".into()),
+            ..test_block(code)
+        };
+        assert_eq!(layout_args(&block, code), vec!["-Kfdp"]);
+    }
+
+    #[test]
+    fn find_layout_attributes() {
+        assert_eq!(
+            layout_attribute("graph { layout=neato }"),
+            Some("neato".into())
+        );
+        assert_eq!(
+            layout_attribute("graph { graph [layout = \"circo\", rankdir=LR] }"),
+            Some("circo".into())
+        );
+        assert_eq!(
+            layout_attribute("digraph { a;layout=twopi; }"),
+            Some("twopi".into())
+        );
+        assert_eq!(layout_attribute("digraph { a [mylayout=x] }"), None);
+        assert_eq!(layout_attribute("digraph { a -> b }"), None);
+    }
+
+    #[test]
+    fn parse_versions() {
+        assert_eq!(
+            GraphvizVersion::parse("dot - graphviz version 2.43.0 (0)"),
+            Some(GraphvizVersion(vec![2, 43]))
+        );
+        assert_eq!(
+            GraphvizVersion::parse("dot - graphviz version 9.0.0 (20230911.1827)"),
+            Some(GraphvizVersion(vec![9]))
+        );
+        assert_eq!(
+            GraphvizVersion::parse("2.40.1"),
+            Some(GraphvizVersion(vec![2, 40, 1]))
+        );
+        assert_eq!(GraphvizVersion::parse("dot - graphviz"), None);
+
+        assert!(GraphvizVersion::parse("2.38").unwrap() < GraphvizVersion::parse("2.40").unwrap());
+        assert!(
+            GraphvizVersion::parse("2.40").unwrap() == GraphvizVersion::parse("2.40.0").unwrap()
+        );
+        assert!(GraphvizVersion::parse("10.0").unwrap() > GraphvizVersion::parse("9.0").unwrap());
+        assert_eq!(GraphvizVersion(vec![2, 43, 1]).to_string(), "2.43.1");
+    }
+
+    #[test]
+    fn graphviz_version_changes_the_render_key() {
+        let older = GraphvizCommand {
+            version: GraphvizVersion::parse("2.43.0"),
+            ..GraphvizCommand::default()
+        };
+        let newer = GraphvizCommand {
+            version: GraphvizVersion::parse("9.0.0"),
+            ..GraphvizCommand::default()
+        };
+        let code = "digraph { a -> b }";
+
+        assert_ne!(
+            older.render_key(&["-Tsvg"], code),
+            newer.render_key(&["-Tsvg"], code)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn probe_dot_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = GraphvizCommand {
+            dot: fake_command(
+                dir.path(),
+                "dot",
+                "echo 'dot - graphviz version 2.43.0 (0)' >&2",
+            ),
+            ..GraphvizCommand::default()
+        };
+        assert_eq!(command.version().unwrap(), GraphvizVersion(vec![2, 43]));
+
+        let command = GraphvizCommand {
+            dot: fake_command(dir.path(), "broken-dot", "exit 1"),
+            ..GraphvizCommand::default()
+        };
+        let error = command.version().unwrap_err().to_string();
+        assert!(error.contains("Install graphviz"), "{}", error);
+
+        let command = GraphvizCommand {
+            dot: dir.path().join("missing-dot"),
+            ..GraphvizCommand::default()
+        };
+        let error = command.version().unwrap_err().to_string();
+        assert!(error.contains("Install graphviz"), "{}", error);
+    }
+
+    /// Write an executable shell script standing in for one of the graphviz tools
+    #[cfg(unix)]
+    pub fn fake_command(dir: &Path, name: &str, script: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        path
+    }
+
+    fn test_block(code: &str) -> GraphvizBlock {
+        GraphvizBlock {
+            graph_name: "Name".into(),
+            code: code.into(),
+            chapter_name: "".into(),
+            chapter_id: String::new(),
+            output_dir: "".into(),
+            link_dir: String::new(),
+            format: "svg".into(),
+            engine: None,
+            seed: None,
+            unflatten: None,
+            filename_template: FilenameTemplate::default(),
+            index_names: true,
+            stable_names: false,
+            duplicate: 0,
+            content_hash: String::new(),
+            index: 0,
+            dpi: None,
+            sub_graph: None,
+            alt: None,
+            alt_template: None,
+            caption: None,
+            width: None,
+            height: None,
+            link: None,
+            file_stem: None,
+            expect_error: false,
+            thumbnail: None,
+            keep_dot: false,
+            source: SourceLine::default(),
+            options: BTreeMap::new(),
+        }
+    }
 }